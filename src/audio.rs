@@ -0,0 +1,287 @@
+use std::collections::VecDeque;
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+
+/// The APU output is sampled once per M-cycle.
+pub const INPUT_RATE: u32 = 1_048_576;
+
+// Charge kept by the output capacitor per clock cycle, removes the DAC's DC offset
+const CAPACITOR_CHARGE: f32 = 0.999958;
+
+const BITS_PER_SAMPLE: u16 = 16;
+const HEADER_LENGTH: u32 = 44;
+
+/// Averages the APU output down to the requested rate and buffers the stereo samples
+/// until they are pulled. At most one second of audio is kept, older samples get dropped.
+#[derive(Debug)]
+pub struct Resampler {
+    rate: u32,
+    phase: u32,
+    sum: (f32, f32),
+    count: u32,
+    // Without a high-pass filter when None
+    charge_factor: Option<f32>,
+    capacitor: (f32, f32),
+    samples: VecDeque<(f32, f32)>,
+}
+
+impl Resampler {
+    /// Rates are clamped to 1..=INPUT_RATE Hz.
+    pub fn new(rate: u32) -> Resampler {
+        let rate = rate.clamp(1, INPUT_RATE);
+        Resampler {
+            rate,
+            phase: 0,
+            sum: (0.0, 0.0),
+            count: 0,
+            charge_factor: Some(CAPACITOR_CHARGE.powf(4.0 * INPUT_RATE as f32 / rate as f32)),
+            capacitor: (0.0, 0.0),
+            samples: VecDeque::new(),
+        }
+    }
+
+    /// Keeps the DC offset of the signal, for looking at it rather than listening to it.
+    pub fn unfiltered(rate: u32) -> Resampler {
+        Resampler {charge_factor: None, ..Resampler::new(rate)}
+    }
+
+    pub fn rate(&self) -> u32 {
+        self.rate
+    }
+
+    /// Feeds one input sample.
+    pub fn push(&mut self, (left, right): (f32, f32)) {
+        self.sum.0 += left;
+        self.sum.1 += right;
+        self.count += 1;
+        self.phase += self.rate;
+        if self.phase < INPUT_RATE {
+            return;
+        }
+        self.phase -= INPUT_RATE;
+        let count = self.count as f32;
+        let sample = (self.high_pass(0, self.sum.0 / count), self.high_pass(1, self.sum.1 / count));
+        self.sum = (0.0, 0.0);
+        self.count = 0;
+        if self.samples.len() == self.rate as usize {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(sample);
+    }
+
+    fn high_pass(&mut self, side: usize, input: f32) -> f32 {
+        let charge_factor = match self.charge_factor {
+            Some(charge_factor) => charge_factor,
+            None => return input,
+        };
+        let capacitor = if side == 0 {&mut self.capacitor.0} else {&mut self.capacitor.1};
+        let output = input - *capacitor;
+        *capacitor = input - output * charge_factor;
+        output
+    }
+
+    /// Number of stereo samples ready to be pulled.
+    pub fn len(&self) -> usize {
+        self.samples.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.samples.is_empty()
+    }
+
+    /// Fills `buffer` with interleaved left/right samples in -1.0..=1.0 and returns how many
+    /// values were written, always an even number.
+    pub fn read_f32(&mut self, buffer: &mut [f32]) -> usize {
+        self.read(buffer, 2, |sample| sample)
+    }
+
+    /// Same as `read_f32` with samples scaled to the full i16 range.
+    pub fn read_i16(&mut self, buffer: &mut [i16]) -> usize {
+        self.read(buffer, 2, to_i16)
+    }
+
+    /// Fills `buffer` with one value per sample, the average of both sides.
+    pub fn read_mono_f32(&mut self, buffer: &mut [f32]) -> usize {
+        self.read(buffer, 1, |sample| sample)
+    }
+
+    pub fn read_mono_i16(&mut self, buffer: &mut [i16]) -> usize {
+        self.read(buffer, 1, to_i16)
+    }
+
+    fn read<T, F: Fn(f32) -> T>(&mut self, buffer: &mut [T], channels: usize, convert: F) -> usize {
+        let mut written = 0;
+        for frame in buffer.chunks_exact_mut(channels) {
+            let (left, right) = match self.samples.pop_front() {
+                Some(sample) => sample,
+                None => break,
+            };
+            if channels == 1 {
+                frame[0] = convert((left + right) / 2.0);
+            } else {
+                frame[0] = convert(left);
+                frame[1] = convert(right);
+            }
+            written += channels;
+        }
+        written
+    }
+}
+
+fn to_i16(sample: f32) -> i16 {
+    (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16
+}
+
+/// Writes interleaved 16 bit PCM into a WAV file, the sizes in the header
+/// are filled in by `finish`.
+#[derive(Debug)]
+pub struct WavWriter<W: Write + Seek> {
+    writer: W,
+    data_length: u32,
+}
+
+impl WavWriter<BufWriter<File>> {
+    pub fn create<P: AsRef<Path>>(path: P, sample_rate: u32, channels: u16) -> io::Result<WavWriter<BufWriter<File>>> {
+        WavWriter::new(BufWriter::new(File::create(path)?), sample_rate, channels)
+    }
+}
+
+impl<W: Write + Seek> WavWriter<W> {
+    /// `channels` is 2 for interleaved stereo and 1 for mono.
+    pub fn new(mut writer: W, sample_rate: u32, channels: u16) -> io::Result<WavWriter<W>> {
+        let block_align = channels * BITS_PER_SAMPLE / 8;
+        writer.write_all(b"RIFF")?;
+        // Patched once the length is known
+        writer.write_all(&0u32.to_le_bytes())?;
+        writer.write_all(b"WAVEfmt ")?;
+        writer.write_all(&16u32.to_le_bytes())?;
+        // PCM
+        writer.write_all(&1u16.to_le_bytes())?;
+        writer.write_all(&channels.to_le_bytes())?;
+        writer.write_all(&sample_rate.to_le_bytes())?;
+        writer.write_all(&(sample_rate * block_align as u32).to_le_bytes())?;
+        writer.write_all(&block_align.to_le_bytes())?;
+        writer.write_all(&BITS_PER_SAMPLE.to_le_bytes())?;
+        writer.write_all(b"data")?;
+        writer.write_all(&0u32.to_le_bytes())?;
+        Ok(WavWriter {writer, data_length: 0})
+    }
+
+    /// Fails without writing anything once the samples don't fit the 4 GiB a WAV file can hold.
+    pub fn write_samples(&mut self, samples: &[i16]) -> io::Result<()> {
+        let data_length = u32::try_from(samples.len()).ok()
+            .and_then(|len| len.checked_mul(2))
+            .and_then(|len| len.checked_add(self.data_length))
+            .filter(|&len| len.checked_add(HEADER_LENGTH - 8).is_some())
+            .ok_or_else(|| io::Error::other("WAV file size limit reached"))?;
+        for sample in samples {
+            self.writer.write_all(&sample.to_le_bytes())?;
+        }
+        self.data_length = data_length;
+        Ok(())
+    }
+
+    /// Fills in the header for the samples written so far, so the file stays readable
+    /// even if `finish` never gets to run.
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.seek(SeekFrom::Start(4))?;
+        self.writer.write_all(&(HEADER_LENGTH - 8 + self.data_length).to_le_bytes())?;
+        self.writer.seek(SeekFrom::Start(HEADER_LENGTH as u64 - 4))?;
+        self.writer.write_all(&self.data_length.to_le_bytes())?;
+        self.writer.seek(SeekFrom::End(0))?;
+        self.writer.flush()
+    }
+
+    /// Fills in the header and hands back the underlying writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.flush()?;
+        Ok(self.writer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn resampling() {
+        let mut resampler = Resampler::new(44100);
+        for _ in 0..INPUT_RATE / 4 {
+            resampler.push((0.5, -0.5));
+        }
+        assert_eq!(resampler.len(), 44100 / 4);
+
+        let mut buffer = [0i16; 5];
+        assert_eq!(resampler.read_i16(&mut buffer), 4);
+        // the DC offset decays, the first samples still carry it
+        assert_eq!(buffer[0], (0.5 * i16::MAX as f32) as i16);
+        assert_eq!(buffer[1], (-0.5 * i16::MAX as f32) as i16);
+        assert_eq!(resampler.len(), 44100 / 4 - 2);
+        let mut buffer = vec![0.0f32; 44100];
+        resampler.read_f32(&mut buffer);
+        assert!(buffer[44100 / 2 - 2].abs() < 0.01);
+        assert!(resampler.is_empty());
+    }
+
+    #[test]
+    fn unfiltered_mono() {
+        let mut resampler = Resampler::unfiltered(1024);
+        for i in 0..INPUT_RATE / 256 {
+            let sample = if i < INPUT_RATE / 512 {0.5} else {0.25};
+            resampler.push((sample, sample / 2.0));
+        }
+        let mut buffer = [0.0; 5];
+        assert_eq!(resampler.read_mono_f32(&mut buffer), 4);
+        assert_eq!(buffer, [0.375, 0.375, 0.1875, 0.1875, 0.0]);
+    }
+
+    #[test]
+    fn keeps_one_second() {
+        let mut resampler = Resampler::new(1000);
+        for _ in 0..INPUT_RATE * 2 {
+            resampler.push((0.0, 0.0));
+        }
+        assert_eq!(resampler.len(), 1000);
+    }
+
+    #[test]
+    fn wav_header() {
+        let mut wav = WavWriter::new(Cursor::new(Vec::new()), 44100, 2).unwrap();
+        wav.write_samples(&[1, -1, 0x1234, 0]).unwrap();
+        let data = wav.finish().unwrap().into_inner();
+        assert_eq!(data.len(), 44 + 8);
+        assert_eq!(&data[0..4], b"RIFF");
+        assert_eq!(data[4..8], 44u32.to_le_bytes());
+        assert_eq!(&data[8..16], b"WAVEfmt ");
+        assert_eq!(data[24..28], 44100u32.to_le_bytes());
+        assert_eq!(data[28..32], (44100u32 * 4).to_le_bytes());
+        assert_eq!(data[40..44], 8u32.to_le_bytes());
+        assert_eq!(data[44..], [1, 0, 0xFF, 0xFF, 0x34, 0x12, 0, 0]);
+    }
+
+    #[test]
+    fn wav_flush() {
+        let mut wav = WavWriter::new(Cursor::new(Vec::new()), 8000, 1).unwrap();
+        wav.write_samples(&[1, 2]).unwrap();
+        wav.flush().unwrap();
+        wav.write_samples(&[3]).unwrap();
+        let data = wav.writer.get_ref();
+        assert_eq!(data.len(), 44 + 6);
+        assert_eq!(data[4..8], 40u32.to_le_bytes());
+        assert_eq!(data[40..44], 4u32.to_le_bytes());
+        assert_eq!(data[44..], [1, 0, 2, 0, 3, 0]);
+    }
+
+    #[test]
+    fn wav_size_limit() {
+        let mut wav = WavWriter::new(Cursor::new(Vec::new()), 8000, 1).unwrap();
+        wav.data_length = u32::MAX - (HEADER_LENGTH - 8) - 4;
+        wav.write_samples(&[1, 2]).unwrap();
+        assert!(wav.write_samples(&[3]).is_err());
+        assert_eq!(wav.data_length, u32::MAX - (HEADER_LENGTH - 8));
+        assert_eq!(wav.writer.get_ref().len(), 44 + 4);
+    }
+}
@@ -1,12 +1,24 @@
+use std::cell::Cell;
+
 use super::ram;
 use super::io;
-
-const ROM_START: u16 = 0x0000;
-const ROM_END: u16 = 0x8000;
+use super::dma::{self, Dma};
+use super::cartridge::{self, Cartridge, Mapper};
+use super::error::{BusError, Strictness};
 
 const WRAM_START: u16 = 0xC000;
 const WRAM_END: u16 = 0xE000;
 
+// Mirror of 0xC000-0xDDFF
+const ECHO_START: u16 = 0xE000;
+const ECHO_END: u16 = 0xFE00;
+
+const OAM_START: u16 = 0xFE00;
+const OAM_END: u16 = 0xFEA0;
+
+const UNUSABLE_START: u16 = 0xFEA0;
+const UNUSABLE_END: u16 = 0xFF00;
+
 const HRAM_START: u16 = 0xFF80;
 const HRAM_END: u16 = 0xFFFF;
 
@@ -19,44 +31,135 @@ const IF: u16 = 0xFF0F;
 const IO_START: u16 = 0xFF00;
 const IO_END: u16 = 0xFF80;
 
+/// The two memory buses an OAM DMA can occupy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum MemoryBus {
+    /// Cartridge and work RAM
+    External,
+    Video,
+}
+
+/// Hardware revision being emulated, for the few places where they differ.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Model {
+    #[default]
+    Dmg,
+    Mgb,
+    Sgb,
+    /// CGB revision E and later running a DMG cartridge
+    Cgb,
+}
+
 #[derive(Debug, Default)]
 pub struct Bus {
+    model: Model,
+    strictness: Strictness,
+    // First offending access since the last take_error, only recorded in strict mode
+    error: Cell<Option<BusError>>,
+    // Clock cycles into the current instruction, every access takes an M-cycle
+    cycle: u32,
+    // Clock cycles of the current instruction the hardware already ran for, None outside of one
+    stepped: Option<u32>,
     wram: ram::Ram,
     hram: ram::Ram,
     vram: ram::Ram,
-    rom: Box<[u8]>,
+    oam: ram::Ram,
+    cartridge: Box<dyn Cartridge>,
     io: io::IO,
+    dma: Dma,
     ie: u8,
     r#if: u8,
 }
 
 impl Bus {
-    pub fn new(wram: ram::Ram, rom: Box<[u8]>, hram: ram::Ram, vram: ram::Ram) -> Bus {
+    pub fn new(model: Model, wram: ram::Ram, cartridge: Box<dyn Cartridge>, hram: ram::Ram, vram: ram::Ram, oam: ram::Ram) -> Bus {
         Bus {
+            model,
+            strictness: Strictness::default(),
+            error: Cell::new(None),
+            cycle: 0,
+            stepped: None,
             wram,
-            rom,
+            cartridge,
             hram,
             vram,
+            oam,
             ie: 0,
             r#if: 0,
-            io: io::IO::new()
+            io: io::IO::new(),
+            dma: Dma::default(),
         }
     }
 
-    pub fn read(&self, addr: u16) -> u8 {
-        if addr < ROM_END {
-            return self.rom[addr as usize];
+    /// CPU read, the hardware first runs up to the M-cycle it happens in.
+    pub fn read(&mut self, addr: u16) -> u8 {
+        self.catch_up();
+        let value = self.dma_conflict(addr).unwrap_or_else(|| self.read_direct(addr));
+        self.cycle = self.cycle.saturating_add(4);
+        value
+    }
+
+    /// Read for debuggers and tests, it neither takes time nor reports errors.
+    pub fn peek(&self, addr: u16) -> u8 {
+        let error = self.error.get();
+        let value = self.read_direct(addr);
+        self.error.set(error);
+        value
+    }
+
+    /// Called by the CPU before fetching an opcode, accesses from then on are timed
+    /// relative to the start of that instruction.
+    pub fn begin_instruction(&mut self) {
+        self.cycle = 0;
+        self.stepped = Some(0);
+    }
+
+    /// Called by the CPU once an instruction is done, runs the hardware for the part of
+    /// its `cycles` the accesses didn't already get to.
+    pub fn end_instruction(&mut self, cycles: u8) {
+        let stepped = self.stepped.take().unwrap_or(0);
+        self.step((cycles as u32).saturating_sub(stepped) as u8);
+    }
+
+    // Accesses see the hardware as it is in their own M-cycle instead of at the start of the
+    // instruction, like timer register writes racing a TIMA reload do
+    fn catch_up(&mut self) {
+        if let Some(stepped) = self.stepped {
+            if self.cycle > stepped {
+                self.stepped = Some(self.cycle);
+                self.step((self.cycle - stepped) as u8);
+            }
         }
-        if WRAM_START <= addr && addr < WRAM_END {
+    }
+
+    /// Read without the restrictions of a running DMA, what the DMA itself sees.
+    fn read_direct(&self, addr: u16) -> u8 {
+        if (cartridge::ROM_START..cartridge::ROM_END).contains(&addr)
+            || (cartridge::RAM_START..cartridge::RAM_END).contains(&addr) {
+            return self.cartridge.read(addr);
+        }
+        if (WRAM_START..WRAM_END).contains(&addr) {
             let ram_addr = addr - WRAM_START;
             return self.wram.read(ram_addr.into());
         }
-        if HRAM_START <= addr && addr < HRAM_END {
+        if (ECHO_START..ECHO_END).contains(&addr) {
+            let ram_addr = addr - ECHO_START;
+            return self.wram.read(ram_addr.into());
+        }
+        if (OAM_START..OAM_END).contains(&addr) {
+            let ram_addr = addr - OAM_START;
+            return self.oam.read(ram_addr.into());
+        }
+        if (UNUSABLE_START..UNUSABLE_END).contains(&addr) {
+            self.report(BusError::UnmappedRead(addr));
+            return self.read_unusable(addr);
+        }
+        if (HRAM_START..HRAM_END).contains(&addr) {
             let ram_addr = addr - HRAM_START;
             /*dbg!(addr, self.hram.read(ram_addr.into()));*/
             return self.hram.read(ram_addr.into());
         }
-        if VRAM_START <= addr && addr < VRAM_END {
+        if (VRAM_START..VRAM_END).contains(&addr) {
             let ram_addr = addr - VRAM_START;
             return self.vram.read(ram_addr.into());
         }
@@ -67,30 +170,62 @@ impl Bus {
         if addr == IF {
            return self.r#if; 
         }
+        if addr == dma::REGISTER {
+            return self.dma.read();
+        }
 
-        if IO_START <= addr && addr < IO_END {
+        if (IO_START..IO_END).contains(&addr) {
+            if !self.io.is_mapped(addr) {
+                self.report(BusError::UnmappedRead(addr));
+            }
             return self.io.read(addr);
         }
 
-        panic!("Reading from unknown addres {:#x}", addr)
+        self.report(BusError::UnmappedRead(addr));
+        0xFF
     }
 
     pub fn write(&mut self, addr: u16, value: u8) {
-        if addr < ROM_END {
-            panic!("Writing to rom to addres {:#x}", addr);
+        self.catch_up();
+        self.cycle = self.cycle.saturating_add(4);
+        if self.dma_conflict(addr).is_some() {
+            return
         }
-        if WRAM_START <= addr && addr < WRAM_END {
+        if (cartridge::ROM_START..cartridge::ROM_END).contains(&addr)
+            || (cartridge::RAM_START..cartridge::RAM_END).contains(&addr) {
+            if addr < cartridge::ROM_END && self.cartridge.header().cartridge_type.mapper() == Some(Mapper::None) {
+                self.report(BusError::RomWrite(addr, value));
+            }
+            self.cartridge.write(addr, value);
+            return
+        }
+        if (WRAM_START..WRAM_END).contains(&addr) {
             let ram_addr = addr - WRAM_START;
             self.wram.write(ram_addr.into(), value);
             return
         }
-        if HRAM_START <= addr && addr < HRAM_END {
+        if (ECHO_START..ECHO_END).contains(&addr) {
+            let ram_addr = addr - ECHO_START;
+            self.wram.write(ram_addr.into(), value);
+            return
+        }
+        if (OAM_START..OAM_END).contains(&addr) {
+            let ram_addr = addr - OAM_START;
+            self.oam.write(ram_addr.into(), value);
+            return
+        }
+        if (UNUSABLE_START..UNUSABLE_END).contains(&addr) {
+            // Writes are ignored on every model
+            self.report(BusError::UnmappedWrite(addr, value));
+            return
+        }
+        if (HRAM_START..HRAM_END).contains(&addr) {
             let ram_addr = addr - HRAM_START;
             /*dbg!(addr, value);*/
             self.hram.write(ram_addr.into(), value);
             return
         }
-        if VRAM_START <= addr && addr < VRAM_END {
+        if (VRAM_START..VRAM_END).contains(&addr) {
             let ram_addr = addr - VRAM_START;
             self.vram.write(ram_addr.into(), value);
             return
@@ -103,17 +238,239 @@ impl Bus {
             self.r#if = value;
             return
         }
+        if addr == dma::REGISTER {
+            self.dma.write(value);
+            return
+        }
 
-        if IO_START <= addr && addr < IO_END {
+        if (IO_START..IO_END).contains(&addr) {
+            if !self.io.is_mapped(addr) {
+                self.report(BusError::UnmappedWrite(addr, value));
+            }
             self.io.write(addr, value);
             return
         }
 
-        panic!("Writing to unknown addres {:#x}", addr)
+        self.report(BusError::UnmappedWrite(addr, value));
+    }
+
+    /// While a DMA runs the CPU only reaches HRAM and the IO registers, the latter so the
+    /// transfer can be restarted through FF46. Writes anywhere else are lost, reads on the bus
+    /// being copied from see the byte being transferred and everything else reads 0xFF.
+    /// The transfer runs alongside the CPU's accesses, see `catch_up`.
+    fn dma_conflict(&self, addr: u16) -> Option<u8> {
+        let source = self.dma.source()?;
+        if addr >= IO_START {
+            return None;
+        }
+        if memory_bus(addr) == memory_bus(source) {Some(self.dma.last_byte())} else {Some(0xFF)}
+    }
+
+    fn report(&self, error: BusError) {
+        if self.strictness == Strictness::Strict && self.error.get().is_none() {
+            self.error.set(Some(error));
+        }
+    }
+
+    pub fn take_error(&mut self) -> Option<BusError> {
+        self.error.take()
+    }
+
+    pub fn strictness(&self) -> Strictness {
+        self.strictness
+    }
+
+    pub fn set_strictness(&mut self, strictness: Strictness) {
+        self.strictness = strictness;
+    }
+
+    fn read_unusable(&self, addr: u16) -> u8 {
+        match self.model {
+            Model::Dmg | Model::Mgb | Model::Sgb => 0x00,
+            // 0xFEAx reads 0xAA, 0xFEBx reads 0xBB and so on
+            Model::Cgb => {
+                let nibble = (addr as u8) >> 4;
+                (nibble << 4) | nibble
+            }
+        }
+    }
+
+    pub fn model(&self) -> Model {
+        self.model
+    }
+
+    pub fn cartridge(&self) -> &dyn Cartridge {
+        self.cartridge.as_ref()
+    }
+
+    pub fn cartridge_mut(&mut self) -> &mut dyn Cartridge {
+        self.cartridge.as_mut()
+    }
+
+    pub fn step(&mut self, cycles: u8) {
+        self.cartridge.step(cycles as u32);
+        for _ in 0..cycles / 4 {
+            if let Some(source) = self.dma.tick() {
+                let value = self.read_direct(source);
+                self.dma.copied(value);
+                self.oam.write((source & 0xFF) as usize, value);
+            }
+        }
+        self.r#if |= self.io.step(cycles, &self.vram, &self.oam);
+    }
+
+    pub fn io(&self) -> &io::IO {
+        &self.io
+    }
+
+    pub fn io_mut(&mut self) -> &mut io::IO {
+        &mut self.io
+    }
+
+}
+
+fn memory_bus(addr: u16) -> Option<MemoryBus> {
+    if (VRAM_START..VRAM_END).contains(&addr) {
+        Some(MemoryBus::Video)
+    } else if addr < OAM_START {
+        Some(MemoryBus::External)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_bus(model: Model) -> Bus {
+        Bus::new(model, ram::Ram::new(0x2000), Default::default(), ram::Ram::new(0x7F), ram::Ram::new(0x2000), ram::Ram::new(0xA0))
+    }
+
+    #[test]
+    fn echo_ram() {
+        let mut bus = new_bus(Model::Dmg);
+        bus.write(0xC123, 0x42);
+        assert_eq!(bus.read(0xE123), 0x42);
+        bus.write(0xFDFF, 0x24);
+        assert_eq!(bus.read(0xDDFF), 0x24);
+    }
+
+    #[test]
+    fn oam() {
+        let mut bus = new_bus(Model::Dmg);
+        bus.write(0xFE00, 0x11);
+        bus.write(0xFE9F, 0x22);
+        assert_eq!(bus.read(0xFE00), 0x11);
+        assert_eq!(bus.read(0xFE9F), 0x22);
+    }
+
+    #[test]
+    fn unusable_region() {
+        let mut bus = new_bus(Model::Dmg);
+        bus.write(0xFEA0, 0x42);
+        assert_eq!(bus.read(0xFEA0), 0x00);
+        assert_eq!(bus.read(0xFEFF), 0x00);
+        let mut bus = new_bus(Model::Cgb);
+        assert_eq!(bus.read(0xFEA3), 0xAA);
+        assert_eq!(bus.read(0xFEF0), 0xFF);
+    }
+
+    #[test]
+    fn unmapped_io() {
+        let mut bus = new_bus(Model::Dmg);
+        for addr in [0xFF03, 0xFF08, 0xFF27, 0xFF4C, 0xFF50, 0xFF7F] {
+            bus.write(addr, 0x00);
+            assert_eq!(bus.read(addr), 0xFF, "{:#X}", addr);
+        }
+        assert_eq!(bus.take_error(), None);
+    }
+
+    #[test]
+    fn strict_mode_reports_first_error() {
+        let mut bus = new_bus(Model::Dmg);
+        bus.set_strictness(Strictness::Strict);
+        bus.write(0xC000, 0x01);
+        assert_eq!(bus.take_error(), None);
+        bus.read(0xFF4C);
+        bus.write(0x2000, 0x01);
+        assert_eq!(bus.take_error(), Some(BusError::UnmappedRead(0xFF4C)));
+        bus.write(0x2000, 0x01);
+        assert_eq!(bus.take_error(), Some(BusError::RomWrite(0x2000, 0x01)));
+        bus.write(0xFEA0, 0x02);
+        assert_eq!(bus.take_error(), Some(BusError::UnmappedWrite(0xFEA0, 0x02)));
     }
 
-    pub fn increment_div(&mut self) {
-        self.io.increment_div();
+    fn run_m_cycles(bus: &mut Bus, count: u32) {
+        for _ in 0..count {
+            bus.step(4);
+        }
     }
 
+    #[test]
+    fn oam_dma() {
+        let mut bus = new_bus(Model::Dmg);
+        for i in 0..0xA0 {
+            bus.write(0xC100 + i, i as u8);
+        }
+        bus.write(0xFF80, 0x42);
+        bus.write(0xFF46, 0xC1);
+        assert_eq!(bus.read(0xFF46), 0xC1);
+        bus.step(8);
+        // the bus being copied from reads the transferred byte, only HRAM and IO still work
+        assert_eq!(bus.read(0xC000), 0x00);
+        bus.step(4);
+        assert_eq!(bus.read(0xC000), 0x01);
+        assert_eq!(bus.read(0x0000), 0x01);
+        assert_eq!(bus.read(0xFE00), 0xFF);
+        assert_eq!(bus.read(0x8000), 0xFF);
+        assert_eq!(bus.read(0xFF80), 0x42);
+        assert_eq!(bus.read(0xFF46), 0xC1);
+        bus.write(0x8000, 0x24);
+        bus.write(0xC000, 0x24);
+        bus.write(0xFF81, 0x24);
+        run_m_cycles(&mut bus, 158);
+        assert_eq!(bus.read(0x8000), 0x00);
+        assert_eq!(bus.read(0xC000), 0x00);
+        assert_eq!(bus.read(0xFF81), 0x24);
+        for i in 0..0xA0 {
+            assert_eq!(bus.read(0xFE00 + i), i as u8);
+        }
+    }
+
+    #[test]
+    fn oam_dma_during_an_instruction() {
+        let mut bus = new_bus(Model::Dmg);
+        bus.write(0xC100, 0x42);
+        bus.write(0xFF46, 0xC1);
+        bus.step(4);
+        // the copy starts in the second M-cycle of the next instruction and its accesses see it
+        bus.begin_instruction();
+        assert_eq!(bus.read(0xC000), 0x00);
+        assert_eq!(bus.read(0xC000), 0x42);
+        assert_eq!(bus.read(0xFE00), 0xFF);
+        bus.end_instruction(12);
+        assert_eq!(bus.peek(0xFE00), 0x42);
+    }
+
+    #[test]
+    fn oam_dma_restart() {
+        let mut bus = new_bus(Model::Dmg);
+        bus.write(0x8000, 0x11);
+        bus.write(0x8001, 0x22);
+        bus.write(0xC000, 0x33);
+        bus.write(0xC001, 0x44);
+        bus.write(0xFF46, 0x80);
+        bus.step(8);
+        bus.write(0xFF46, 0xC0);
+        bus.step(4);
+        assert_eq!(bus.read(0x8000), 0x22);
+        bus.step(4);
+        // the new transfer took over the external bus, VRAM stays out of reach
+        assert_eq!(bus.read(0x8000), 0xFF);
+        assert_eq!(bus.read(0xD000), 0x33);
+        run_m_cycles(&mut bus, 159);
+        assert_eq!(bus.read(0xFE00), 0x33);
+        assert_eq!(bus.read(0xFE01), 0x44);
+    }
 }
@@ -0,0 +1,213 @@
+use super::CartridgeError;
+
+const TITLE_START: usize = 0x134;
+const TITLE_END: usize = 0x144;
+const CGB_FLAG: usize = 0x143;
+const NEW_LICENSEE_CODE: usize = 0x144;
+const SGB_FLAG: usize = 0x146;
+const CARTRIDGE_TYPE: usize = 0x147;
+const ROM_SIZE: usize = 0x148;
+const RAM_SIZE: usize = 0x149;
+const OLD_LICENSEE_CODE: usize = 0x14B;
+const HEADER_CHECKSUM: usize = 0x14D;
+const GLOBAL_CHECKSUM: usize = 0x14E;
+
+pub const HEADER_END: usize = 0x150;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CgbSupport {
+    None,
+    Supported,
+    Only,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Licensee {
+    Old(u8),
+    New([u8; 2]),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mapper {
+    None,
+    Mbc1,
+    Mbc2,
+    Mbc3,
+    Mbc5,
+}
+
+/// Raw cartridge type byte (0x0147) describing the mapper and the extra hardware on board.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CartridgeType(pub u8);
+
+impl CartridgeType {
+    pub fn mapper(&self) -> Option<Mapper> {
+        match self.0 {
+            0x00 | 0x08 | 0x09 => Some(Mapper::None),
+            0x01..=0x03 => Some(Mapper::Mbc1),
+            0x05 | 0x06 => Some(Mapper::Mbc2),
+            0x0F..=0x13 => Some(Mapper::Mbc3),
+            0x19..=0x1E => Some(Mapper::Mbc5),
+            _ => None,
+        }
+    }
+
+    pub fn has_ram(&self) -> bool {
+        matches!(self.0, 0x02 | 0x03 | 0x05 | 0x06 | 0x08 | 0x09 | 0x10 | 0x12 | 0x13 | 0x1A | 0x1B | 0x1D | 0x1E)
+    }
+
+    pub fn has_battery(&self) -> bool {
+        matches!(self.0, 0x03 | 0x06 | 0x09 | 0x0F | 0x10 | 0x13 | 0x1B | 0x1E)
+    }
+
+    pub fn has_timer(&self) -> bool {
+        matches!(self.0, 0x0F | 0x10)
+    }
+
+    pub fn has_rumble(&self) -> bool {
+        matches!(self.0, 0x1C..=0x1E)
+    }
+}
+
+/// Cartridge header found at 0x0100-0x014F of every ROM.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Header {
+    pub title: String,
+    pub cgb: CgbSupport,
+    pub sgb: bool,
+    pub cartridge_type: CartridgeType,
+    pub rom_size: usize,
+    pub ram_size: usize,
+    pub licensee: Licensee,
+    pub header_checksum: u8,
+    pub global_checksum: u16,
+}
+
+impl Header {
+    pub fn parse(rom: &[u8]) -> Result<Header, CartridgeError> {
+        if rom.len() < HEADER_END {
+            return Err(CartridgeError::TooShort(rom.len()));
+        }
+
+        let cgb = match rom[CGB_FLAG] {
+            0x80 => CgbSupport::Supported,
+            0xC0 => CgbSupport::Only,
+            _ => CgbSupport::None,
+        };
+        // CGB aware titles give up their last character for the CGB flag
+        let title_end = if cgb == CgbSupport::None {TITLE_END} else {CGB_FLAG};
+        let title = rom[TITLE_START..title_end]
+            .iter()
+            .take_while(|&&c| c != 0)
+            .map(|&c| c as char)
+            .collect();
+
+        let rom_size = match rom[ROM_SIZE] {
+            n @ 0x00..=0x08 => (32 * 1024) << n,
+            n => return Err(CartridgeError::InvalidRomSize(n)),
+        };
+        let ram_size = match rom[RAM_SIZE] {
+            0x00 => 0,
+            0x01 => 2 * 1024,
+            0x02 => 8 * 1024,
+            0x03 => 32 * 1024,
+            0x04 => 128 * 1024,
+            0x05 => 64 * 1024,
+            n => return Err(CartridgeError::InvalidRamSize(n)),
+        };
+        let licensee = match rom[OLD_LICENSEE_CODE] {
+            0x33 => Licensee::New([rom[NEW_LICENSEE_CODE], rom[NEW_LICENSEE_CODE + 1]]),
+            code => Licensee::Old(code),
+        };
+
+        Ok(Header {
+            title,
+            cgb,
+            // SGB functions are ignored unless the old licensee code says so as well
+            sgb: rom[SGB_FLAG] == 0x03 && rom[OLD_LICENSEE_CODE] == 0x33,
+            cartridge_type: CartridgeType(rom[CARTRIDGE_TYPE]),
+            rom_size,
+            ram_size,
+            licensee,
+            header_checksum: rom[HEADER_CHECKSUM],
+            global_checksum: u16::from_be_bytes([rom[GLOBAL_CHECKSUM], rom[GLOBAL_CHECKSUM + 1]]),
+        })
+    }
+
+    /// The boot ROM refuses to start a cartridge whose header checksum doesn't match.
+    pub fn is_header_checksum_valid(&self, rom: &[u8]) -> bool {
+        header_checksum(rom) == self.header_checksum
+    }
+
+    /// Never verified by the hardware, mismatches are common on homebrew.
+    pub fn is_global_checksum_valid(&self, rom: &[u8]) -> bool {
+        global_checksum(rom) == self.global_checksum
+    }
+}
+
+pub fn header_checksum(rom: &[u8]) -> u8 {
+    rom[TITLE_START..HEADER_CHECKSUM]
+        .iter()
+        .fold(0u8, |acc, &b| acc.wrapping_sub(b).wrapping_sub(1))
+}
+
+pub fn global_checksum(rom: &[u8]) -> u16 {
+    rom.iter()
+        .enumerate()
+        .filter(|&(i, _)| i != GLOBAL_CHECKSUM && i != GLOBAL_CHECKSUM + 1)
+        .fold(0u16, |acc, (_, &b)| acc.wrapping_add(b as u16))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let mut rom = vec![0; 0x8000];
+        rom[TITLE_START..TITLE_START + 6].copy_from_slice(b"TETRIS");
+        rom[CARTRIDGE_TYPE] = 0x03;
+        rom[ROM_SIZE] = 0x01;
+        rom[RAM_SIZE] = 0x02;
+        rom[OLD_LICENSEE_CODE] = 0x01;
+        rom[HEADER_CHECKSUM] = header_checksum(&rom);
+        let [msb, lsb] = global_checksum(&rom).to_be_bytes();
+        rom[GLOBAL_CHECKSUM] = msb;
+        rom[GLOBAL_CHECKSUM + 1] = lsb;
+
+        let header = Header::parse(&rom).unwrap();
+        assert_eq!(header.title, "TETRIS");
+        assert_eq!(header.cgb, CgbSupport::None);
+        assert!(!header.sgb);
+        assert_eq!(header.cartridge_type.mapper(), Some(Mapper::Mbc1));
+        assert!(header.cartridge_type.has_battery());
+        assert_eq!(header.rom_size, 64 * 1024);
+        assert_eq!(header.ram_size, 8 * 1024);
+        assert_eq!(header.licensee, Licensee::Old(0x01));
+        assert!(header.is_header_checksum_valid(&rom));
+        assert!(header.is_global_checksum_valid(&rom));
+        rom[TITLE_START] = b'X';
+        assert!(!header.is_header_checksum_valid(&rom));
+    }
+
+    #[test]
+    fn parse_cgb_title_and_new_licensee() {
+        let mut rom = vec![0; HEADER_END];
+        rom[TITLE_START..CGB_FLAG].copy_from_slice(b"ABCDEFGHIJKLMNO");
+        rom[CGB_FLAG] = 0xC0;
+        rom[OLD_LICENSEE_CODE] = 0x33;
+        rom[NEW_LICENSEE_CODE..NEW_LICENSEE_CODE + 2].copy_from_slice(b"01");
+        rom[SGB_FLAG] = 0x03;
+
+        let header = Header::parse(&rom).unwrap();
+        assert_eq!(header.title, "ABCDEFGHIJKLMNO");
+        assert_eq!(header.cgb, CgbSupport::Only);
+        assert!(header.sgb);
+        assert_eq!(header.licensee, Licensee::New(*b"01"));
+    }
+
+    #[test]
+    fn parse_too_short() {
+        assert!(matches!(Header::parse(&[0; 0x100]), Err(CartridgeError::TooShort(0x100))));
+    }
+}
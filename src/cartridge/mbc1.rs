@@ -0,0 +1,211 @@
+use super::{load_ram, save_ram, Cartridge, CartridgeError, Header};
+use super::super::ram::Ram;
+
+const ROM_BANK_SIZE: usize = 0x4000;
+const RAM_BANK_SIZE: usize = 0x2000;
+
+const MULTICART_ROM_SIZE: usize = 1024 * 1024;
+const LOGO_START: usize = 0x104;
+
+const NINTENDO_LOGO: [u8; 48] = [
+    0xCE, 0xED, 0x66, 0x66, 0xCC, 0x0D, 0x00, 0x0B, 0x03, 0x73, 0x00, 0x83, 0x00, 0x0C, 0x00, 0x0D,
+    0x00, 0x08, 0x11, 0x1F, 0x88, 0x89, 0x00, 0x0E, 0xDC, 0xCC, 0x6E, 0xE6, 0xDD, 0xDD, 0xD9, 0x99,
+    0xBB, 0xBB, 0x67, 0x63, 0x6E, 0x0E, 0xEC, 0xCC, 0xDD, 0xDC, 0x99, 0x9F, 0xBB, 0xB9, 0x33, 0x3E,
+];
+
+#[derive(Debug)]
+pub struct Mbc1 {
+    header: Header,
+    rom: Box<[u8]>,
+    ram: Ram,
+    ram_enabled: bool,
+    // 5 bit register selecting the ROM bank mapped at 0x4000-0x7FFF
+    bank1: u8,
+    // 2 bit register, either upper ROM bank bits or the RAM bank depending on the mode
+    bank2: u8,
+    mode: u8,
+    // MBC1M multicarts don't wire the highest bit of BANK1, BANK2 lands one bit lower
+    multicart: bool,
+}
+
+impl Mbc1 {
+    pub fn new(header: Header, rom: Vec<u8>) -> Mbc1 {
+        let ram_size = if header.cartridge_type.has_ram() {header.ram_size} else {0};
+        Mbc1 {
+            header,
+            multicart: is_multicart(&rom),
+            rom: rom.into_boxed_slice(),
+            ram: Ram::new(ram_size),
+            ram_enabled: false,
+            bank1: 1,
+            bank2: 0,
+            mode: 0,
+        }
+    }
+
+    fn bank2_shift(&self) -> u32 {
+        if self.multicart {4} else {5}
+    }
+
+    fn rom_bank_0(&self) -> usize {
+        if self.mode == 0 {
+            return 0;
+        }
+        (self.bank2 as usize) << self.bank2_shift()
+    }
+
+    fn rom_bank_n(&self) -> usize {
+        let bank1 = if self.multicart {self.bank1 & 0x0F} else {self.bank1};
+        ((self.bank2 as usize) << self.bank2_shift()) | bank1 as usize
+    }
+
+    fn ram_bank(&self) -> usize {
+        if self.mode == 0 {0} else {self.bank2 as usize}
+    }
+
+    fn read_rom(&self, bank: usize, addr: u16) -> u8 {
+        let bank_count = (self.rom.len() / ROM_BANK_SIZE).max(1);
+        let offset = (bank % bank_count) * ROM_BANK_SIZE + (addr as usize & (ROM_BANK_SIZE - 1));
+        self.rom.get(offset).copied().unwrap_or(0xFF)
+    }
+
+    fn ram_addr(&self, addr: u16) -> Option<usize> {
+        if !self.ram_enabled || self.ram.is_empty() {
+            return None;
+        }
+        let offset = self.ram_bank() * RAM_BANK_SIZE + (addr as usize - 0xA000);
+        Some(offset % self.ram.len())
+    }
+}
+
+impl Cartridge for Mbc1 {
+    fn header(&self) -> &Header {
+        &self.header
+    }
+
+    fn read(&self, addr: u16) -> u8 {
+        match addr {
+            0x0000..=0x3FFF => self.read_rom(self.rom_bank_0(), addr),
+            0x4000..=0x7FFF => self.read_rom(self.rom_bank_n(), addr),
+            0xA000..=0xBFFF => match self.ram_addr(addr) {
+                Some(ram_addr) => self.ram.read(ram_addr),
+                None => 0xFF,
+            },
+            _ => 0xFF,
+        }
+    }
+
+    fn write(&mut self, addr: u16, value: u8) {
+        match addr {
+            0x0000..=0x1FFF => self.ram_enabled = value & 0x0F == 0x0A,
+            0x2000..=0x3FFF => {
+                // Zero is detected on all 5 bits, even on multicarts which only use 4 of them
+                self.bank1 = value & 0x1F;
+                if self.bank1 == 0 {
+                    self.bank1 = 1;
+                }
+            }
+            0x4000..=0x5FFF => self.bank2 = value & 0x03,
+            0x6000..=0x7FFF => self.mode = value & 0x01,
+            0xA000..=0xBFFF => {
+                if let Some(ram_addr) = self.ram_addr(addr) {
+                    self.ram.write(ram_addr, value);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn save_data(&self) -> Vec<u8> {
+        save_ram(&self.ram)
+    }
+
+    fn load_save_data(&mut self, data: &[u8]) -> Result<(), CartridgeError> {
+        load_ram(&mut self.ram, data)
+    }
+}
+
+/// MBC1M multicarts are 1 MiB ROMs made of several games, each starting with
+/// its own header, so the Nintendo logo shows up again in bank 0x10.
+fn is_multicart(rom: &[u8]) -> bool {
+    if rom.len() != MULTICART_ROM_SIZE {
+        return false;
+    }
+    let logo = 0x10 * ROM_BANK_SIZE + LOGO_START;
+    rom[logo..logo + NINTENDO_LOGO.len()] == NINTENDO_LOGO
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::{load, test_rom};
+
+    #[test]
+    fn rom_banking() {
+        // 2 MiB ROM, 128 banks
+        let mut cartridge = load(test_rom(0x01, 0x06, 0x00)).unwrap();
+        assert_eq!(cartridge.read(0x4000), 1);
+        cartridge.write(0x2000, 0x05);
+        assert_eq!(cartridge.read(0x4000), 5);
+        // bank 0 is remapped to 1, so are 0x20, 0x40 and 0x60 through the zero check
+        cartridge.write(0x2000, 0x00);
+        assert_eq!(cartridge.read(0x4000), 1);
+        cartridge.write(0x4000, 0x01);
+        assert_eq!(cartridge.read(0x4000), 0x21);
+        cartridge.write(0x2000, 0xE3);
+        assert_eq!(cartridge.read(0x4000), 0x23);
+        // mode 1 maps BANK2 into the 0x0000-0x3FFF area too
+        assert_eq!(cartridge.read(0x0000), 0);
+        cartridge.write(0x6000, 0x01);
+        assert_eq!(cartridge.read(0x0000), 0x20);
+        assert_eq!(cartridge.read(0x4000), 0x23);
+    }
+
+    #[test]
+    fn rom_bank_is_masked_to_rom_size() {
+        // 256 KiB ROM, 16 banks
+        let mut cartridge = load(test_rom(0x01, 0x03, 0x00)).unwrap();
+        cartridge.write(0x2000, 0x12);
+        assert_eq!(cartridge.read(0x4000), 0x02);
+        // a masked bank can still end up being bank 0
+        cartridge.write(0x2000, 0x10);
+        assert_eq!(cartridge.read(0x4000), 0x00);
+    }
+
+    #[test]
+    fn ram_banking() {
+        let mut cartridge = load(test_rom(0x03, 0x01, 0x03)).unwrap();
+        cartridge.write(0xA000, 0x11);
+        assert_eq!(cartridge.read(0xA000), 0xFF);
+        cartridge.write(0x0000, 0x0A);
+        cartridge.write(0xA000, 0x11);
+        assert_eq!(cartridge.read(0xA000), 0x11);
+        // BANK2 only selects the RAM bank in mode 1
+        cartridge.write(0x4000, 0x02);
+        assert_eq!(cartridge.read(0xA000), 0x11);
+        cartridge.write(0x6000, 0x01);
+        assert_eq!(cartridge.read(0xA000), 0x00);
+        cartridge.write(0xA000, 0x22);
+        cartridge.write(0x6000, 0x00);
+        assert_eq!(cartridge.read(0xA000), 0x11);
+        cartridge.write(0x0000, 0x00);
+        assert_eq!(cartridge.read(0xA000), 0xFF);
+    }
+
+    #[test]
+    fn multicart() {
+        let mut rom = test_rom(0x01, 0x05, 0x00);
+        let logo = 0x10 * ROM_BANK_SIZE + LOGO_START;
+        rom[logo..logo + NINTENDO_LOGO.len()].copy_from_slice(&NINTENDO_LOGO);
+        let mut cartridge = load(rom).unwrap();
+        cartridge.write(0x2000, 0x12);
+        assert_eq!(cartridge.read(0x4000), 0x02);
+        cartridge.write(0x4000, 0x01);
+        assert_eq!(cartridge.read(0x4000), 0x12);
+        cartridge.write(0x6000, 0x01);
+        assert_eq!(cartridge.read(0x0000), 0x10);
+        // the zero check still looks at all 5 bits
+        cartridge.write(0x2000, 0x10);
+        assert_eq!(cartridge.read(0x4000), 0x10);
+    }
+}
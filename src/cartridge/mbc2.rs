@@ -0,0 +1,103 @@
+use super::{load_ram, save_ram, Cartridge, CartridgeError, Header};
+use super::super::ram::Ram;
+
+const ROM_BANK_SIZE: usize = 0x4000;
+// 512 half-bytes built into the MBC2 chip itself
+const RAM_SIZE: usize = 512;
+
+#[derive(Debug)]
+pub struct Mbc2 {
+    header: Header,
+    rom: Box<[u8]>,
+    ram: Ram,
+    ram_enabled: bool,
+    rom_bank: u8,
+}
+
+impl Mbc2 {
+    pub fn new(header: Header, rom: Vec<u8>) -> Mbc2 {
+        Mbc2 {
+            header,
+            rom: rom.into_boxed_slice(),
+            ram: Ram::new(RAM_SIZE),
+            ram_enabled: false,
+            rom_bank: 1,
+        }
+    }
+
+    fn read_rom(&self, bank: usize, addr: u16) -> u8 {
+        let bank_count = (self.rom.len() / ROM_BANK_SIZE).max(1);
+        let offset = (bank % bank_count) * ROM_BANK_SIZE + (addr as usize & (ROM_BANK_SIZE - 1));
+        self.rom.get(offset).copied().unwrap_or(0xFF)
+    }
+}
+
+impl Cartridge for Mbc2 {
+    fn header(&self) -> &Header {
+        &self.header
+    }
+
+    fn read(&self, addr: u16) -> u8 {
+        match addr {
+            0x0000..=0x3FFF => self.read_rom(0, addr),
+            0x4000..=0x7FFF => self.read_rom(self.rom_bank as usize, addr),
+            // Only 9 address bits are decoded, the RAM repeats through the whole area
+            0xA000..=0xBFFF if self.ram_enabled => 0xF0 | self.ram.read(addr as usize & (RAM_SIZE - 1)),
+            _ => 0xFF,
+        }
+    }
+
+    fn write(&mut self, addr: u16, value: u8) {
+        match addr {
+            // Address bit 8 decides which register is written
+            0x0000..=0x3FFF if addr & 0x0100 == 0 => self.ram_enabled = value & 0x0F == 0x0A,
+            0x0000..=0x3FFF => {
+                self.rom_bank = value & 0x0F;
+                if self.rom_bank == 0 {
+                    self.rom_bank = 1;
+                }
+            }
+            0xA000..=0xBFFF if self.ram_enabled => self.ram.write(addr as usize & (RAM_SIZE - 1), value & 0x0F),
+            _ => {}
+        }
+    }
+
+    fn save_data(&self) -> Vec<u8> {
+        save_ram(&self.ram)
+    }
+
+    fn load_save_data(&mut self, data: &[u8]) -> Result<(), CartridgeError> {
+        load_ram(&mut self.ram, data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{load, test_rom};
+
+    #[test]
+    fn rom_banking() {
+        let mut cartridge = load(test_rom(0x05, 0x03, 0x00)).unwrap();
+        cartridge.write(0x2100, 0x03);
+        assert_eq!(cartridge.read(0x4000), 3);
+        cartridge.write(0x0100, 0x00);
+        assert_eq!(cartridge.read(0x4000), 1);
+        // bit 8 clear goes to RAM enable, the bank stays the same
+        cartridge.write(0x2000, 0x05);
+        assert_eq!(cartridge.read(0x4000), 1);
+    }
+
+    #[test]
+    fn half_byte_ram() {
+        let mut cartridge = load(test_rom(0x06, 0x01, 0x00)).unwrap();
+        cartridge.write(0xA000, 0x05);
+        assert_eq!(cartridge.read(0xA000), 0xFF);
+        cartridge.write(0x0000, 0x0A);
+        cartridge.write(0xA000, 0x35);
+        assert_eq!(cartridge.read(0xA000), 0xF5);
+        assert_eq!(cartridge.read(0xA200), 0xF5);
+        assert_eq!(cartridge.read(0xBE00), 0xF5);
+        cartridge.write(0xA3FF, 0x0C);
+        assert_eq!(cartridge.read(0xA1FF), 0xFC);
+    }
+}
@@ -0,0 +1,190 @@
+use super::{load_ram, save_ram, Cartridge, CartridgeError, Header};
+use super::rtc::{self, Rtc, RtcMode};
+use super::super::ram::Ram;
+
+const ROM_BANK_SIZE: usize = 0x4000;
+const RAM_BANK_SIZE: usize = 0x2000;
+
+#[derive(Debug)]
+pub struct Mbc3 {
+    header: Header,
+    rom: Box<[u8]>,
+    ram: Ram,
+    rtc: Option<Rtc>,
+    ram_enabled: bool,
+    rom_bank: u8,
+    // 0x00-0x07 select a RAM bank, 0x08-0x0C one of the clock registers
+    ram_bank: u8,
+}
+
+impl Mbc3 {
+    pub fn new(header: Header, rom: Vec<u8>, rtc_mode: RtcMode) -> Mbc3 {
+        let ram_size = if header.cartridge_type.has_ram() {header.ram_size} else {0};
+        let rtc = if header.cartridge_type.has_timer() {Some(Rtc::new(rtc_mode))} else {None};
+        Mbc3 {
+            header,
+            rom: rom.into_boxed_slice(),
+            ram: Ram::new(ram_size),
+            rtc,
+            ram_enabled: false,
+            rom_bank: 1,
+            ram_bank: 0,
+        }
+    }
+
+    fn read_rom(&self, bank: usize, addr: u16) -> u8 {
+        let bank_count = (self.rom.len() / ROM_BANK_SIZE).max(1);
+        let offset = (bank % bank_count) * ROM_BANK_SIZE + (addr as usize & (ROM_BANK_SIZE - 1));
+        self.rom.get(offset).copied().unwrap_or(0xFF)
+    }
+
+    fn ram_addr(&self, addr: u16) -> Option<usize> {
+        if self.ram.is_empty() {
+            return None;
+        }
+        let offset = self.ram_bank as usize * RAM_BANK_SIZE + (addr as usize - 0xA000);
+        Some(offset % self.ram.len())
+    }
+}
+
+impl Cartridge for Mbc3 {
+    fn header(&self) -> &Header {
+        &self.header
+    }
+
+    fn read(&self, addr: u16) -> u8 {
+        match addr {
+            0x0000..=0x3FFF => self.read_rom(0, addr),
+            0x4000..=0x7FFF => self.read_rom(self.rom_bank as usize, addr),
+            0xA000..=0xBFFF if self.ram_enabled => match (self.ram_bank, &self.rtc) {
+                (0x08..=0x0C, Some(rtc)) => rtc.read(self.ram_bank),
+                (0x00..=0x07, _) => match self.ram_addr(addr) {
+                    Some(ram_addr) => self.ram.read(ram_addr),
+                    None => 0xFF,
+                },
+                _ => 0xFF,
+            },
+            _ => 0xFF,
+        }
+    }
+
+    fn write(&mut self, addr: u16, value: u8) {
+        match addr {
+            0x0000..=0x1FFF => self.ram_enabled = value & 0x0F == 0x0A,
+            0x2000..=0x3FFF => {
+                self.rom_bank = value & 0x7F;
+                if self.rom_bank == 0 {
+                    self.rom_bank = 1;
+                }
+            }
+            0x4000..=0x5FFF => self.ram_bank = value & 0x0F,
+            0x6000..=0x7FFF => {
+                if let Some(rtc) = &mut self.rtc {
+                    rtc.write_latch(value);
+                }
+            }
+            0xA000..=0xBFFF if self.ram_enabled => match (self.ram_bank, &mut self.rtc) {
+                (0x08..=0x0C, Some(rtc)) => rtc.write(self.ram_bank, value),
+                (0x00..=0x07, _) => {
+                    if let Some(ram_addr) = self.ram_addr(addr) {
+                        self.ram.write(ram_addr, value);
+                    }
+                }
+                _ => {}
+            },
+            _ => {}
+        }
+    }
+
+    fn step(&mut self, cycles: u32) {
+        if let Some(rtc) = &mut self.rtc {
+            rtc.step(cycles);
+        }
+    }
+
+    fn save_data(&self) -> Vec<u8> {
+        let mut data = save_ram(&self.ram);
+        if let Some(rtc) = &self.rtc {
+            rtc.save(&mut data);
+        }
+        data
+    }
+
+    fn load_save_data(&mut self, data: &[u8]) -> Result<(), CartridgeError> {
+        let ram_size = self.ram.len();
+        let expected = ram_size + if self.rtc.is_some() {rtc::SAVE_SIZE} else {0};
+        let size_error = CartridgeError::InvalidSaveSize {expected, actual: data.len()};
+        if data.len() < ram_size {
+            return Err(size_error);
+        }
+        match &mut self.rtc {
+            Some(rtc) => rtc.load(&data[ram_size..]).map_err(|_| size_error)?,
+            None if data.len() != ram_size => return Err(size_error),
+            None => {}
+        }
+        load_ram(&mut self.ram, &data[..ram_size])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{load, test_rom};
+
+    #[test]
+    fn rom_and_ram_banking() {
+        // 2 MiB ROM, 32 KiB RAM
+        let mut cartridge = load(test_rom(0x13, 0x06, 0x03)).unwrap();
+        cartridge.write(0x2000, 0x00);
+        assert_eq!(cartridge.read(0x4000), 1);
+        cartridge.write(0x2000, 0x7F);
+        assert_eq!(cartridge.read(0x4000), 0x7F);
+        // no bank 0x20/0x40/0x60 holes unlike MBC1
+        cartridge.write(0x2000, 0x20);
+        assert_eq!(cartridge.read(0x4000), 0x20);
+
+        cartridge.write(0x0000, 0x0A);
+        cartridge.write(0x4000, 0x03);
+        cartridge.write(0xA000, 0x33);
+        cartridge.write(0x4000, 0x00);
+        assert_eq!(cartridge.read(0xA000), 0x00);
+        cartridge.write(0x4000, 0x03);
+        assert_eq!(cartridge.read(0xA000), 0x33);
+    }
+
+    #[test]
+    fn rtc_registers() {
+        let mut cartridge = load(test_rom(0x10, 0x01, 0x02)).unwrap();
+        cartridge.write(0x0000, 0x0A);
+        cartridge.write(0x4000, 0x09);
+        cartridge.write(0xA000, 30);
+        cartridge.write(0x4000, 0x08);
+        cartridge.write(0xA000, 59);
+        cartridge.step(4194304);
+        cartridge.write(0x6000, 0x00);
+        cartridge.write(0x6000, 0x01);
+        assert_eq!(cartridge.read(0xA000), 0);
+        cartridge.write(0x4000, 0x09);
+        assert_eq!(cartridge.read(0xA000), 31);
+    }
+
+    #[test]
+    fn save_data_includes_clock() {
+        let mut cartridge = load(test_rom(0x10, 0x01, 0x02)).unwrap();
+        cartridge.write(0x0000, 0x0A);
+        cartridge.write(0xA000, 0x42);
+        cartridge.write(0x4000, 0x0A);
+        cartridge.write(0xA000, 12);
+        let save = cartridge.save_data();
+        assert_eq!(save.len(), 8 * 1024 + 48);
+
+        let mut loaded = load(test_rom(0x10, 0x01, 0x02)).unwrap();
+        loaded.load_save_data(&save).unwrap();
+        loaded.write(0x0000, 0x0A);
+        assert_eq!(loaded.read(0xA000), 0x42);
+        loaded.write(0x6000, 0x00);
+        loaded.write(0x6000, 0x01);
+        loaded.write(0x4000, 0x0A);
+        assert_eq!(loaded.read(0xA000), 12);
+        assert!(loaded.load_save_data(&save[..100]).is_err());
+    }
+}
@@ -0,0 +1,149 @@
+use super::{load_ram, save_ram, Cartridge, CartridgeError, Header};
+use super::super::ram::Ram;
+
+const ROM_BANK_SIZE: usize = 0x4000;
+const RAM_BANK_SIZE: usize = 0x2000;
+
+// On rumble cartridges bit 3 of the RAM bank register drives the motor instead
+const RUMBLE_MOTOR: u8 = 0b1000;
+
+#[derive(Debug)]
+pub struct Mbc5 {
+    header: Header,
+    rom: Box<[u8]>,
+    ram: Ram,
+    ram_enabled: bool,
+    // 9 bit ROM bank, bank 0 can be mapped at 0x4000-0x7FFF as well
+    rom_bank: u16,
+    ram_bank: u8,
+    has_rumble: bool,
+    rumble: bool,
+}
+
+impl Mbc5 {
+    pub fn new(header: Header, rom: Vec<u8>) -> Mbc5 {
+        let ram_size = if header.cartridge_type.has_ram() {header.ram_size} else {0};
+        let has_rumble = header.cartridge_type.has_rumble();
+        Mbc5 {
+            header,
+            rom: rom.into_boxed_slice(),
+            ram: Ram::new(ram_size),
+            ram_enabled: false,
+            rom_bank: 1,
+            ram_bank: 0,
+            has_rumble,
+            rumble: false,
+        }
+    }
+
+    fn read_rom(&self, bank: usize, addr: u16) -> u8 {
+        let bank_count = (self.rom.len() / ROM_BANK_SIZE).max(1);
+        let offset = (bank % bank_count) * ROM_BANK_SIZE + (addr as usize & (ROM_BANK_SIZE - 1));
+        self.rom.get(offset).copied().unwrap_or(0xFF)
+    }
+
+    fn ram_addr(&self, addr: u16) -> Option<usize> {
+        if !self.ram_enabled || self.ram.is_empty() {
+            return None;
+        }
+        let offset = self.ram_bank as usize * RAM_BANK_SIZE + (addr as usize - 0xA000);
+        Some(offset % self.ram.len())
+    }
+}
+
+impl Cartridge for Mbc5 {
+    fn header(&self) -> &Header {
+        &self.header
+    }
+
+    fn read(&self, addr: u16) -> u8 {
+        match addr {
+            0x0000..=0x3FFF => self.read_rom(0, addr),
+            0x4000..=0x7FFF => self.read_rom(self.rom_bank as usize, addr),
+            0xA000..=0xBFFF => match self.ram_addr(addr) {
+                Some(ram_addr) => self.ram.read(ram_addr),
+                None => 0xFF,
+            },
+            _ => 0xFF,
+        }
+    }
+
+    fn write(&mut self, addr: u16, value: u8) {
+        match addr {
+            0x0000..=0x1FFF => self.ram_enabled = value == 0x0A,
+            0x2000..=0x2FFF => self.rom_bank = (self.rom_bank & 0x100) | value as u16,
+            0x3000..=0x3FFF => self.rom_bank = (self.rom_bank & 0xFF) | (((value & 0x01) as u16) << 8),
+            0x4000..=0x5FFF => {
+                if self.has_rumble {
+                    self.rumble = value & RUMBLE_MOTOR != 0;
+                    self.ram_bank = value & 0x07;
+                } else {
+                    self.ram_bank = value & 0x0F;
+                }
+            }
+            0xA000..=0xBFFF => {
+                if let Some(ram_addr) = self.ram_addr(addr) {
+                    self.ram.write(ram_addr, value);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn rumble(&self) -> bool {
+        self.rumble
+    }
+
+    fn save_data(&self) -> Vec<u8> {
+        save_ram(&self.ram)
+    }
+
+    fn load_save_data(&mut self, data: &[u8]) -> Result<(), CartridgeError> {
+        load_ram(&mut self.ram, data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{load, test_rom};
+
+    #[test]
+    fn rom_banking() {
+        // 8 MiB ROM, 512 banks
+        let mut cartridge = load(test_rom(0x19, 0x08, 0x00)).unwrap();
+        assert_eq!(cartridge.read(0x4000), 1);
+        cartridge.write(0x2000, 0x00);
+        assert_eq!(cartridge.read(0x4000), 0);
+        cartridge.write(0x2000, 0x34);
+        cartridge.write(0x3000, 0x01);
+        assert_eq!(cartridge.read(0x4000), 0x34);
+        assert_eq!(cartridge.read(0x4001), 0x01);
+    }
+
+    #[test]
+    fn ram_banking() {
+        // 128 KiB RAM, 16 banks
+        let mut cartridge = load(test_rom(0x1B, 0x01, 0x04)).unwrap();
+        cartridge.write(0x0000, 0x0A);
+        cartridge.write(0x4000, 0x0F);
+        cartridge.write(0xA000, 0x0F);
+        cartridge.write(0x4000, 0x07);
+        assert_eq!(cartridge.read(0xA000), 0x00);
+        cartridge.write(0x4000, 0x0F);
+        assert_eq!(cartridge.read(0xA000), 0x0F);
+        assert!(!cartridge.rumble());
+    }
+
+    #[test]
+    fn rumble() {
+        let mut cartridge = load(test_rom(0x1E, 0x01, 0x03)).unwrap();
+        cartridge.write(0x0000, 0x0A);
+        cartridge.write(0x4000, 0x09);
+        assert!(cartridge.rumble());
+        // the motor bit doesn't select a RAM bank
+        cartridge.write(0xA000, 0x42);
+        cartridge.write(0x4000, 0x01);
+        assert!(!cartridge.rumble());
+        assert_eq!(cartridge.read(0xA000), 0x42);
+    }
+}
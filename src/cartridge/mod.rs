@@ -0,0 +1,159 @@
+use std::error::Error;
+use std::fmt;
+
+use super::ram::Ram;
+
+pub mod header;
+mod rom_only;
+mod mbc1;
+mod mbc2;
+mod mbc3;
+mod mbc5;
+pub mod rtc;
+
+pub use self::header::{CartridgeType, CgbSupport, Header, Licensee, Mapper};
+pub use self::rom_only::RomOnly;
+pub use self::mbc1::Mbc1;
+pub use self::mbc2::Mbc2;
+pub use self::mbc3::Mbc3;
+pub use self::mbc5::Mbc5;
+pub use self::rtc::RtcMode;
+
+pub const ROM_START: u16 = 0x0000;
+pub const ROM_END: u16 = 0x8000;
+
+pub const RAM_START: u16 = 0xA000;
+pub const RAM_END: u16 = 0xC000;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum CartridgeError {
+    TooShort(usize),
+    InvalidRomSize(u8),
+    InvalidRamSize(u8),
+    UnsupportedType(u8),
+    InvalidSaveSize {expected: usize, actual: usize},
+}
+
+impl fmt::Display for CartridgeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CartridgeError::TooShort(len) => write!(f, "ROM is too short to contain a header ({} bytes)", len),
+            CartridgeError::InvalidRomSize(code) => write!(f, "Invalid ROM size code {:#04X}", code),
+            CartridgeError::InvalidRamSize(code) => write!(f, "Invalid RAM size code {:#04X}", code),
+            CartridgeError::UnsupportedType(code) => write!(f, "Unsupported cartridge type {:#04X}", code),
+            CartridgeError::InvalidSaveSize {expected, actual} => {
+                write!(f, "Save data has {} bytes, the cartridge expects {}", actual, expected)
+            }
+        }
+    }
+}
+
+impl Error for CartridgeError {}
+
+/// Everything living on the cartridge side of the bus: the ROM area (0x0000-0x7FFF),
+/// where writes drive the memory bank controller, and the external RAM area (0xA000-0xBFFF).
+pub trait Cartridge: fmt::Debug {
+    fn header(&self) -> &Header;
+    fn read(&self, addr: u16) -> u8;
+    fn write(&mut self, addr: u16, value: u8);
+
+    /// Advances hardware living on the cartridge, like the MBC3 clock.
+    fn step(&mut self, _cycles: u32) {}
+
+    /// Whether the rumble motor of the cartridge is currently spinning.
+    fn rumble(&self) -> bool {
+        false
+    }
+
+    /// Whether the external RAM keeps its contents when the power is off.
+    fn has_battery(&self) -> bool {
+        self.header().cartridge_type.has_battery()
+    }
+
+    /// Contents of the external RAM followed by any other state which survives power off.
+    fn save_data(&self) -> Vec<u8> {
+        Vec::new()
+    }
+
+    fn load_save_data(&mut self, _data: &[u8]) -> Result<(), CartridgeError> {
+        Ok(())
+    }
+}
+
+impl Default for Box<dyn Cartridge> {
+    fn default() -> Self {
+        Box::new(RomOnly::new(Header::parse(&[0; header::HEADER_END]).unwrap(), Vec::new()))
+    }
+}
+
+fn save_ram(ram: &Ram) -> Vec<u8> {
+    ram.as_slice().to_vec()
+}
+
+fn load_ram(ram: &mut Ram, data: &[u8]) -> Result<(), CartridgeError> {
+    if data.len() != ram.len() {
+        return Err(CartridgeError::InvalidSaveSize {expected: ram.len(), actual: data.len()});
+    }
+    ram.as_mut_slice().copy_from_slice(data);
+    Ok(())
+}
+
+/// Parses the header and builds the cartridge implementation it asks for.
+pub fn load(rom: Vec<u8>) -> Result<Box<dyn Cartridge>, CartridgeError> {
+    load_with_rtc(rom, RtcMode::default())
+}
+
+pub fn load_with_rtc(rom: Vec<u8>, rtc_mode: RtcMode) -> Result<Box<dyn Cartridge>, CartridgeError> {
+    let header = Header::parse(&rom)?;
+    match header.cartridge_type.mapper() {
+        Some(Mapper::None) => Ok(Box::new(RomOnly::new(header, rom))),
+        Some(Mapper::Mbc1) => Ok(Box::new(Mbc1::new(header, rom))),
+        Some(Mapper::Mbc2) => Ok(Box::new(Mbc2::new(header, rom))),
+        Some(Mapper::Mbc3) => Ok(Box::new(Mbc3::new(header, rom, rtc_mode))),
+        Some(Mapper::Mbc5) => Ok(Box::new(Mbc5::new(header, rom))),
+        _ => Err(CartridgeError::UnsupportedType(header.cartridge_type.0)),
+    }
+}
+
+#[cfg(test)]
+pub(crate) fn test_rom(cartridge_type: u8, rom_size: u8, ram_size: u8) -> Vec<u8> {
+    let mut rom = vec![0; (32 * 1024) << rom_size];
+    rom[0x147] = cartridge_type;
+    rom[0x148] = rom_size;
+    rom[0x149] = ram_size;
+    // Tag every bank with its number so bank switching is easy to observe
+    for (bank, chunk) in rom.chunks_mut(0x4000).enumerate().skip(1) {
+        chunk[0] = bank as u8;
+        chunk[1] = (bank >> 8) as u8;
+    }
+    rom
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rom_only() {
+        let mut rom = test_rom(0x00, 0x00, 0x00);
+        rom[0x7FFF] = 0x42;
+        let mut cartridge = load(rom).unwrap();
+        assert_eq!(cartridge.read(0x4000), 1);
+        assert_eq!(cartridge.read(0x7FFF), 0x42);
+        cartridge.write(0x2000, 0x02);
+        assert_eq!(cartridge.read(0x4000), 1);
+        assert_eq!(cartridge.read(0xA000), 0xFF);
+    }
+
+    #[test]
+    fn rom_only_short_rom() {
+        let cartridge = load(vec![0; 0x200]).unwrap();
+        assert_eq!(cartridge.read(0x1FF), 0x00);
+        assert_eq!(cartridge.read(0x7FFF), 0xFF);
+    }
+
+    #[test]
+    fn unsupported_type() {
+        assert_eq!(load(test_rom(0xFC, 0x00, 0x00)).unwrap_err(), CartridgeError::UnsupportedType(0xFC));
+    }
+}
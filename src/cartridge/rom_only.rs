@@ -0,0 +1,55 @@
+use super::{load_ram, save_ram, Cartridge, CartridgeError, Header};
+use super::super::ram::Ram;
+
+/// Plain 32 KiB cartridge without a memory bank controller, optionally with up to 8 KiB of RAM.
+#[derive(Debug)]
+pub struct RomOnly {
+    header: Header,
+    rom: Box<[u8]>,
+    ram: Ram,
+}
+
+impl RomOnly {
+    pub fn new(header: Header, rom: Vec<u8>) -> RomOnly {
+        let ram_size = if header.cartridge_type.has_ram() {header.ram_size} else {0};
+        RomOnly {
+            header,
+            rom: rom.into_boxed_slice(),
+            ram: Ram::new(ram_size),
+        }
+    }
+}
+
+impl Cartridge for RomOnly {
+    fn header(&self) -> &Header {
+        &self.header
+    }
+
+    fn read(&self, addr: u16) -> u8 {
+        match addr {
+            0x0000..=0x7FFF => self.rom.get(addr as usize).copied().unwrap_or(0xFF),
+            0xA000..=0xBFFF => {
+                let ram_addr = (addr - 0xA000) as usize;
+                if ram_addr < self.ram.len() {self.ram.read(ram_addr)} else {0xFF}
+            }
+            _ => 0xFF,
+        }
+    }
+
+    fn write(&mut self, addr: u16, value: u8) {
+        if let 0xA000..=0xBFFF = addr {
+            let ram_addr = (addr - 0xA000) as usize;
+            if ram_addr < self.ram.len() {
+                self.ram.write(ram_addr, value);
+            }
+        }
+    }
+
+    fn save_data(&self) -> Vec<u8> {
+        save_ram(&self.ram)
+    }
+
+    fn load_save_data(&mut self, data: &[u8]) -> Result<(), CartridgeError> {
+        load_ram(&mut self.ram, data)
+    }
+}
@@ -0,0 +1,313 @@
+use std::convert::TryInto;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use super::CartridgeError;
+use super::super::emulator::CYCLES_PER_SECOND;
+
+// How often the host clock is consulted in RtcMode::Host, roughly every millisecond
+const HOST_SYNC_CYCLES: u32 = 4096;
+
+const DH_DAY_HIGH: u8 = 0b0000_0001;
+const DH_HALT: u8 = 0b0100_0000;
+const DH_CARRY: u8 = 0b1000_0000;
+
+/// Size of the clock state appended to the battery RAM, in the layout used by BGB and VBA-M.
+pub const SAVE_SIZE: usize = 48;
+// Older emulators stored the timestamp as a 32 bit value
+const LEGACY_SAVE_SIZE: usize = 44;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum RtcMode {
+    /// The clock is driven by emulated cycles, so it runs as fast as the emulation does.
+    #[default]
+    Emulated,
+    /// The clock follows the host's wall clock regardless of emulation speed.
+    Host,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+struct Registers {
+    seconds: u8,
+    minutes: u8,
+    hours: u8,
+    days: u16,
+    halt: bool,
+    carry: bool,
+}
+
+impl Registers {
+    fn read(&self, reg: u8) -> u8 {
+        match reg {
+            0x08 => self.seconds & 0x3F,
+            0x09 => self.minutes & 0x3F,
+            0x0A => self.hours & 0x1F,
+            0x0B => self.days as u8,
+            0x0C => {
+                let mut dh = (self.days >> 8) as u8 & DH_DAY_HIGH;
+                if self.halt {dh |= DH_HALT}
+                if self.carry {dh |= DH_CARRY}
+                dh
+            }
+            _ => 0xFF,
+        }
+    }
+
+    fn write(&mut self, reg: u8, value: u8) {
+        match reg {
+            0x08 => self.seconds = value & 0x3F,
+            0x09 => self.minutes = value & 0x3F,
+            0x0A => self.hours = value & 0x1F,
+            0x0B => self.days = (self.days & 0x100) | value as u16,
+            0x0C => {
+                self.days = (self.days & 0xFF) | (((value & DH_DAY_HIGH) as u16) << 8);
+                self.halt = value & DH_HALT != 0;
+                self.carry = value & DH_CARRY != 0;
+            }
+            _ => {}
+        }
+    }
+
+    fn is_normalized(&self) -> bool {
+        self.seconds < 60 && self.minutes < 60 && self.hours < 24
+    }
+
+    // Out of range values written by the game keep counting up to the register's
+    // bit width and wrap to 0 without carrying into the next register.
+    fn tick(&mut self) {
+        self.seconds = (self.seconds + 1) & 0x3F;
+        if self.seconds != 60 {
+            return;
+        }
+        self.seconds = 0;
+        self.minutes = (self.minutes + 1) & 0x3F;
+        if self.minutes != 60 {
+            return;
+        }
+        self.minutes = 0;
+        self.hours = (self.hours + 1) & 0x1F;
+        if self.hours != 24 {
+            return;
+        }
+        self.hours = 0;
+        self.days += 1;
+        if self.days == 512 {
+            self.days = 0;
+            self.carry = true;
+        }
+    }
+
+    fn advance(&mut self, mut seconds: u64) {
+        while seconds > 0 && !self.is_normalized() {
+            self.tick();
+            seconds -= 1;
+        }
+        if seconds == 0 {
+            return;
+        }
+        let total = seconds + self.seconds as u64 + self.minutes as u64 * 60 + self.hours as u64 * 3600;
+        self.seconds = (total % 60) as u8;
+        self.minutes = (total / 60 % 60) as u8;
+        self.hours = (total / 3600 % 24) as u8;
+        let days = self.days as u64 + total / 86400;
+        if days >= 512 {
+            self.carry = true;
+        }
+        self.days = (days % 512) as u16;
+    }
+
+    fn save(&self, buf: &mut Vec<u8>) {
+        for reg in 0x08..=0x0C {
+            buf.extend_from_slice(&(self.read(reg) as u32).to_le_bytes());
+        }
+    }
+
+    fn load(data: &[u8]) -> Registers {
+        let mut regs = Registers::default();
+        for (i, reg) in (0x08..=0x0C).enumerate() {
+            regs.write(reg, data[i * 4]);
+        }
+        regs
+    }
+}
+
+/// MBC3 real time clock with its latched copy of the registers.
+#[derive(Debug)]
+pub struct Rtc {
+    mode: RtcMode,
+    current: Registers,
+    latched: Registers,
+    latch_armed: bool,
+    cycles: u32,
+    last_sync: SystemTime,
+}
+
+impl Rtc {
+    pub fn new(mode: RtcMode) -> Rtc {
+        Rtc {
+            mode,
+            current: Registers::default(),
+            latched: Registers::default(),
+            latch_armed: false,
+            cycles: 0,
+            last_sync: SystemTime::now(),
+        }
+    }
+
+    pub fn step(&mut self, cycles: u32) {
+        self.cycles += cycles;
+        match self.mode {
+            RtcMode::Emulated => {
+                while self.cycles >= CYCLES_PER_SECOND {
+                    self.cycles -= CYCLES_PER_SECOND;
+                    if !self.current.halt {
+                        self.current.tick();
+                    }
+                }
+            }
+            RtcMode::Host => {
+                if self.cycles >= HOST_SYNC_CYCLES {
+                    self.cycles = 0;
+                    self.sync_with_host();
+                }
+            }
+        }
+    }
+
+    fn sync_with_host(&mut self) {
+        let elapsed = SystemTime::now().duration_since(self.last_sync).unwrap_or_default();
+        let seconds = elapsed.as_secs();
+        if seconds == 0 {
+            return;
+        }
+        // Keep the sub-second remainder for the next sync
+        self.last_sync += Duration::from_secs(seconds);
+        if !self.current.halt {
+            self.current.advance(seconds);
+        }
+    }
+
+    /// Writing 0x00 and then 0x01 to 0x6000-0x7FFF copies the clock into the readable registers.
+    pub fn write_latch(&mut self, value: u8) {
+        if self.latch_armed && value == 0x01 {
+            if self.mode == RtcMode::Host {
+                self.sync_with_host();
+            }
+            self.latched = self.current;
+        }
+        self.latch_armed = value == 0x00;
+    }
+
+    pub fn read(&self, reg: u8) -> u8 {
+        self.latched.read(reg)
+    }
+
+    pub fn write(&mut self, reg: u8, value: u8) {
+        if reg == 0x08 {
+            // Writing seconds restarts the sub-second divider
+            self.cycles = 0;
+            self.last_sync = SystemTime::now();
+        }
+        self.current.write(reg, value);
+        self.latched.write(reg, value);
+    }
+
+    pub fn save(&self, buf: &mut Vec<u8>) {
+        self.current.save(buf);
+        self.latched.save(buf);
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        buf.extend_from_slice(&timestamp.to_le_bytes());
+    }
+
+    /// Restores the clock and advances it by the time the emulator was not running.
+    pub fn load(&mut self, data: &[u8]) -> Result<(), CartridgeError> {
+        let timestamp = match data.len() {
+            SAVE_SIZE => u64::from_le_bytes(data[40..48].try_into().unwrap()),
+            LEGACY_SAVE_SIZE => u32::from_le_bytes(data[40..44].try_into().unwrap()) as u64,
+            len => return Err(CartridgeError::InvalidSaveSize {expected: SAVE_SIZE, actual: len}),
+        };
+        self.current = Registers::load(&data[0..20]);
+        self.latched = Registers::load(&data[20..40]);
+        self.cycles = 0;
+        self.last_sync = SystemTime::now();
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        if !self.current.halt {
+            self.current.advance(now.saturating_sub(timestamp));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn latch(rtc: &mut Rtc) {
+        rtc.write_latch(0x00);
+        rtc.write_latch(0x01);
+    }
+
+    #[test]
+    fn counts_emulated_cycles() {
+        let mut rtc = Rtc::new(RtcMode::Emulated);
+        rtc.write(0x08, 59);
+        rtc.write(0x09, 59);
+        rtc.write(0x0A, 23);
+        rtc.write(0x0B, 0xFF);
+        rtc.write(0x0C, 0x01);
+        rtc.step(CYCLES_PER_SECOND - 4);
+        latch(&mut rtc);
+        assert_eq!(rtc.read(0x08), 59);
+        rtc.step(4);
+        // the latched registers only change on the next latch
+        assert_eq!(rtc.read(0x08), 59);
+        latch(&mut rtc);
+        assert_eq!(rtc.read(0x08), 0);
+        assert_eq!(rtc.read(0x09), 0);
+        assert_eq!(rtc.read(0x0A), 0);
+        assert_eq!(rtc.read(0x0B), 0);
+        assert_eq!(rtc.read(0x0C), DH_CARRY);
+    }
+
+    #[test]
+    fn halt_stops_the_clock() {
+        let mut rtc = Rtc::new(RtcMode::Emulated);
+        rtc.write(0x0C, DH_HALT);
+        rtc.step(CYCLES_PER_SECOND * 3);
+        latch(&mut rtc);
+        assert_eq!(rtc.read(0x08), 0);
+        rtc.write(0x0C, 0);
+        rtc.step(CYCLES_PER_SECOND * 3);
+        latch(&mut rtc);
+        assert_eq!(rtc.read(0x08), 3);
+    }
+
+    #[test]
+    fn out_of_range_values_wrap_without_carry() {
+        let mut rtc = Rtc::new(RtcMode::Emulated);
+        rtc.write(0x08, 63);
+        rtc.step(CYCLES_PER_SECOND);
+        latch(&mut rtc);
+        assert_eq!(rtc.read(0x08), 0);
+        assert_eq!(rtc.read(0x09), 0);
+    }
+
+    #[test]
+    fn save_and_load_advance_time() {
+        let mut rtc = Rtc::new(RtcMode::Emulated);
+        rtc.write(0x0A, 5);
+        let mut save = Vec::new();
+        rtc.save(&mut save);
+        assert_eq!(save.len(), SAVE_SIZE);
+        // pretend the save was written two days and a minute ago
+        let timestamp = u64::from_le_bytes(save[40..48].try_into().unwrap()) - (2 * 86400 + 60);
+        save[40..48].copy_from_slice(&timestamp.to_le_bytes());
+
+        let mut loaded = Rtc::new(RtcMode::Emulated);
+        loaded.load(&save).unwrap();
+        latch(&mut loaded);
+        assert_eq!(loaded.read(0x09), 1);
+        assert_eq!(loaded.read(0x0A), 5);
+        assert_eq!(loaded.read(0x0B), 2);
+    }
+}
@@ -1,5 +1,6 @@
 use super::bitvec::prelude::*;
 use super::bus;
+use super::error::{EmuError, Strictness};
 
 #[derive(Debug, Default)]
 pub struct Cpu {
@@ -11,29 +12,31 @@ pub struct Cpu {
     sp: u16,
     pc: u16,
     ime: bool,
-    clock_freq: u32,
-    div_cycles: u16,
-    timer_cycles: u32,
     is_halted: bool,
+    // STOP only ends when a joypad input line goes low
+    is_stopped: bool,
+    // Set by illegal opcodes, only a reset gets the CPU going again
+    is_locked: bool,
     i: u64, //debug
 }
 
 impl Cpu {
 
     pub fn new() -> Cpu {
-        let mut cpu: Cpu = Default::default();
         // TODO: Implement BOOT ROM properly
-        cpu.pc = 0x100;
-        cpu.sp = 0xFFFE;
+        let mut cpu = Cpu {
+            pc: 0x100,
+            sp: 0xFFFE,
+            ime: false,
+            is_halted: false,
+            i: 1,
+            ..Default::default()
+        };
         //cpu.reg_af[8..16].store_be(0xB0);
         cpu.reg_af.store_be(0x01B0);
         cpu.reg_bc.store_be(0x0013);
         cpu.reg_de.store_be(0x00D8);
         cpu.reg_hl.store_be(0x014D);
-        cpu.ime = false;
-        cpu.clock_freq = 4194304;
-        cpu.is_halted = false;
-        cpu.i = 1;
         cpu
     }
 
@@ -41,27 +44,99 @@ impl Cpu {
         self.bus = bus; 
     } 
 
-    pub fn run_next_instruction(&mut self) {
+    pub fn bus(&self) -> &bus::Bus {
+        &self.bus
+    }
+
+    pub fn bus_mut(&mut self) -> &mut bus::Bus {
+        &mut self.bus
+    }
+
+    pub fn pc(&self) -> u16 {
+        self.pc
+    }
+
+    pub fn sp(&self) -> u16 {
+        self.sp
+    }
+
+    pub fn set_pc(&mut self, pc: u16) {
+        self.pc = pc;
+    }
+
+    pub fn set_sp(&mut self, sp: u16) {
+        self.sp = sp;
+    }
+
+    /// Calls the routine at `addr` as if a CALL instruction was executed at the current PC,
+    /// for running code on behalf of the host like the GBS player does.
+    pub fn call(&mut self, addr: u16) {
+        self.push(self.pc);
+        self.pc = addr;
+    }
+
+    pub fn ime(&self) -> bool {
+        self.ime
+    }
+
+    pub fn is_halted(&self) -> bool {
+        self.is_halted
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.is_stopped
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.is_locked
+    }
+
+    /// Runs one instruction and steps the rest of the hardware by its cycles. Errors raised
+    /// by the instruction, by the hardware stepped alongside it and by interrupt dispatch are
+    /// all reported against the pc and opcode of this instruction.
+    pub fn run_next_instruction(&mut self) -> Result<u8, EmuError> {
+        let (cycles, result) = self.step();
+        result.map(|_| cycles)
+    }
+
+    /// Same as `run_next_instruction`, but the cycles are returned even when it fails since
+    /// the hardware was stepped by them either way.
+    pub(crate) fn step(&mut self) -> (u8, Result<(), EmuError>) {
+        if self.is_stopped {
+            // The whole system clock is stopped, only the joypad can wake it
+            if self.bus.io().joypad().is_line_low() {
+                self.is_stopped = false;
+            }
+            return (4, Ok(()));
+        }
+        let pc = self.pc;
+        self.bus.begin_instruction();
         let inst = self.bus.read(self.pc);
-        let cycles = if self.is_halted {4} else {self.perform_instruction(inst)};
-        self.handle_timer(cycles);
+        let cycles = if self.is_halted || self.is_locked {4} else {self.perform_instruction(inst)};
+        self.bus.end_instruction(cycles);
         self.handle_interrupts();
+        if let Some(error) = self.bus.take_error() {
+            return (cycles, Err(EmuError::Bus {pc, opcode: inst, error}));
+        }
+        if self.is_locked && self.bus.strictness() == Strictness::Strict {
+            return (cycles, Err(EmuError::UnknownOpcode {pc, opcode: inst}));
+        }
+        (cycles, Ok(()))
     }
 
     pub fn perform_instruction(&mut self, inst: u8) -> u8 {
         //println!("{}: Instruction 0x{:02X} @ {:#X}: A: {:02X}, F: {:02X}, B: {:02X}, C: {:02X}, D: {:02x}, E: {:02x}, H: {:02X}, L: {:02X}, SP: {:02X}", self.i, inst, self.pc, self.get_reg_a(), self.get_reg_f(), self.get_reg_b(), self.get_reg_c(), self.get_reg_d(), self.get_reg_e(), self.get_reg_h(), self.get_reg_l(), self.sp);
-        // https://github.com/retrio/gb-test-roms/tree/master/cpu_instrs
-        // CONSOLE OUTPUT
         let mut cycles = 4;
         self.i += 1;
-        if self.bus.read(0xff02) == 0x81 {
-            print!("{}", self.bus.read(0xff01) as char);
-            self.bus.write(0xff02, 0x0);
-        }
         match inst {
             // SPECIAL
             0x00 => self.pc += 1,
-            0x10 => {self.is_halted = true; self.pc += 1;},
+            0x10 => {
+                // STOP is followed by a padding byte and resets DIV
+                self.is_stopped = true;
+                self.bus.write(0xFF04, 0);
+                self.pc += 2;
+            },
             0x76 => {self.is_halted = true; self.pc += 1;},
             0xCB => {
                 self.pc += 1;
@@ -127,17 +202,20 @@ impl Cpu {
             }
             // LD 16 bit
             0x01 => {
-                self.set_reg_bc(self.read_u16());
+                let value = self.read_u16();
+                self.set_reg_bc(value);
                 self.pc +=3;
                 cycles = 12;
             }
             0x11 => {
-                self.set_reg_de(self.read_u16());
+                let value = self.read_u16();
+                self.set_reg_de(value);
                 self.pc +=3;
                 cycles = 12;
             }
             0x21 => {
-                self.set_reg_hl(self.read_u16());
+                let value = self.read_u16();
+                self.set_reg_hl(value);
                 self.pc +=3;
                 cycles = 12;
             }
@@ -147,7 +225,8 @@ impl Cpu {
                 cycles = 12;
             }
             0xF8 => {
-                let res = self.add_i16(self.sp as i16, self.bus.read(self.pc+1) as i8 as i16);
+                let value = self.bus.read(self.pc+1);
+                let res = self.add_i16(self.sp as i16, value as i8 as i16);
                 self.set_reg_hl(res as u16);
                 self.pc +=2;
                 cycles = 12;
@@ -180,66 +259,78 @@ impl Cpu {
             }
             0x0A => {
                 let addr = self.get_reg_bc();
-                self.set_reg_a(self.bus.read(addr));
+                let value = self.bus.read(addr);
+                self.set_reg_a(value);
                 self.pc +=1;
                 cycles = 8;
             }
             0x06 => {
-                self.set_reg_b(self.bus.read(self.pc+1));
+                let value = self.bus.read(self.pc+1);
+                self.set_reg_b(value);
                 self.pc +=2;
                 cycles = 8;
             }
             0x16 => {
-                self.set_reg_d(self.bus.read(self.pc+1));
+                let value = self.bus.read(self.pc+1);
+                self.set_reg_d(value);
                 self.pc +=2;
                 cycles = 8;
             }
             0x26 => {
-                self.set_reg_h(self.bus.read(self.pc+1));
+                let value = self.bus.read(self.pc+1);
+                self.set_reg_h(value);
                 self.pc +=2;
                 cycles = 8;
             }
             0x36 => {
-                self.bus.write(self.get_reg_hl(), self.bus.read(self.pc+1));
+                let value = self.bus.read(self.pc+1);
+                self.bus.write(self.get_reg_hl(), value);
                 self.pc +=2;
                 cycles = 12;
             }
             0x0E => {
-                self.set_reg_c(self.bus.read(self.pc+1));
+                let value = self.bus.read(self.pc+1);
+                self.set_reg_c(value);
                 self.pc +=2;
                 cycles = 8;
             }
             0x1E => {
-                self.set_reg_e(self.bus.read(self.pc+1));
+                let value = self.bus.read(self.pc+1);
+                self.set_reg_e(value);
                 self.pc +=2;
                 cycles = 8;
             }
             0x2E => {
-                self.set_reg_l(self.bus.read(self.pc+1));
+                let value = self.bus.read(self.pc+1);
+                self.set_reg_l(value);
                 self.pc +=2;
                 cycles = 8;
             }
             0x3E => {
-                self.set_reg_a(self.bus.read(self.pc+1));
+                let value = self.bus.read(self.pc+1);
+                self.set_reg_a(value);
                 self.pc +=2;
                 cycles = 8;
             }
             0x1A => {
                 let addr = self.get_reg_de();
-                self.set_reg_a(self.bus.read(addr));
+                let value = self.bus.read(addr);
+                self.set_reg_a(value);
                 self.pc +=1;
                 cycles = 8;
             }
             0x2A => {
                 let addr = self.get_reg_hl();
-                self.set_reg_a(self.bus.read(addr));
+                let value = self.bus.read(addr);
+                self.set_reg_a(value);
                 self.set_reg_hl(addr+1);
                 self.pc +=1;
                 cycles = 8;
             }
             0x3A => {
                 let addr = self.get_reg_hl();
-                self.set_reg_a(self.bus.read(addr));
+                let value = self.bus.read(addr);
+                self.set_reg_a(value);
                 self.set_reg_hl(addr-1);
                 self.pc +=1;
                 cycles = 8;
@@ -269,7 +360,8 @@ impl Cpu {
                 self.pc +=1;
             }
             0x46 => {
-                self.set_reg_b(self.bus.read(self.get_reg_hl()));
+                let value = self.bus.read(self.get_reg_hl());
+                self.set_reg_b(value);
                 self.pc +=1;
                 cycles = 8;
             }
@@ -302,7 +394,8 @@ impl Cpu {
                 self.pc +=1;
             }
             0x4E => {
-                self.set_reg_c(self.bus.read(self.get_reg_hl()));
+                let value = self.bus.read(self.get_reg_hl());
+                self.set_reg_c(value);
                 self.pc +=1;
                 cycles = 8;
             }
@@ -335,7 +428,8 @@ impl Cpu {
                 self.pc +=1;
             }
             0x56 => {
-                self.set_reg_d(self.bus.read(self.get_reg_hl()));
+                let value = self.bus.read(self.get_reg_hl());
+                self.set_reg_d(value);
                 self.pc +=1;
                 cycles = 8;
             }
@@ -368,7 +462,8 @@ impl Cpu {
                 self.pc +=1;
             }
             0x5E => {
-                self.set_reg_e(self.bus.read(self.get_reg_hl()));
+                let value = self.bus.read(self.get_reg_hl());
+                self.set_reg_e(value);
                 self.pc +=1;
                 cycles = 8;
             }
@@ -379,6 +474,7 @@ impl Cpu {
             0x70 => {
                 self.bus.write(self.get_reg_hl(), self.get_reg_b());
                 self.pc +=1;
+                cycles = 8;
             }
             0x60 => {
                 self.set_reg_h(self.get_reg_b());
@@ -405,7 +501,8 @@ impl Cpu {
                 self.pc +=1;
             }
             0x66 => {
-                self.set_reg_h(self.bus.read(self.get_reg_hl()));
+                let value = self.bus.read(self.get_reg_hl());
+                self.set_reg_h(value);
                 self.pc +=1;
                 cycles = 8;
             }
@@ -438,7 +535,8 @@ impl Cpu {
                 self.pc +=1;
             }
             0x6E => {
-                self.set_reg_l(self.bus.read(self.get_reg_hl()));
+                let value = self.bus.read(self.get_reg_hl());
+                self.set_reg_l(value);
                 self.pc +=1;
                 cycles = 8;
             }
@@ -501,7 +599,8 @@ impl Cpu {
                 self.pc +=1;
             }
             0x7E => {
-                self.set_reg_a(self.bus.read(self.get_reg_hl()));
+                let value = self.bus.read(self.get_reg_hl());
+                self.set_reg_a(value);
                 self.pc +=1;
                 cycles = 8;
             }
@@ -511,7 +610,8 @@ impl Cpu {
             }
             0xFA => {
                 let addr = self.read_u16();
-                self.set_reg_a(self.bus.read(addr));
+                let value = self.bus.read(addr);
+                self.set_reg_a(value);
                 self.pc +=3;
                 cycles = 16;
             }
@@ -523,7 +623,8 @@ impl Cpu {
             }
             0xF2 => {
                 let n: u16 = self.get_reg_c().into();
-                self.set_reg_a(self.bus.read(0xFF00 + n));
+                let value = self.bus.read(0xFF00 + n);
+                self.set_reg_a(value);
                 self.pc +=1;
                 cycles = 8;
             }
@@ -553,13 +654,15 @@ impl Cpu {
                 cycles = 8;
             }
             0xC6 => {
-                let res = self.add_8(self.get_reg_a(), self.bus.read(self.pc+1));
+                let value = self.bus.read(self.pc+1);
+                let res = self.add_8(self.get_reg_a(), value);
                 self.set_reg_a(res);
                 self.pc +=2;
                 cycles = 8;
             }
             0xE8 => {
-                let res = self.add_i16(self.sp as i16, self.bus.read(self.pc+1) as i8 as i16);
+                let value = self.bus.read(self.pc+1);
+                let res = self.add_i16(self.sp as i16, value as i8 as i16);
                 self.sp = res as u16;
                 self.pc +=2;
                 cycles = 16;
@@ -590,7 +693,8 @@ impl Cpu {
                 self.pc +=1;
             }
             0x86 => {
-                self.add_a(self.bus.read(self.get_reg_hl()));
+                let value = self.bus.read(self.get_reg_hl());
+                self.add_a(value);
                 self.pc +=1;
                 cycles = 8;
             }
@@ -624,7 +728,8 @@ impl Cpu {
                 self.pc +=1;
             }
             0x8E => {
-                self.add_a_c(self.bus.read(self.get_reg_hl()));
+                let value = self.bus.read(self.get_reg_hl());
+                self.add_a_c(value);
                 self.pc +=1;
                 cycles = 8;
             }
@@ -633,13 +738,15 @@ impl Cpu {
                 self.pc +=1;
             }
             0xCE => {
-                self.add_a_c(self.bus.read(self.pc+1));
+                let value = self.bus.read(self.pc+1);
+                self.add_a_c(value);
                 self.pc +=2;
                 cycles = 8;
             }
             // SUB
             0xD6 => {
-                self.sub_a(self.bus.read(self.pc+1));
+                let value = self.bus.read(self.pc+1);
+                self.sub_a(value);
                 self.pc +=2;
                 cycles = 8;
             }
@@ -668,7 +775,8 @@ impl Cpu {
                 self.pc +=1;
             }
             0x96 => {
-                self.sub_a(self.bus.read(self.get_reg_hl()));
+                let value = self.bus.read(self.get_reg_hl());
+                self.sub_a(value);
                 self.pc +=1;
                 cycles = 8;
             }
@@ -702,7 +810,8 @@ impl Cpu {
                 self.pc +=1;
             }
             0x9E => {
-                self.sub_a_c(self.bus.read(self.get_reg_hl()));
+                let value = self.bus.read(self.get_reg_hl());
+                self.sub_a_c(value);
                 self.pc +=1;
                 cycles = 8;
             }
@@ -711,7 +820,8 @@ impl Cpu {
                 self.pc +=1;
             }
             0xDE => {
-                self.sub_a_c(self.bus.read(self.pc+1));
+                let value = self.bus.read(self.pc+1);
+                self.sub_a_c(value);
                 self.pc +=2;
                 cycles = 8;
             }
@@ -795,7 +905,8 @@ impl Cpu {
                 self.pc += 1;
             }
             0xA6 => {
-                self.and_a(self.bus.read(self.get_reg_hl()));
+                let value = self.bus.read(self.get_reg_hl());
+                self.and_a(value);
                 self.pc += 1;
                 cycles = 8;
             }
@@ -804,7 +915,8 @@ impl Cpu {
                 self.pc += 1;
             }
             0xE6 => {
-                self.and_a(self.bus.read(self.pc+1));
+                let value = self.bus.read(self.pc+1);
+                self.and_a(value);
                 self.pc += 2;
                 cycles = 8;
             }
@@ -834,12 +946,14 @@ impl Cpu {
                 self.pc += 1;
             }
             0xB6 => {
-                self.or_a(self.bus.read(self.get_reg_hl()));
+                let value = self.bus.read(self.get_reg_hl());
+                self.or_a(value);
                 self.pc += 1;
                 cycles = 8;
             }
             0xF6 => {
-                self.or_a(self.bus.read(self.pc+1));
+                let value = self.bus.read(self.pc+1);
+                self.or_a(value);
                 self.pc += 2;
                 cycles = 8;
             }
@@ -917,7 +1031,7 @@ impl Cpu {
                 let addr_offset = self.bus.read(self.pc+1) as i8;
                 self.pc +=2;
                 cycles = 8;
-                if self.get_z_flag() == false {
+                if !self.get_z_flag() {
                     self.pc = (self.pc as i16 + addr_offset as i16) as u16;
                     cycles = 12;
                 } 
@@ -935,7 +1049,7 @@ impl Cpu {
                 let addr_offset = self.bus.read(self.pc+1) as i8;
                 self.pc +=2;
                 cycles = 8;
-                if self.get_c_flag() == false {
+                if !self.get_c_flag() {
                     self.pc = (self.pc as i16 + addr_offset as i16) as u16;
                     cycles = 12;
                 } 
@@ -965,7 +1079,7 @@ impl Cpu {
             }
             // JUMP
             0xC2 => {
-                if self.get_z_flag() == false {
+                if !self.get_z_flag() {
                     let lsb = self.bus.read(self.pc+1);
                     let msb = self.bus.read(self.pc+2);
                     self.pc = u16::from_le_bytes([lsb, msb]);
@@ -976,7 +1090,7 @@ impl Cpu {
                 }
             }
             0xD2 => {
-                if self.get_c_flag() == false {
+                if !self.get_c_flag() {
                     let lsb = self.bus.read(self.pc+1);
                     let msb = self.bus.read(self.pc+2);
                     self.pc = u16::from_le_bytes([lsb, msb]);
@@ -1032,7 +1146,7 @@ impl Cpu {
             0xC0 => {
                 self.pc += 1;
                 cycles = 8;
-                if self.get_z_flag() == false {
+                if !self.get_z_flag() {
                     let value = self.pop();
                     self.pc = value;
                     cycles = 20;
@@ -1050,7 +1164,7 @@ impl Cpu {
             0xD0 => {
                 self.pc += 1;
                 cycles = 8;
-                if self.get_c_flag() == false {
+                if !self.get_c_flag() {
                     let value = self.pop();
                     self.pc = value;
                     cycles = 20;
@@ -1091,7 +1205,8 @@ impl Cpu {
                 self.pc +=1;
             }
             0xAE => {
-                self.xor_a(self.bus.read(self.get_reg_hl()));
+                let value = self.bus.read(self.get_reg_hl());
+                self.xor_a(value);
                 self.pc +=1;
                 cycles = 8;
             }
@@ -1100,7 +1215,8 @@ impl Cpu {
                 self.pc +=1;
             }
             0xEE => {
-                self.xor_a(self.bus.read(self.pc+1));
+                let value = self.bus.read(self.pc+1);
+                self.xor_a(value);
                 self.pc +=2;
                 cycles = 8;
             }
@@ -1109,7 +1225,7 @@ impl Cpu {
                 let addr = self.read_u16();
                 self.pc += 3;
                 cycles = 12;
-                if self.get_z_flag() == false {
+                if !self.get_z_flag() {
                     self.push(self.pc);
                     self.pc = addr;
                     cycles = 24;
@@ -1131,7 +1247,7 @@ impl Cpu {
                 let addr = self.read_u16();
                 self.pc += 3;
                 cycles = 12;
-                if self.get_c_flag() == false {
+                if !self.get_c_flag() {
                     self.push(self.pc);
                     self.pc = addr;
                     cycles = 24;
@@ -1273,7 +1389,8 @@ impl Cpu {
             }
             0xF0 => {
                 let n: u16 = self.bus.read(self.pc+1).into();
-                self.set_reg_a(self.bus.read(0xFF00 + n));
+                let value = self.bus.read(0xFF00 + n);
+                self.set_reg_a(value);
                 self.pc += 2;
                 cycles = 12;
             }
@@ -1303,7 +1420,8 @@ impl Cpu {
                 self.pc += 1;
             }
             0xBE => {
-                self.cp(self.bus.read(self.get_reg_hl()));
+                let value = self.bus.read(self.get_reg_hl());
+                self.cp(value);
                 self.pc += 1;
                 cycles = 8;
             }
@@ -1312,19 +1430,21 @@ impl Cpu {
                 self.pc += 1;
             }
             0xFE => {
-                self.cp(self.bus.read(self.pc+1));
+                let value = self.bus.read(self.pc+1);
+                self.cp(value);
                 self.pc += 2;
                 cycles = 8;
             }
-            _ => panic!("Unknown instruction 0x{:02X}", inst)
+            // 0xD3, 0xDB, 0xDD, 0xE3, 0xE4, 0xEB, 0xEC, 0xED, 0xF4, 0xFC and 0xFD hang the CPU
+            _ => self.is_locked = true,
         };
 
-        return cycles;
+        cycles
     }
 
-    pub fn run(&mut self) {
+    pub fn run(&mut self) -> Result<(), EmuError> {
         loop {
-            self.run_next_instruction();
+            self.run_next_instruction()?;
         }
     }
 
@@ -1337,7 +1457,7 @@ impl Cpu {
         self.set_c_flag(a < value);
     }
 
-    fn read_u16(&self) -> u16 {
+    fn read_u16(&mut self) -> u16 {
         let lsb = self.bus.read(self.pc+1);
         let msb = self.bus.read(self.pc+2);
         u16::from_le_bytes([lsb, msb])
@@ -1357,7 +1477,7 @@ impl Cpu {
         let msb = self.bus.read(self.sp); 
         self.sp += 1;
 
-        return u16::from_le_bytes([lsb,msb]);
+        u16::from_le_bytes([lsb,msb])
     
     }
 
@@ -1448,7 +1568,7 @@ impl Cpu {
         self.set_h_flag((a & 0b1111111111) + (b & 0b1111111111) > 0b1111111111);
         self.set_c_flag(carry);
 
-        return res
+        res
     }
 
     // Used in 0xE8 = ADD SP, r8
@@ -1464,7 +1584,7 @@ impl Cpu {
             self.set_c_flag((res & 0b11111111) <= (a & 0b11111111));
         }
 
-        return res
+        res
     }
 
     fn add_8(&mut self, a: u8, b: u8) -> u8 {
@@ -1474,7 +1594,7 @@ impl Cpu {
         self.set_h_flag((a & 0b1111) + (b & 0b1111) > 0b1111);
         self.set_c_flag(carry);
 
-        return res
+        res
     }
 
     fn add_a_c(&mut self, value: u8) {
@@ -1542,7 +1662,7 @@ impl Cpu {
         self.reg_hl.store_be(value);
     }
 
-    fn set_reg_a(&mut self, value: u8) {
+    pub fn set_reg_a(&mut self, value: u8) {
         self.reg_af[0..8].store_be(value);
     }
 
@@ -1570,7 +1690,7 @@ impl Cpu {
         self.reg_hl[8..16].store_be(value);
     }
 
-    fn get_reg_af(&self) -> u16 {
+    pub fn get_reg_af(&self) -> u16 {
         self.reg_af.load_be::<u16>()
     }
 
@@ -1930,10 +2050,9 @@ impl Cpu {
             0xFD => self.set(7, "L"),
             0xFE => self.set(7, "HL"),
             0xFF => self.set(7, "A"),
-            _ => panic!("Unknown CB instruction 0x{:02X}", inst)
         }
 
-        return cycles;
+        cycles
     }
 
     fn rlc(&mut self, reg_id: &str) {
@@ -2205,7 +2324,7 @@ impl Cpu {
         if reg_id == "HL" {
             let mut val = bitarr!(u8, Msb0; 0; 8);
             val.store_be(self.bus.read(self.get_reg_hl()));
-            let z = val[7 - bit] == false;
+            let z = !val[7 - bit];
             self.set_z_flag(z);
             self.set_n_flag(false);
             self.set_h_flag(true);
@@ -2270,12 +2389,19 @@ impl Cpu {
     }
 
     fn handle_interrupts(&mut self) {
-        if !self.ime {
+        if self.is_locked {
             return;
         }
 
         let ie_val = self.bus.read(0xFFFF);
         let if_val = self.bus.read(0xFF0F);
+        // HALT ends as soon as an interrupt is pending, even with IME off
+        if ie_val & if_val & 0x1F != 0 {
+            self.is_halted = false;
+        }
+        if !self.ime {
+            return;
+        }
         let mut ie = bitarr!(u8, Msb0; 0; 8);
         ie.store_be(ie_val);
         let mut r#if = bitarr!(u8, Msb0; 0; 8);
@@ -2293,42 +2419,6 @@ impl Cpu {
         }
     }
 
-    fn handle_timer(&mut self, cycles: u8) {
-        // update div, freq 4194304/16384
-        self.div_cycles += cycles as u16;
-        if self.div_cycles >= 1024 {
-            self.div_cycles -= 1024;
-            self.bus.increment_div();
-        }
-
-        let tac = self.bus.read(0xFF07);
-        // is timer enabled
-        if tac & 0b100 == 0 {
-            return
-        }
-        let freq = match tac & 0b11 {
-            0 => 4096,
-            1 => 262144,
-            2 => 65536,
-            3 => 16384,
-            _ => panic!("can't happen"),
-        };
-        self.timer_cycles += cycles as u32;
-
-        let ratio = self.clock_freq / freq;
-        while self.timer_cycles as u32  >= ratio {
-            let tima = self.bus.read(0xFF05);
-            if tima == 0xFF {
-                self.bus.write(0xFF05, self.bus.read(0xFF06));
-                self.bus.write(0xFF0F, self.bus.read(0xFF0F) | 0b00100); 
-                self.is_halted = false;
-            } else {
-                self.bus.write(0xFF05, tima + 1);
-            }
-            self.timer_cycles -= ratio;
-        }
-    }
-
     /*fn decrement_register(&mut self, reg_id: &str) {*/
         /*let reg = match reg_id {*/
             /*"A" => &mut self.reg_af[0..8],*/
@@ -2353,18 +2443,18 @@ mod tests {
     use super::*;
     #[test]
     fn reg_af() {
-        let mut cpu = Cpu::new();
+        let mut cpu = Cpu::default();
         assert_eq!(cpu.get_reg_af(), 0);
         cpu.set_reg_af(0b1010101010101010);
-        assert_eq!(cpu.get_reg_af(), 0b1010101010101010);
+        assert_eq!(cpu.get_reg_af(), 0b1010101010100000);
         cpu.set_reg_a(0b11100000);
-        assert_eq!(cpu.get_reg_af(), 0b1110000010101010);
+        assert_eq!(cpu.get_reg_af(), 0b1110000010100000);
         assert_eq!(cpu.get_reg_a(), 0b11100000);
     }
 
     #[test]
     fn reg_bc() {
-        let mut cpu = Cpu::new();
+        let mut cpu = Cpu::default();
         assert_eq!(cpu.get_reg_bc(), 0);
         cpu.set_reg_bc(0b1010101010101010);
         assert_eq!(cpu.get_reg_bc(), 0b1010101010101010);
@@ -2378,7 +2468,7 @@ mod tests {
 
     #[test]
     fn reg_de() {
-        let mut cpu = Cpu::new();
+        let mut cpu = Cpu::default();
         assert_eq!(cpu.get_reg_de(), 0);
         cpu.set_reg_de(0b1010101010101010);
         assert_eq!(cpu.get_reg_de(), 0b1010101010101010);
@@ -2392,7 +2482,7 @@ mod tests {
 
     #[test]
     fn reg_hl() {
-        let mut cpu = Cpu::new();
+        let mut cpu = Cpu::default();
         assert_eq!(cpu.get_reg_hl(), 0);
         cpu.set_reg_hl(0b1010101010101010);
         assert_eq!(cpu.get_reg_hl(), 0b1010101010101010);
@@ -2406,47 +2496,48 @@ mod tests {
 
     #[test]
     fn inst_0x05() {
-        let mut cpu = Cpu::new();
-        assert_eq!(cpu.get_h_flag(), false);
+        let mut cpu = Cpu::default();
+        assert!(!cpu.get_h_flag());
         cpu.set_reg_b(0b10000);
         cpu.perform_instruction(0x05);
         assert_eq!(cpu.get_reg_b(), 0b1111);
-        assert_eq!(cpu.get_n_flag(), true);
-        assert_eq!(cpu.get_h_flag(), true);
+        assert!(cpu.get_n_flag());
+        assert!(cpu.get_h_flag());
         cpu.reset_flags();
         cpu.set_reg_b(0b100);
         cpu.perform_instruction(0x05);
         assert_eq!(cpu.get_reg_b(), 0b11);
-        assert_eq!(cpu.get_n_flag(), true);
-        assert_eq!(cpu.get_h_flag(), false);
+        assert!(cpu.get_n_flag());
+        assert!(!cpu.get_h_flag());
         cpu.reset_flags();
         cpu.set_reg_b(0b0);
         cpu.perform_instruction(0x05);
         assert_eq!(cpu.get_reg_b(), 0b11111111);
-        assert_eq!(cpu.get_n_flag(), true);
-        assert_eq!(cpu.get_h_flag(), true);
+        assert!(cpu.get_n_flag());
+        assert!(cpu.get_h_flag());
     }
 
     #[test]
+    #[allow(non_snake_case)]
     fn inst_0x0D() {
-        let mut cpu = Cpu::new();
-        assert_eq!(cpu.get_h_flag(), false);
+        let mut cpu = Cpu::default();
+        assert!(!cpu.get_h_flag());
         cpu.set_reg_c(0b10000);
         cpu.perform_instruction(0x0D);
         assert_eq!(cpu.get_reg_c(), 0b1111);
-        assert_eq!(cpu.get_n_flag(), true);
-        assert_eq!(cpu.get_h_flag(), true);
+        assert!(cpu.get_n_flag());
+        assert!(cpu.get_h_flag());
         cpu.reset_flags();
         cpu.set_reg_c(0b100);
         cpu.perform_instruction(0x0D);
         assert_eq!(cpu.get_reg_c(), 0b11);
-        assert_eq!(cpu.get_n_flag(), true);
-        assert_eq!(cpu.get_h_flag(), false);
+        assert!(cpu.get_n_flag());
+        assert!(!cpu.get_h_flag());
         cpu.reset_flags();
         cpu.set_reg_c(0b0);
         cpu.perform_instruction(0x0D);
         assert_eq!(cpu.get_reg_c(), 0b11111111);
-        assert_eq!(cpu.get_n_flag(), true);
-        assert_eq!(cpu.get_h_flag(), true);
+        assert!(cpu.get_n_flag());
+        assert!(cpu.get_h_flag());
     }
 }
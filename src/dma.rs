@@ -0,0 +1,103 @@
+pub const REGISTER: u16 = 0xFF46;
+
+const LENGTH: u16 = 160;
+// M-cycles between the write to FF46 and the first copied byte
+const START_DELAY: u8 = 1;
+
+/// OAM DMA, copies XX00-XX9F into OAM at one byte per M-cycle.
+#[derive(Debug, Default)]
+pub struct Dma {
+    register: u8,
+    // Source of the running transfer and the offset of the next byte to copy
+    active: Option<(u16, u16)>,
+    // Transfer requested through FF46, it takes over once the delay runs out.
+    // A running transfer keeps going until then.
+    pending: Option<(u16, u8)>,
+    // Last byte the transfer read, what the CPU sees on a conflicting bus
+    last_byte: u8,
+}
+
+impl Dma {
+    pub fn read(&self) -> u8 {
+        self.register
+    }
+
+    pub fn write(&mut self, value: u8) {
+        self.register = value;
+        let mut source = (value as u16) << 8;
+        // There's no OAM or IO behind the transfer, the upper pages read work RAM
+        if source >= 0xE000 {
+            source -= 0x2000;
+        }
+        self.pending = Some((source, START_DELAY));
+    }
+
+    /// Base address of the running transfer.
+    pub fn source(&self) -> Option<u16> {
+        self.active.map(|(source, _)| source)
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.active.is_some()
+    }
+
+    pub fn last_byte(&self) -> u8 {
+        self.last_byte
+    }
+
+    /// Advances the transfer by one M-cycle and returns the address to copy from,
+    /// its low byte is the offset into OAM. The bus answers through `copied`.
+    pub fn tick(&mut self) -> Option<u16> {
+        match self.pending {
+            Some((source, 0)) => {
+                self.pending = None;
+                self.active = Some((source, 0));
+            }
+            Some((source, delay)) => self.pending = Some((source, delay - 1)),
+            None => {}
+        }
+        self.active.map(|(source, offset)| {
+            self.active = if offset + 1 < LENGTH {Some((source, offset + 1))} else {None};
+            source + offset
+        })
+    }
+
+    pub fn copied(&mut self, value: u8) {
+        self.last_byte = value;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn transfer() {
+        let mut dma = Dma::default();
+        dma.write(0xC1);
+        assert_eq!(dma.read(), 0xC1);
+        assert_eq!(dma.tick(), None);
+        assert!(!dma.is_active());
+        assert_eq!(dma.source(), None);
+        for offset in 0..LENGTH {
+            assert_eq!(dma.tick(), Some(0xC100 + offset));
+        }
+        assert!(!dma.is_active());
+        assert_eq!(dma.tick(), None);
+    }
+
+    #[test]
+    fn restart() {
+        let mut dma = Dma::default();
+        dma.write(0x80);
+        dma.tick();
+        for offset in 0..10 {
+            assert_eq!(dma.tick(), Some(0x8000 + offset));
+        }
+        // the old transfer runs on while the new one is being set up
+        dma.write(0xFE);
+        assert_eq!(dma.tick(), Some(0x800A));
+        assert_eq!(dma.tick(), Some(0xDE00));
+        assert_eq!(dma.source(), Some(0xDE00));
+    }
+}
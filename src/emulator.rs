@@ -0,0 +1,465 @@
+use std::fmt;
+
+use super::bus::{self, Model};
+use super::cartridge::{self, Cartridge, CartridgeError, Header, RtcMode};
+use super::cpu;
+use super::error::{EmuError, Strictness};
+use super::io::joypad::Button;
+use super::io::serial::SerialDevice;
+use super::io::sound::SoundController;
+use super::ram;
+
+const WRAM_CAPACITY: usize = 8 * 1024;
+const HRAM_CAPACITY: usize = 127;
+const VRAM_CAPACITY: usize = 8 * 1024;
+const OAM_CAPACITY: usize = 160;
+
+/// Clock cycles per second of the DMG's 4 MiHz clock.
+pub const CYCLES_PER_SECOND: u32 = 4_194_304;
+
+/// Number of clock cycles the DMG spends on one full frame (154 lines * 456 cycles).
+pub const CYCLES_PER_FRAME: u32 = 70224;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Config {
+    pub model: Model,
+    pub rtc_mode: RtcMode,
+    pub strictness: Strictness,
+}
+
+/// Called with the new motor state whenever a rumble cartridge starts or stops vibrating.
+pub type RumbleCallback = Box<dyn FnMut(bool)>;
+
+/// Entry point for embedding rustboy: owns the CPU (and through it the bus)
+/// and drives them instruction by instruction or frame by frame.
+pub struct Emulator {
+    cpu: cpu::Cpu,
+    config: Config,
+    rom: Box<[u8]>,
+    frame_cycles: u32,
+    total_cycles: u64,
+    rumble: bool,
+    rumble_callback: Option<RumbleCallback>,
+}
+
+impl fmt::Debug for Emulator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Emulator")
+            .field("cpu", &self.cpu)
+            .field("config", &self.config)
+            .field("frame_cycles", &self.frame_cycles)
+            .field("total_cycles", &self.total_cycles)
+            .field("rumble", &self.rumble)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Emulator {
+    pub fn new(rom: Vec<u8>) -> Result<Emulator, CartridgeError> {
+        Emulator::with_config(rom, Config::default())
+    }
+
+    pub fn with_config(rom: Vec<u8>, config: Config) -> Result<Emulator, CartridgeError> {
+        let cartridge = cartridge::load_with_rtc(rom.clone(), config.rtc_mode)?;
+        Ok(Emulator {
+            cpu: Emulator::boot(&config, cartridge),
+            config,
+            rom: rom.into_boxed_slice(),
+            frame_cycles: 0,
+            total_cycles: 0,
+            rumble: false,
+            rumble_callback: None,
+        })
+    }
+
+    pub(crate) fn boot(config: &Config, cartridge: Box<dyn Cartridge>) -> cpu::Cpu {
+        let wram = ram::Ram::new(WRAM_CAPACITY);
+        let hram = ram::Ram::new(HRAM_CAPACITY);
+        let vram = ram::Ram::new(VRAM_CAPACITY);
+        let oam = ram::Ram::new(OAM_CAPACITY);
+        let mut bus = bus::Bus::new(config.model, wram, cartridge, hram, vram, oam);
+        bus.set_strictness(config.strictness);
+        let mut cpu = cpu::Cpu::new();
+        cpu.connect_bus(bus);
+        cpu
+    }
+
+    /// Puts the machine back into its post-boot state with the same ROM loaded.
+    /// Battery backed memory and the cartridge clock survive the reset.
+    pub fn reset(&mut self) {
+        let save_data = self.save_data();
+        let mut cartridge = cartridge::load_with_rtc(self.rom.to_vec(), self.config.rtc_mode)
+            .expect("ROM was already loaded once");
+        cartridge.load_save_data(&save_data).expect("save data comes from the same cartridge");
+        // Whatever is plugged into the link port stays plugged in, same for the audio settings
+        let device = self.cpu.bus_mut().io_mut().serial_mut().take_device();
+        let mixer = self.sound_mut().take_mixer();
+        self.cpu = Emulator::boot(&self.config, cartridge);
+        self.cpu.bus_mut().io_mut().serial_mut().set_device(device);
+        self.sound_mut().set_mixer(mixer);
+        self.frame_cycles = 0;
+        self.total_cycles = 0;
+        self.update_rumble();
+    }
+
+    /// Executes a single instruction and returns the number of clock cycles it took.
+    /// Errors are only ever returned in strict mode.
+    pub fn step_instruction(&mut self) -> Result<u8, EmuError> {
+        let (cycles, result) = self.cpu.step();
+        self.frame_cycles += cycles as u32;
+        self.total_cycles += cycles as u64;
+        self.update_rumble();
+        result.map(|_| cycles)
+    }
+
+    fn update_rumble(&mut self) {
+        let rumble = self.bus().cartridge().rumble();
+        if rumble == self.rumble {
+            return;
+        }
+        self.rumble = rumble;
+        if let Some(callback) = &mut self.rumble_callback {
+            callback(rumble);
+        }
+    }
+
+    pub fn set_rumble_callback<F: FnMut(bool) + 'static>(&mut self, callback: F) {
+        self.rumble_callback = Some(Box::new(callback));
+    }
+
+    /// Whether the cartridge's rumble motor is currently on.
+    pub fn rumble(&self) -> bool {
+        self.rumble
+    }
+
+    /// Plugs a device into the link port, replacing the previous one.
+    pub fn set_serial_device<D: SerialDevice + 'static>(&mut self, device: D) {
+        self.cpu.bus_mut().io_mut().serial_mut().set_device(Box::new(device));
+    }
+
+    pub fn press(&mut self, button: Button) {
+        self.cpu.bus_mut().io_mut().joypad_mut().press(button);
+    }
+
+    pub fn release(&mut self, button: Button) {
+        self.cpu.bus_mut().io_mut().joypad_mut().release(button);
+    }
+
+    /// Sets the state of every button at once, buttons not listed are released.
+    pub fn set_buttons(&mut self, buttons: &[Button]) {
+        self.cpu.bus_mut().io_mut().joypad_mut().set_buttons(buttons);
+    }
+
+    /// Starts producing stereo audio at `rate` Hz for the `read_samples` calls, `None` stops it.
+    /// Up to one second of samples is buffered, pull them at least once per frame.
+    pub fn set_sample_rate(&mut self, rate: Option<u32>) {
+        self.sound_mut().set_sample_rate(rate);
+    }
+
+    /// Number of stereo samples waiting to be read.
+    pub fn samples_available(&self) -> usize {
+        self.sound().samples_available()
+    }
+
+    /// Moves buffered samples into `buffer` as interleaved left/right pairs in -1.0..=1.0
+    /// and returns the number of values written.
+    pub fn read_samples_f32(&mut self, buffer: &mut [f32]) -> usize {
+        self.sound_mut().read_samples_f32(buffer)
+    }
+
+    /// Same as `read_samples_f32` for 16 bit samples.
+    pub fn read_samples_i16(&mut self, buffer: &mut [i16]) -> usize {
+        self.sound_mut().read_samples_i16(buffer)
+    }
+
+    pub fn sound(&self) -> &SoundController {
+        self.bus().io().sound()
+    }
+
+    /// For muting, soloing and capturing single channels.
+    pub fn sound_mut(&mut self) -> &mut SoundController {
+        self.cpu.bus_mut().io_mut().sound_mut()
+    }
+
+    /// Runs instructions until a frame worth of cycles has elapsed. Cycles overshooting
+    /// the frame boundary are carried over to the next frame.
+    pub fn run_frame(&mut self) -> Result<(), EmuError> {
+        while self.frame_cycles < CYCLES_PER_FRAME {
+            self.step_instruction()?;
+        }
+        self.frame_cycles -= CYCLES_PER_FRAME;
+        Ok(())
+    }
+
+    pub fn run(&mut self) -> Result<(), EmuError> {
+        loop {
+            self.run_frame()?;
+        }
+    }
+
+    pub fn cpu(&self) -> &cpu::Cpu {
+        &self.cpu
+    }
+
+    pub fn bus(&self) -> &bus::Bus {
+        self.cpu.bus()
+    }
+
+    pub fn rom(&self) -> &[u8] {
+        &self.rom
+    }
+
+    pub fn header(&self) -> &Header {
+        self.bus().cartridge().header()
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// The picture on the LCD, 160x144 shades from 0 (white) to 3 (black), row by row.
+    pub fn framebuffer(&self) -> &[u8] {
+        self.bus().io().lcd().framebuffer()
+    }
+
+    pub fn has_battery(&self) -> bool {
+        self.bus().cartridge().has_battery()
+    }
+
+    /// External RAM and clock state in the layout of a .sav file.
+    pub fn save_data(&self) -> Vec<u8> {
+        self.bus().cartridge().save_data()
+    }
+
+    pub fn load_save_data(&mut self, data: &[u8]) -> Result<(), CartridgeError> {
+        self.cpu.bus_mut().cartridge_mut().load_save_data(data)
+    }
+
+    /// Total clock cycles executed since power on or the last reset.
+    pub fn cycles(&self) -> u64 {
+        self.total_cycles
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::io::serial::CaptureDevice;
+    use super::super::io::sound::Channel;
+    use super::super::error::BusError;
+
+    fn rom_with_program(program: &[u8]) -> Vec<u8> {
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x100 + program.len()].copy_from_slice(program);
+        rom
+    }
+
+    #[test]
+    fn step_instruction() {
+        // LD A, 0x42; LD (0xC000), A
+        let mut emulator = Emulator::new(rom_with_program(&[0x3E, 0x42, 0xEA, 0x00, 0xC0])).unwrap();
+        assert_eq!(emulator.step_instruction(), Ok(8));
+        assert_eq!(emulator.cpu().get_reg_a(), 0x42);
+        assert_eq!(emulator.step_instruction(), Ok(16));
+        assert_eq!(emulator.bus().peek(0xC000), 0x42);
+        assert_eq!(emulator.cpu().pc(), 0x105);
+        assert_eq!(emulator.cycles(), 24);
+    }
+
+    #[test]
+    fn run_frame_and_reset() {
+        // JR -2
+        let mut emulator = Emulator::new(rom_with_program(&[0x18, 0xFE])).unwrap();
+        emulator.run_frame().unwrap();
+        assert!(emulator.cycles() >= CYCLES_PER_FRAME as u64);
+        emulator.reset();
+        assert_eq!(emulator.cycles(), 0);
+        assert_eq!(emulator.cpu().pc(), 0x100);
+    }
+
+    #[test]
+    fn rumble_callback() {
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
+        // LD A, 0x08; LD (0x4000), A; XOR A; LD (0x4000), A
+        let mut rom = rom_with_program(&[0x3E, 0x08, 0xEA, 0x00, 0x40, 0xAF, 0xEA, 0x00, 0x40]);
+        rom[0x147] = 0x1C;
+        let mut emulator = Emulator::new(rom).unwrap();
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let recorded = events.clone();
+        emulator.set_rumble_callback(move |on| recorded.borrow_mut().push(on));
+        for _ in 0..4 {
+            emulator.step_instruction().unwrap();
+        }
+        assert_eq!(*events.borrow(), vec![true, false]);
+        assert!(!emulator.rumble());
+    }
+
+    #[test]
+    fn halt_until_vblank() {
+        // LD A, 0x01; LDH (0xFF), A; HALT; NOP
+        let mut emulator = Emulator::new(rom_with_program(&[0x3E, 0x01, 0xE0, 0xFF, 0x76, 0x00])).unwrap();
+        for _ in 0..3 {
+            emulator.step_instruction().unwrap();
+        }
+        assert!(emulator.cpu().is_halted());
+        while emulator.cpu().is_halted() {
+            emulator.step_instruction().unwrap();
+        }
+        assert_eq!(emulator.bus().io().lcd().ly(), 144);
+        assert_eq!(emulator.bus().peek(0xFF0F) & 0x01, 0x01);
+    }
+
+    #[test]
+    fn joypad_wakes_halt_and_stop() {
+        // LD A, 0x10; LDH (0x00), A; LDH (0xFF), A; HALT; STOP; NOP
+        let rom = rom_with_program(&[0x3E, 0x10, 0xE0, 0x00, 0xE0, 0xFF, 0x76, 0x10, 0x00, 0x00]);
+        let mut emulator = Emulator::new(rom).unwrap();
+        for _ in 0..5 {
+            emulator.step_instruction().unwrap();
+        }
+        assert!(emulator.cpu().is_halted());
+        // directions aren't selected
+        emulator.press(Button::Up);
+        emulator.step_instruction().unwrap();
+        assert!(emulator.cpu().is_halted());
+        emulator.set_buttons(&[Button::Start]);
+        emulator.step_instruction().unwrap();
+        assert!(!emulator.cpu().is_halted());
+        assert_eq!(emulator.bus().peek(0xFF00), 0xD7);
+
+        emulator.release(Button::Start);
+        emulator.step_instruction().unwrap();
+        assert!(emulator.cpu().is_stopped());
+        for _ in 0..10 {
+            emulator.step_instruction().unwrap();
+        }
+        assert!(emulator.cpu().is_stopped());
+        emulator.press(Button::A);
+        emulator.step_instruction().unwrap();
+        assert!(!emulator.cpu().is_stopped());
+        assert_eq!(emulator.cpu().pc(), 0x109);
+    }
+
+    #[test]
+    fn serial_device_survives_reset() {
+        // LD A, 0x41; LDH (0x01), A; LD A, 0x81; LDH (0x02), A
+        let rom = rom_with_program(&[0x3E, 0x41, 0xE0, 0x01, 0x3E, 0x81, 0xE0, 0x02]);
+        let mut emulator = Emulator::new(rom).unwrap();
+        let capture = CaptureDevice::new();
+        emulator.set_serial_device(capture.clone());
+        for _ in 0..4 {
+            emulator.step_instruction().unwrap();
+        }
+        assert_eq!(capture.take_output(), b"A");
+        emulator.reset();
+        for _ in 0..4 {
+            emulator.step_instruction().unwrap();
+        }
+        assert_eq!(capture.take_output(), b"A");
+    }
+
+    #[test]
+    fn audio_samples() {
+        // JR -2
+        let mut emulator = Emulator::new(rom_with_program(&[0x18, 0xFE])).unwrap();
+        emulator.run_frame().unwrap();
+        assert_eq!(emulator.samples_available(), 0);
+
+        emulator.set_sample_rate(Some(44100));
+        emulator.run_frame().unwrap();
+        assert_eq!(emulator.samples_available(), 738);
+        let mut buffer = vec![0i16; 2000];
+        assert_eq!(emulator.read_samples_i16(&mut buffer), 738 * 2);
+        assert_eq!(emulator.samples_available(), 0);
+
+        // the sample rate outlives a reset
+        emulator.reset();
+        emulator.run_frame().unwrap();
+        assert_eq!(emulator.samples_available(), 738);
+        // so do mute and solo
+        emulator.sound_mut().set_muted(Channel::Noise, true);
+        emulator.reset();
+        assert!(emulator.sound().is_muted(Channel::Noise));
+        emulator.set_sample_rate(None);
+        assert_eq!(emulator.read_samples_f32(&mut [0.0; 2]), 0);
+    }
+
+    #[test]
+    fn wave_ram_access_timing() {
+        let program = [
+            0x3E, 0x42, 0xE0, 0x30, // wave RAM byte 0 = 0x42 while channel 3 is off
+            0x3E, 0x80, 0xE0, 0x1A, // DAC on
+            0x3E, 0xF8, 0xE0, 0x1D, // a byte every 16 cycles
+            0x3E, 0x87, 0xE0, 0x1E, // trigger
+        ];
+        // the read lands on the channel's first fetch only when timed within the instruction
+        let mut emulator = Emulator::new(rom_with_program(&[&program[..], &[0x00, 0x00, 0xF0, 0x30]].concat())).unwrap();
+        for _ in 0..11 {
+            emulator.step_instruction().unwrap();
+        }
+        assert_eq!(emulator.cpu().get_reg_a(), 0x42);
+
+        let mut emulator = Emulator::new(rom_with_program(&[&program[..], &[0x00, 0xF0, 0x30]].concat())).unwrap();
+        for _ in 0..10 {
+            emulator.step_instruction().unwrap();
+        }
+        assert_eq!(emulator.cpu().get_reg_a(), 0xFF);
+    }
+
+    #[test]
+    fn timer_write_timing() {
+        // TAC = 0b101, TIMA = 0xFF with the overflow 16 cycles after the write
+        let setup = [0x3E, 0x05, 0xE0, 0x07, 0x3E, 0xFF, 0xE0, 0x05];
+        // the TIMA write lands in the last M-cycle of LDH, one NOP later it's in the overflow
+        // M-cycle and cancels the reload
+        let mut emulator = Emulator::new(rom_with_program(&[&setup[..], &[0x00, 0xE0, 0x05]].concat())).unwrap();
+        for _ in 0..6 {
+            emulator.step_instruction().unwrap();
+        }
+        assert_eq!(emulator.bus().peek(0xFF05), 0xFF);
+        assert_eq!(emulator.bus().peek(0xFF0F) & 0x04, 0);
+
+        // two NOPs later it's in the reload M-cycle and gets lost
+        let mut emulator = Emulator::new(rom_with_program(&[&setup[..], &[0x00, 0x00, 0xE0, 0x05]].concat())).unwrap();
+        for _ in 0..7 {
+            emulator.step_instruction().unwrap();
+        }
+        assert_eq!(emulator.bus().peek(0xFF05), 0x00);
+        assert_eq!(emulator.bus().peek(0xFF0F) & 0x04, 0x04);
+    }
+
+    #[test]
+    fn strictness() {
+        // LD (0x2000), A; illegal opcode 0xD3
+        let rom = rom_with_program(&[0xEA, 0x00, 0x20, 0xD3]);
+        let mut emulator = Emulator::new(rom.clone()).unwrap();
+        emulator.step_instruction().unwrap();
+        emulator.step_instruction().unwrap();
+        assert!(emulator.cpu().is_locked());
+        assert_eq!(emulator.cpu().pc(), 0x103);
+
+        let config = Config {strictness: Strictness::Strict, ..Default::default()};
+        let mut emulator = Emulator::with_config(rom, config).unwrap();
+        let error = BusError::RomWrite(0x2000, 0x01);
+        assert_eq!(emulator.step_instruction(), Err(EmuError::Bus {pc: 0x100, opcode: 0xEA, error}));
+        assert_eq!(emulator.step_instruction(), Err(EmuError::UnknownOpcode {pc: 0x103, opcode: 0xD3}));
+        // the failing instructions still ran on the hardware
+        assert_eq!(emulator.cycles(), 16 + 4);
+    }
+
+    #[test]
+    fn interrupt_dispatch_errors() {
+        // LD SP, 0xFEA2; EI, the interrupt pushes into the unusable region
+        let rom = rom_with_program(&[0x31, 0xA2, 0xFE, 0xFB]);
+        let config = Config {strictness: Strictness::Strict, ..Default::default()};
+        let mut emulator = Emulator::with_config(rom, config).unwrap();
+        emulator.cpu.bus_mut().write(0xFFFF, 0x01);
+        emulator.cpu.bus_mut().write(0xFF0F, 0x01);
+        emulator.step_instruction().unwrap();
+        let error = emulator.step_instruction().unwrap_err();
+        assert!(matches!(error, EmuError::Bus {pc: 0x103, opcode: 0xFB, error: BusError::UnmappedWrite(0xFEA1, _)}), "{:?}", error);
+        assert_eq!(emulator.cpu().pc(), 0x40);
+    }
+}
@@ -0,0 +1,56 @@
+use std::error::Error;
+use std::fmt;
+
+/// How the emulator reacts to accesses real hardware would silently shrug off.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Strictness {
+    /// Behave like the hardware: unmapped reads float high, stray writes are dropped
+    /// and illegal opcodes lock up the CPU.
+    #[default]
+    Hardware,
+    /// Report the first such access as an error from the step that caused it.
+    Strict,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BusError {
+    UnmappedRead(u16),
+    UnmappedWrite(u16, u8),
+    RomWrite(u16, u8),
+}
+
+impl fmt::Display for BusError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BusError::UnmappedRead(addr) => write!(f, "Reading from unmapped address {:#06X}", addr),
+            BusError::UnmappedWrite(addr, value) => write!(f, "Writing {:#04X} to unmapped address {:#06X}", value, addr),
+            BusError::RomWrite(addr, value) => write!(f, "Writing {:#04X} to ROM at {:#06X}", value, addr),
+        }
+    }
+}
+
+impl Error for BusError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EmuError {
+    Bus {pc: u16, opcode: u8, error: BusError},
+    UnknownOpcode {pc: u16, opcode: u8},
+}
+
+impl fmt::Display for EmuError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EmuError::Bus {pc, opcode, error} => write!(f, "{} (instruction {:#04X} @ {:#06X})", error, opcode, pc),
+            EmuError::UnknownOpcode {pc, opcode} => write!(f, "Unknown instruction {:#04X} @ {:#06X}", opcode, pc),
+        }
+    }
+}
+
+impl Error for EmuError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            EmuError::Bus {error, ..} => Some(error),
+            EmuError::UnknownOpcode {..} => None,
+        }
+    }
+}
@@ -0,0 +1,375 @@
+use std::convert::TryInto;
+use std::error::Error;
+use std::fmt;
+
+use super::cartridge::{header, Cartridge, Header};
+use super::cpu::Cpu;
+use super::emulator::{Config, Emulator, CYCLES_PER_FRAME};
+use super::error::EmuError;
+use super::io::sound::SoundController;
+use super::ram::Ram;
+
+const SIGNATURE: &[u8] = b"GBS";
+const VERSION: u8 = 1;
+const HEADER_LENGTH: usize = 0x70;
+
+// Everything below the load address belongs to the player
+const MIN_LOAD_ADDRESS: u16 = 0x400;
+const BANK_SIZE: usize = 0x4000;
+const RAM_SIZE: usize = 8 * 1024;
+
+// Routines return here and spin until the next call
+const IDLE_LOOP: u16 = 0x0070;
+// The restart vectors are relocated to the load address
+const RST_VECTORS: [u16; 8] = [0x00, 0x08, 0x10, 0x18, 0x20, 0x28, 0x30, 0x38];
+const INTERRUPT_VECTORS: [u16; 5] = [0x40, 0x48, 0x50, 0x58, 0x60];
+const VBLANK_VECTOR: u16 = 0x40;
+const TIMER_VECTOR: u16 = 0x50;
+
+const JP: u8 = 0xC3;
+const JR: u8 = 0x18;
+const RETI: u8 = 0xD9;
+
+const LCDC: u16 = 0xFF40;
+const TMA: u16 = 0xFF06;
+const TAC: u16 = 0xFF07;
+const TAC_ENABLE: u8 = 0b100;
+const IF: u16 = 0xFF0F;
+const INT_VBLANK: u8 = 0b0001;
+const INT_TIMER: u8 = 0b0100;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum GbsError {
+    TooShort(usize),
+    InvalidSignature,
+    UnsupportedVersion(u8),
+    InvalidLoadAddress(u16),
+    InvalidTrack(u8),
+}
+
+impl fmt::Display for GbsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            GbsError::TooShort(len) => write!(f, "GBS file is too short to contain a header ({} bytes)", len),
+            GbsError::InvalidSignature => write!(f, "Not a GBS file"),
+            GbsError::UnsupportedVersion(version) => write!(f, "Unsupported GBS version {}", version),
+            GbsError::InvalidLoadAddress(addr) => write!(f, "Invalid load address {:#06X}", addr),
+            GbsError::InvalidTrack(track) => write!(f, "Invalid track {}", track),
+        }
+    }
+}
+
+impl Error for GbsError {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GbsHeader {
+    pub version: u8,
+    pub songs: u8,
+    /// 1-based like the track numbers players show.
+    pub first_song: u8,
+    pub load_address: u16,
+    pub init_address: u16,
+    pub play_address: u16,
+    pub stack_pointer: u16,
+    pub timer_modulo: u8,
+    pub timer_control: u8,
+    pub title: String,
+    pub author: String,
+    pub copyright: String,
+}
+
+impl GbsHeader {
+    pub fn parse(data: &[u8]) -> Result<GbsHeader, GbsError> {
+        if data.len() < HEADER_LENGTH {
+            return Err(GbsError::TooShort(data.len()));
+        }
+        if &data[0..3] != SIGNATURE {
+            return Err(GbsError::InvalidSignature);
+        }
+        if data[0x03] != VERSION {
+            return Err(GbsError::UnsupportedVersion(data[0x03]));
+        }
+        let word = |offset: usize| u16::from_le_bytes(data[offset..offset + 2].try_into().unwrap());
+        let text = |offset: usize| {
+            data[offset..offset + 32].iter().take_while(|&&c| c != 0).map(|&c| c as char).collect()
+        };
+        let header = GbsHeader {
+            version: data[0x03],
+            songs: data[0x04],
+            first_song: data[0x05],
+            load_address: word(0x06),
+            init_address: word(0x08),
+            play_address: word(0x0A),
+            stack_pointer: word(0x0C),
+            timer_modulo: data[0x0E],
+            timer_control: data[0x0F],
+            title: text(0x10),
+            author: text(0x30),
+            copyright: text(0x50),
+        };
+        if !(MIN_LOAD_ADDRESS..0x8000).contains(&header.load_address) {
+            return Err(GbsError::InvalidLoadAddress(header.load_address));
+        }
+        Ok(header)
+    }
+
+    /// Whether the play routine follows the timer interrupt rather than VBlank.
+    pub fn uses_timer(&self) -> bool {
+        self.timer_control & TAC_ENABLE != 0
+    }
+}
+
+/// The music code mapped at its load address, banked like MBC1 above 0x4000, with 8 KiB of RAM.
+#[derive(Debug)]
+struct GbsCartridge {
+    header: Header,
+    rom: Box<[u8]>,
+    bank: usize,
+    ram: Ram,
+}
+
+impl GbsCartridge {
+    fn new(gbs: &GbsHeader, data: &[u8]) -> GbsCartridge {
+        let code = &data[HEADER_LENGTH..];
+        let load_address = gbs.load_address as usize;
+        let banks = ((load_address + code.len()) / BANK_SIZE + 1).max(2);
+        let mut rom = vec![0; banks * BANK_SIZE];
+        rom[load_address..load_address + code.len()].copy_from_slice(code);
+
+        for &vector in &RST_VECTORS {
+            let [low, high] = (gbs.load_address + vector).to_le_bytes();
+            rom[vector as usize..vector as usize + 3].copy_from_slice(&[JP, low, high]);
+        }
+        for &vector in &INTERRUPT_VECTORS {
+            rom[vector as usize] = RETI;
+        }
+        rom[IDLE_LOOP as usize..IDLE_LOOP as usize + 2].copy_from_slice(&[JR, 0xFE]);
+
+        GbsCartridge {
+            header: Header::parse(&[0; header::HEADER_END]).expect("an empty header is valid"),
+            rom: rom.into_boxed_slice(),
+            bank: 1,
+            ram: Ram::new(RAM_SIZE),
+        }
+    }
+}
+
+impl Cartridge for GbsCartridge {
+    fn header(&self) -> &Header {
+        &self.header
+    }
+
+    fn read(&self, addr: u16) -> u8 {
+        match addr {
+            0x0000..=0x3FFF => self.rom[addr as usize],
+            0x4000..=0x7FFF => self.rom[self.bank * BANK_SIZE + (addr as usize - 0x4000)],
+            0xA000..=0xBFFF => self.ram.read((addr - 0xA000) as usize),
+            _ => 0xFF,
+        }
+    }
+
+    fn write(&mut self, addr: u16, value: u8) {
+        match addr {
+            0x2000..=0x3FFF => {
+                let banks = self.rom.len() / BANK_SIZE;
+                self.bank = (value as usize).max(1) % banks;
+            }
+            0xA000..=0xBFFF => self.ram.write((addr - 0xA000) as usize, value),
+            _ => {}
+        }
+    }
+}
+
+/// Plays GBS rips: the music code runs on the regular CPU and bus, the init routine once per
+/// track and the play routine on every timer or VBlank interrupt, whichever the header picks.
+/// The LCD stays off, VBlank is raised every `CYCLES_PER_FRAME` clock cycles instead.
+#[derive(Debug)]
+pub struct GbsPlayer {
+    header: GbsHeader,
+    data: Box<[u8]>,
+    cpu: Cpu,
+    // The interrupt fired while a routine was still running
+    play_pending: bool,
+    // Clock cycles since the last VBlank
+    frame_cycles: u32,
+}
+
+impl GbsPlayer {
+    pub fn new(data: Vec<u8>) -> Result<GbsPlayer, GbsError> {
+        let header = GbsHeader::parse(&data)?;
+        let mut player = GbsPlayer {
+            cpu: Cpu::new(),
+            header,
+            data: data.into_boxed_slice(),
+            play_pending: false,
+            frame_cycles: 0,
+        };
+        player.start_track(player.header.first_song.max(1))?;
+        Ok(player)
+    }
+
+    pub fn header(&self) -> &GbsHeader {
+        &self.header
+    }
+
+    /// Restarts the machine and runs the init routine for the 1-based `track`.
+    /// Mute, solo and the sample rate carry over.
+    pub fn start_track(&mut self, track: u8) -> Result<(), GbsError> {
+        if track == 0 || track > self.header.songs {
+            return Err(GbsError::InvalidTrack(track));
+        }
+        let mixer = self.cpu.bus_mut().io_mut().sound_mut().take_mixer();
+        let cartridge = GbsCartridge::new(&self.header, &self.data);
+        self.cpu = Emulator::boot(&Config::default(), Box::new(cartridge));
+        self.cpu.bus_mut().io_mut().sound_mut().set_mixer(mixer);
+
+        let bus = self.cpu.bus_mut();
+        bus.write(LCDC, 0);
+        bus.write(TMA, self.header.timer_modulo);
+        bus.write(TAC, self.header.timer_control);
+        self.cpu.set_pc(IDLE_LOOP);
+        self.cpu.set_sp(self.header.stack_pointer);
+        self.cpu.set_reg_a(track - 1);
+        self.cpu.call(self.header.init_address);
+        self.play_pending = false;
+        self.frame_cycles = 0;
+        Ok(())
+    }
+
+    /// Runs for at least `cycles` clock cycles, calling the play routine once the interrupt
+    /// fired and the previous routine returned. The timer follows whatever the music code
+    /// writes to it, so tempo changes apply. Errors are only ever returned in strict mode.
+    pub fn run(&mut self, cycles: u32) -> Result<(), EmuError> {
+        let (interrupt, vector) = if self.header.uses_timer() {
+            (INT_TIMER, TIMER_VECTOR)
+        } else {
+            (INT_VBLANK, VBLANK_VECTOR)
+        };
+        let mut elapsed = 0;
+        while elapsed < cycles {
+            let instruction_cycles = self.cpu.run_next_instruction()? as u32;
+            elapsed += instruction_cycles;
+            let pc = self.cpu.pc();
+            let bus = self.cpu.bus_mut();
+            let mut flags = bus.peek(IF);
+            if !self.header.uses_timer() {
+                self.frame_cycles += instruction_cycles;
+                if self.frame_cycles >= CYCLES_PER_FRAME {
+                    self.frame_cycles -= CYCLES_PER_FRAME;
+                    flags |= INT_VBLANK;
+                }
+            }
+            // Music code enabling the interrupt itself gets it dispatched to the RETI stub
+            if flags & interrupt != 0 || pc == vector {
+                bus.write(IF, flags & !interrupt);
+                self.play_pending = true;
+            }
+            if self.play_pending && pc == IDLE_LOOP {
+                self.play_pending = false;
+                self.cpu.call(self.header.play_address);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn cpu(&self) -> &Cpu {
+        &self.cpu
+    }
+
+    pub fn sound(&self) -> &SoundController {
+        self.cpu.bus().io().sound()
+    }
+
+    /// The sample rate, the `read_samples` calls and the captures live on the sound controller.
+    pub fn sound_mut(&mut self) -> &mut SoundController {
+        self.cpu.bus_mut().io_mut().sound_mut()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::CYCLES_PER_SECOND;
+
+    const PLAY: u16 = 0x400;
+    const INIT: u16 = 0x405;
+
+    /// Rip whose init stores the track at 0xC000 after running `init`, play counts at 0xC001.
+    fn gbs(timer_modulo: u8, timer_control: u8, init: &[u8]) -> Vec<u8> {
+        let mut data = vec![0; HEADER_LENGTH];
+        data[0..4].copy_from_slice(b"GBS\x01");
+        data[0x04] = 3;
+        data[0x05] = 2;
+        data[0x06..0x08].copy_from_slice(&PLAY.to_le_bytes());
+        data[0x08..0x0A].copy_from_slice(&INIT.to_le_bytes());
+        data[0x0A..0x0C].copy_from_slice(&PLAY.to_le_bytes());
+        data[0x0C..0x0E].copy_from_slice(&0xDFFFu16.to_le_bytes());
+        data[0x0E] = timer_modulo;
+        data[0x0F] = timer_control;
+        data[0x10..0x15].copy_from_slice(b"Title");
+        data.extend_from_slice(&[
+            0x21, 0x01, 0xC0, // play: LD HL, 0xC001
+            0x34,             // INC (HL)
+            0xC9,             // RET
+            0xEA, 0x00, 0xC0, // init: LD (0xC000), A
+        ]);
+        data.extend_from_slice(init);
+        data.push(0xC9);      // RET
+        data
+    }
+
+    #[test]
+    fn header() {
+        let header = GbsHeader::parse(&gbs(0xC0, 0x04, &[])).unwrap();
+        assert_eq!(header.songs, 3);
+        assert_eq!(header.first_song, 2);
+        assert_eq!(header.play_address, PLAY);
+        assert_eq!(header.title, "Title");
+        assert!(header.uses_timer());
+        assert!(!GbsHeader::parse(&gbs(0, 0, &[])).unwrap().uses_timer());
+
+        assert_eq!(GbsHeader::parse(&[0; 0x20]), Err(GbsError::TooShort(0x20)));
+        assert_eq!(GbsHeader::parse(&[0; 0x70]), Err(GbsError::InvalidSignature));
+        let mut data = gbs(0, 0, &[]);
+        data[0x03] = 2;
+        assert_eq!(GbsHeader::parse(&data), Err(GbsError::UnsupportedVersion(2)));
+        let mut data = gbs(0, 0, &[]);
+        data[0x06..0x08].copy_from_slice(&0x0100u16.to_le_bytes());
+        assert_eq!(GbsHeader::parse(&data), Err(GbsError::InvalidLoadAddress(0x100)));
+    }
+
+    #[test]
+    fn play_on_vblank() {
+        let mut player = GbsPlayer::new(gbs(0, 0, &[])).unwrap();
+        // the first VBlank comes a full frame after init, the play routine needs a few more cycles
+        player.run(CYCLES_PER_FRAME * 10 + 100).unwrap();
+        // init got the 0-based first song
+        assert_eq!(player.cpu().bus().peek(0xC000), 1);
+        assert_eq!(player.cpu().bus().peek(0xC001), 10);
+        assert_eq!(player.cpu().bus().peek(LCDC), 0x00);
+
+        player.start_track(3).unwrap();
+        assert_eq!(player.cpu().bus().peek(0xC001), 0);
+        player.run(100).unwrap();
+        assert_eq!(player.cpu().bus().peek(0xC000), 2);
+        assert_eq!(player.start_track(4).unwrap_err(), GbsError::InvalidTrack(4));
+    }
+
+    #[test]
+    fn play_on_timer() {
+        let mut player = GbsPlayer::new(gbs(0xC0, 0x04, &[])).unwrap();
+        assert_eq!(player.cpu().bus().peek(TAC) & 0b111, 0b100);
+        assert_eq!(player.cpu().bus().peek(LCDC), 0x00);
+        player.run(CYCLES_PER_SECOND).unwrap();
+        // TIMA starts from 0, so the first overflow takes 256 ticks instead of 64
+        assert_eq!(player.cpu().bus().peek(0xC001), 61);
+    }
+
+    #[test]
+    fn tempo_change() {
+        // init halves the tempo: LD A, 0x80; LDH (TMA), A
+        let mut player = GbsPlayer::new(gbs(0xC0, 0x04, &[0x3E, 0x80, 0xE0, 0x06])).unwrap();
+        player.run(CYCLES_PER_SECOND).unwrap();
+        assert_eq!(player.cpu().bus().peek(0xC001), 31);
+    }
+}
@@ -1,43 +1,27 @@
+use super::super::ram::Ram;
 use super::sound;
 use super::lcd;
-use super::super::ram::Ram;
-
-const SB: u16 = 0xFF01;
-const SC: u16 = 0xFF02;
-
-const DIV: u16 = 0xFF04;
-const TIMA: u16 = 0xFF05;
-const TMA: u16 = 0xFF06;
-const TAC: u16 = 0xFF07;
-
-const IO_REGISTERS_START: usize = 0xFF08;
-const IO_REGISTERS_END: usize = 0xFF80;
+use super::joypad::{self, Joypad};
+use super::serial::{self, Serial};
+use super::timer::{self, Timer};
 
 #[derive(Debug, Default)]
 pub struct IO {
     sound_controller: sound::SoundController,
-    lcd: lcd::LCD,
-    sb: u8,
-    sc: u8,
-    div: u8,
-    tima: u8,
-    tma: u8,
-    tac: u8,
-    io_registers: Ram,
+    lcd: lcd::Lcd,
+    joypad: Joypad,
+    serial: Serial,
+    timer: Timer,
 }
 
 impl IO {
     pub fn new() -> IO {
         IO {
             sound_controller: sound::SoundController::new(),
-            lcd: lcd::LCD::new(),
-            sb: 0,
-            sc: 0,
-            div: 0,
-            tima: 0,
-            tma: 0,
-            tac: 0,
-            io_registers: Ram::new(IO_REGISTERS_END - IO_REGISTERS_START),
+            lcd: lcd::Lcd::new(),
+            joypad: Joypad::new(),
+            serial: Serial::new(),
+            timer: Timer::new(),
         }
     }
 
@@ -50,18 +34,22 @@ impl IO {
             self.lcd.write(addr.into(), value);
             return
         }
-        if IO_REGISTERS_START <= addr as usize && (addr as usize) < IO_REGISTERS_END {
-            self.io_registers.write(addr as usize - IO_REGISTERS_START, value);
+        if sound::WAVE_START <= addr as usize && (addr as usize) < sound::WAVE_END {
+            self.sound_controller.write_wave(addr.into(), value);
             return
         }
         match addr {
-            SB => self.sb = value,
-            SC => self.sc = value,
-            DIV => self.div = 0x00, // any write to DIV resets it
-            TIMA => self.tima = value,
-            TMA => self.tma = value,
-            TAC => self.tac = value,
-            _ => panic!("Writing to unknown IO {:#X?}", addr)
+            joypad::P1 => self.joypad.write(value),
+            serial::SB | serial::SC => self.serial.write(addr, value),
+            timer::DIV => {
+                // Resetting DIV can clock the frame sequencer as well
+                let counter = self.timer.counter();
+                self.timer.write(addr, value);
+                self.sound_controller.div_reset(counter);
+            }
+            timer::TIMA..=timer::TAC => self.timer.write(addr, value),
+            // Unmapped registers ignore writes
+            _ => {}
         }
     }
 
@@ -72,22 +60,68 @@ impl IO {
         if lcd::START <= addr as usize && (addr as usize) < lcd::END {
             return self.lcd.read(addr.into());
         }
-        if IO_REGISTERS_START <= addr as usize && (addr as usize) < IO_REGISTERS_END {
-            return self.io_registers.read(addr as usize - IO_REGISTERS_START);
+        if sound::WAVE_START <= addr as usize && (addr as usize) < sound::WAVE_END {
+            return self.sound_controller.read_wave(addr.into());
         }
         match addr {
-            SB => self.sb,
-            SC => self.sc,
-            DIV => self.div,
-            TIMA => self.tima,
-            TMA => self.tma,
-            TAC => self.tac,
-            _ => panic!("Reading from unknown IO {:#X?}", addr)
+            joypad::P1 => self.joypad.read(),
+            serial::SB | serial::SC => self.serial.read(addr),
+            timer::DIV..=timer::TAC => self.timer.read(addr),
+            // Unmapped registers float high
+            _ => 0xFF
         }
     }
 
-    pub fn increment_div(&mut self) {
-        self.div += 1;
+    /// Whether anything answers at this address, unmapped registers read 0xFF and ignore writes.
+    pub fn is_mapped(&self, addr: u16) -> bool {
+        let addr = addr as usize;
+        (sound::START..sound::END).contains(&addr)
+            || (sound::WAVE_START..sound::WAVE_END).contains(&addr)
+            || (lcd::START..lcd::END).contains(&addr)
+            || matches!(addr as u16, joypad::P1 | serial::SB | serial::SC | timer::DIV..=timer::TAC)
+    }
+
+    /// Advances the IO devices and returns the interrupts they requested in the IF layout.
+    pub fn step(&mut self, cycles: u8, vram: &Ram, oam: &Ram) -> u8 {
+        let counter = self.timer.counter();
+        let timer_interrupt = self.timer.step(cycles);
+        self.sound_controller.step(cycles, counter);
+        timer_interrupt
+            | self.lcd.step(cycles, vram, oam)
+            | self.serial.step(cycles)
+            | self.joypad.take_interrupt()
+    }
+
+    pub fn sound(&self) -> &sound::SoundController {
+        &self.sound_controller
+    }
+
+    pub fn sound_mut(&mut self) -> &mut sound::SoundController {
+        &mut self.sound_controller
+    }
+
+    pub fn lcd(&self) -> &lcd::Lcd {
+        &self.lcd
+    }
+
+    pub fn joypad(&self) -> &Joypad {
+        &self.joypad
+    }
+
+    pub fn joypad_mut(&mut self) -> &mut Joypad {
+        &mut self.joypad
+    }
+
+    pub fn serial(&self) -> &Serial {
+        &self.serial
+    }
+
+    pub fn serial_mut(&mut self) -> &mut Serial {
+        &mut self.serial
+    }
+
+    pub fn timer(&self) -> &Timer {
+        &self.timer
     }
 }
 
@@ -0,0 +1,140 @@
+pub const P1: u16 = 0xFF00;
+
+pub const INT_JOYPAD: u8 = 0b0001_0000;
+
+// Select lines are active low
+const SELECT_ACTION: u8 = 0b0010_0000;
+const SELECT_DIRECTION: u8 = 0b0001_0000;
+const SELECT_MASK: u8 = SELECT_ACTION | SELECT_DIRECTION;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Button {
+    Right,
+    Left,
+    Up,
+    Down,
+    A,
+    B,
+    Select,
+    Start,
+}
+
+impl Button {
+    // Directions take the low nibble and actions the high one, both in P1 bit order
+    fn mask(self) -> u8 {
+        1 << self as u8
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Joypad {
+    select: u8,
+    pressed: u8,
+    interrupt: bool,
+}
+
+impl Joypad {
+    pub fn new() -> Joypad {
+        Joypad::default()
+    }
+
+    pub fn read(&self) -> u8 {
+        0xC0 | self.select | (!self.low_lines() & 0x0F)
+    }
+
+    pub fn write(&mut self, value: u8) {
+        let low_lines = self.low_lines();
+        self.select = value & SELECT_MASK;
+        self.update(low_lines);
+    }
+
+    pub fn press(&mut self, button: Button) {
+        self.set_pressed(self.pressed | button.mask());
+    }
+
+    pub fn release(&mut self, button: Button) {
+        self.set_pressed(self.pressed & !button.mask());
+    }
+
+    /// Replaces the whole button state, buttons not listed are released.
+    pub fn set_buttons(&mut self, buttons: &[Button]) {
+        self.set_pressed(buttons.iter().fold(0, |pressed, button| pressed | button.mask()));
+    }
+
+    pub fn is_pressed(&self, button: Button) -> bool {
+        self.pressed & button.mask() != 0
+    }
+
+    /// Whether any of the P1 input lines is pulled low, which is what ends STOP.
+    pub fn is_line_low(&self) -> bool {
+        self.low_lines() != 0
+    }
+
+    /// Returns the joypad interrupt in the IF layout if one was requested since the last call.
+    pub fn take_interrupt(&mut self) -> u8 {
+        if std::mem::take(&mut self.interrupt) {INT_JOYPAD} else {0}
+    }
+
+    fn set_pressed(&mut self, pressed: u8) {
+        let low_lines = self.low_lines();
+        self.pressed = pressed;
+        self.update(low_lines);
+    }
+
+    // The interrupt fires when any input line goes from high to low
+    fn update(&mut self, old_low_lines: u8) {
+        if self.low_lines() & !old_low_lines != 0 {
+            self.interrupt = true;
+        }
+    }
+
+    // P1 input lines pulled low by pressed buttons of the selected groups
+    fn low_lines(&self) -> u8 {
+        let mut lines = 0;
+        if self.select & SELECT_DIRECTION == 0 {
+            lines |= self.pressed & 0x0F;
+        }
+        if self.select & SELECT_ACTION == 0 {
+            lines |= self.pressed >> 4;
+        }
+        lines
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn select_lines() {
+        let mut joypad = Joypad::new();
+        joypad.set_buttons(&[Button::Down, Button::A]);
+        joypad.write(SELECT_ACTION);
+        assert_eq!(joypad.read(), 0xC0 | SELECT_ACTION | 0b0111);
+        joypad.write(SELECT_DIRECTION);
+        assert_eq!(joypad.read(), 0xC0 | SELECT_DIRECTION | 0b1110);
+        // both groups at once are AND-ed together
+        joypad.write(0);
+        assert_eq!(joypad.read(), 0xC0 | 0b0110);
+        joypad.write(SELECT_MASK);
+        assert_eq!(joypad.read(), 0xFF);
+    }
+
+    #[test]
+    fn interrupt_on_falling_edge() {
+        let mut joypad = Joypad::new();
+        joypad.write(SELECT_ACTION);
+        // not selected, the line stays high
+        joypad.press(Button::Start);
+        assert_eq!(joypad.take_interrupt(), 0);
+        joypad.press(Button::Left);
+        assert_eq!(joypad.take_interrupt(), INT_JOYPAD);
+        assert_eq!(joypad.take_interrupt(), 0);
+        joypad.release(Button::Left);
+        assert_eq!(joypad.take_interrupt(), 0);
+        // selecting a group with a button held pulls its line low too
+        joypad.write(SELECT_DIRECTION);
+        assert_eq!(joypad.take_interrupt(), INT_JOYPAD);
+        assert!(joypad.is_line_low());
+    }
+}
@@ -1,37 +1,790 @@
+use std::collections::VecDeque;
+
+use super::super::ram::Ram;
+
 pub const START: usize = 0xFF40;
 pub const END: usize = 0xFF4C;
 
 const CAPACITY: usize = END-START;
 
+const LCDC: usize = 0xFF40;
+const STAT: usize = 0xFF41;
+const SCY: usize = 0xFF42;
+const SCX: usize = 0xFF43;
+const LY: usize = 0xFF44;
+const LYC: usize = 0xFF45;
+const BGP: usize = 0xFF47;
+const OBP0: usize = 0xFF48;
+const OBP1: usize = 0xFF49;
+const WY: usize = 0xFF4A;
+const WX: usize = 0xFF4B;
+
+const LCDC_ENABLE: u8 = 0b1000_0000;
+const LCDC_WINDOW_MAP: u8 = 0b0100_0000;
+const LCDC_WINDOW_ENABLE: u8 = 0b0010_0000;
+const LCDC_TILE_DATA: u8 = 0b0001_0000;
+const LCDC_BG_MAP: u8 = 0b0000_1000;
+const LCDC_OBJ_SIZE: u8 = 0b0000_0100;
+const LCDC_OBJ_ENABLE: u8 = 0b0000_0010;
+const LCDC_BG_ENABLE: u8 = 0b0000_0001;
+
+const STAT_LYC_INTERRUPT: u8 = 0b0100_0000;
+const STAT_OAM_INTERRUPT: u8 = 0b0010_0000;
+const STAT_VBLANK_INTERRUPT: u8 = 0b0001_0000;
+const STAT_HBLANK_INTERRUPT: u8 = 0b0000_1000;
+const STAT_COINCIDENCE: u8 = 0b0000_0100;
+const STAT_WRITABLE: u8 = 0b0111_1000;
+
+pub const INT_VBLANK: u8 = 0b0000_0001;
+pub const INT_STAT: u8 = 0b0000_0010;
+
+const DOTS_PER_LINE: u16 = 456;
+const OAM_SCAN_DOTS: u16 = 80;
+// The first tile fetch of a line is done twice, then every fetch takes 6 dots
+// before it can be pushed and an object fetch stalls the pipeline for another 6
+const DUMMY_FETCH_DOTS: u8 = 6;
+const FETCH_DOTS: u8 = 6;
+const SPRITE_FETCH_DOTS: u8 = 6;
+const VISIBLE_LINES: u8 = 144;
+const LINES: u8 = 154;
+
+pub const SCREEN_WIDTH: usize = 160;
+pub const SCREEN_HEIGHT: usize = 144;
+
+// VRAM offsets, relative to 0x8000
+const TILE_MAP_0: usize = 0x1800;
+const TILE_MAP_1: usize = 0x1C00;
+const TILE_DATA_SIGNED_BASE: usize = 0x1000;
+
+const OAM_ENTRIES: usize = 40;
+const SPRITES_PER_LINE: usize = 10;
+
+const OBJ_BEHIND_BG: u8 = 0b1000_0000;
+const OBJ_Y_FLIP: u8 = 0b0100_0000;
+const OBJ_X_FLIP: u8 = 0b0010_0000;
+const OBJ_PALETTE: u8 = 0b0001_0000;
+
+#[derive(Debug, Clone, Copy)]
+struct Sprite {
+    // Screen coordinates of the top edge, X is still offset by 8
+    y: i16,
+    x: u8,
+    tile: u8,
+    flags: u8,
+    index: usize,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct ObjPixel {
+    color: u8,
+    flags: u8,
+}
+
+#[derive(Debug, Default)]
+struct Fetcher {
+    // Tile column relative to the start of the line (or of the window)
+    x: u8,
+    // Dots spent on the current tile, it's ready to push once they reach FETCH_DOTS
+    dots: u8,
+    window: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    HBlank = 0,
+    VBlank = 1,
+    OamScan = 2,
+    Drawing = 3,
+}
+
 #[derive(Debug)]
-pub struct LCD {
+pub struct Lcd {
     regs: [u8; CAPACITY],
+    mode: Mode,
+    // Position within the current line, 0-455
+    dot: u16,
+    // The STAT interrupt fires on the rising edge of all enabled sources OR-ed together
+    stat_line: bool,
+    // Set once LY matched WY during the frame, the window can't show up before that
+    window_y_triggered: bool,
+    // Window row to draw next, only advances on lines where the window was visible
+    window_line: u8,
+    // Objects selected by the OAM scan of the current line, in drawing priority
+    sprites: Vec<Sprite>,
+    // Mode 3 pixel pipeline
+    bg_fifo: VecDeque<u8>,
+    obj_fifo: VecDeque<ObjPixel>,
+    fetcher: Fetcher,
+    // Next pixel of the line to be shifted out to the LCD
+    lx: u8,
+    // Pixels to drop before output starts, for fine scrolling and windows left of the edge
+    discard: u8,
+    stall: u8,
+    next_sprite: usize,
+    sprite_fetch_dots: u8,
+    window_active: bool,
+    // Shades 0-3 after applying the palettes, row by row
+    framebuffer: Box<[u8]>,
 }
 
-impl LCD {
-    pub fn new() -> LCD {
-        let mut lcd = LCD {
+impl Lcd {
+    pub fn new() -> Lcd {
+        let mut lcd = Lcd {
             regs: [0; CAPACITY],
+            mode: Mode::OamScan,
+            dot: 0,
+            stat_line: false,
+            window_y_triggered: false,
+            window_line: 0,
+            sprites: Vec::with_capacity(SPRITES_PER_LINE),
+            bg_fifo: VecDeque::with_capacity(16),
+            obj_fifo: VecDeque::with_capacity(8),
+            fetcher: Fetcher::default(),
+            lx: 0,
+            discard: 0,
+            stall: 0,
+            next_sprite: 0,
+            sprite_fetch_dots: 0,
+            window_active: false,
+            framebuffer: vec![0; SCREEN_WIDTH * SCREEN_HEIGHT].into_boxed_slice(),
         };
-        // TODO: Test - is it necessary?
-        lcd.regs[0xFF44-START] = 0x90;
+        // State left behind by the boot ROM
+        lcd.regs[LCDC - START] = 0x91;
+        lcd.regs[BGP - START] = 0xFC;
         lcd
     }
 
     pub fn write(&mut self, addr: usize, value: u8) {
-       self.regs[addr - START] = value; 
+        match addr {
+            LCDC => {
+                let was_enabled = self.is_enabled();
+                self.regs[LCDC - START] = value;
+                if was_enabled && !self.is_enabled() {
+                    self.regs[LY - START] = 0;
+                    self.dot = 0;
+                    self.mode = Mode::HBlank;
+                    self.window_y_triggered = false;
+                    self.window_line = 0;
+                } else if !was_enabled && self.is_enabled() {
+                    self.mode = Mode::OamScan;
+                }
+            }
+            STAT => self.regs[STAT - START] = value & STAT_WRITABLE,
+            // LY is read only
+            LY => {}
+            _ => self.regs[addr - START] = value,
+        }
     }
 
     pub fn read(&self, addr: usize) -> u8 {
-       return self.regs[addr - START]; 
+        match addr {
+            STAT => {
+                let mut stat = 0x80 | self.regs[STAT - START] | self.mode as u8;
+                if self.ly() == self.regs[LYC - START] {
+                    stat |= STAT_COINCIDENCE;
+                }
+                stat
+            }
+            _ => self.regs[addr - START],
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.regs[LCDC - START] & LCDC_ENABLE != 0
+    }
+
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    pub fn ly(&self) -> u8 {
+        self.regs[LY - START]
     }
+
+    /// The last rendered picture, one shade (0 = white, 3 = black) per pixel.
+    pub fn framebuffer(&self) -> &[u8] {
+        &self.framebuffer
+    }
+
+    /// Advances the LCD by the given number of dots (clock cycles) and returns
+    /// the interrupts it requested in the IF layout.
+    pub fn step(&mut self, cycles: u8, vram: &Ram, oam: &Ram) -> u8 {
+        let mut interrupts = 0;
+        if !self.is_enabled() {
+            return interrupts;
+        }
+        for _ in 0..cycles {
+            interrupts |= self.tick(vram, oam);
+        }
+        interrupts
+    }
+
+    fn tick(&mut self, vram: &Ram, oam: &Ram) -> u8 {
+        let mut interrupts = 0;
+        self.dot += 1;
+        if self.dot == DOTS_PER_LINE {
+            self.dot = 0;
+            let ly = (self.ly() + 1) % LINES;
+            self.regs[LY - START] = ly;
+            if ly == VISIBLE_LINES {
+                self.mode = Mode::VBlank;
+                interrupts |= INT_VBLANK;
+            } else if ly < VISIBLE_LINES {
+                self.mode = Mode::OamScan;
+            }
+            if ly == 0 {
+                self.window_y_triggered = false;
+                self.window_line = 0;
+            }
+        } else if self.ly() < VISIBLE_LINES {
+            if self.dot == OAM_SCAN_DOTS {
+                self.scan_oam(oam);
+                self.start_drawing();
+            } else if self.mode == Mode::Drawing {
+                self.draw_dot(vram);
+                if self.lx as usize == SCREEN_WIDTH {
+                    self.mode = Mode::HBlank;
+                    if self.window_active {
+                        self.window_line += 1;
+                    }
+                }
+            }
+        }
+
+        let stat_line = self.stat_line();
+        if stat_line && !self.stat_line {
+            interrupts |= INT_STAT;
+        }
+        self.stat_line = stat_line;
+        interrupts
+    }
+
+    /// Picks the (up to 10) objects overlapping the current line, in OAM order.
+    fn scan_oam(&mut self, oam: &Ram) {
+        let height = self.sprite_height();
+        let ly = self.ly() as i16;
+        self.sprites.clear();
+        for index in 0..OAM_ENTRIES {
+            let entry = index * 4;
+            let y = oam.read(entry) as i16 - 16;
+            if ly < y || ly >= y + height as i16 {
+                continue;
+            }
+            self.sprites.push(Sprite {
+                y,
+                x: oam.read(entry + 1),
+                tile: oam.read(entry + 2),
+                flags: oam.read(entry + 3),
+                index,
+            });
+            if self.sprites.len() == SPRITES_PER_LINE {
+                break;
+            }
+        }
+        // On DMG the object with the smaller X wins, ties go to the lower OAM index
+        self.sprites.sort_by_key(|sprite| (sprite.x, sprite.index));
+    }
+
+    fn sprite_height(&self) -> u8 {
+        if self.regs[LCDC - START] & LCDC_OBJ_SIZE != 0 {16} else {8}
+    }
+
+    fn start_drawing(&mut self) {
+        self.mode = Mode::Drawing;
+        if self.ly() == self.regs[WY - START] {
+            self.window_y_triggered = true;
+        }
+        self.bg_fifo.clear();
+        self.obj_fifo.clear();
+        self.fetcher = Fetcher::default();
+        self.lx = 0;
+        // The pixels scrolled out of the first tile are fetched and thrown away
+        self.discard = self.regs[SCX - START] % 8;
+        self.stall = DUMMY_FETCH_DOTS;
+        self.next_sprite = 0;
+        self.sprite_fetch_dots = 0;
+        self.window_active = false;
+    }
+
+    /// One dot of mode 3: the fetcher fills the background FIFO, objects are fetched
+    /// as the output reaches them and one pixel at most is shifted out to the LCD.
+    fn draw_dot(&mut self, vram: &Ram) {
+        if self.stall > 0 {
+            self.stall -= 1;
+            return;
+        }
+
+        // An object fetch stalls everything else
+        if self.sprite_fetch_dots > 0 {
+            self.sprite_fetch_dots -= 1;
+            if self.sprite_fetch_dots == 0 {
+                self.fetch_sprite(vram);
+            }
+            return;
+        }
+
+        let lcdc = self.regs[LCDC - START];
+        let wx = self.regs[WX - START];
+        if !self.window_active && lcdc & LCDC_WINDOW_ENABLE != 0 && self.window_y_triggered
+            && self.lx as u16 + 7 >= wx as u16
+        {
+            // The window restarts the fetcher from its own first tile
+            self.window_active = true;
+            self.bg_fifo.clear();
+            self.fetcher = Fetcher {window: true, ..Default::default()};
+            self.discard = 7u8.saturating_sub(wx);
+        }
+
+        if self.discard == 0 && lcdc & LCDC_OBJ_ENABLE != 0 && self.sprite_due() {
+            // The object fetch waits until the background fetcher reads its last byte
+            if self.fetcher.dots >= FETCH_DOTS - 1 {
+                // this dot is the first one of the fetch
+                self.sprite_fetch_dots = SPRITE_FETCH_DOTS - 1;
+            } else {
+                self.fetcher.dots += 1;
+            }
+            return;
+        }
+
+        if self.fetcher.dots < FETCH_DOTS {
+            self.fetcher.dots += 1;
+        }
+        self.shift_pixel();
+        // The row is pushed as soon as the FIFO runs dry
+        if self.fetcher.dots == FETCH_DOTS && self.bg_fifo.is_empty() {
+            let row = self.fetch_tile_row(vram);
+            self.bg_fifo.extend(row.iter());
+            self.fetcher.x = self.fetcher.x.wrapping_add(1);
+            self.fetcher.dots = 0;
+        }
+    }
+
+    fn sprite_due(&self) -> bool {
+        self.sprites.get(self.next_sprite).is_some_and(|sprite| sprite.x as u16 <= self.lx as u16 + 8)
+    }
+
+    /// Color indexes (0-3) of the 8 pixels the fetcher is at, read from the registers as they are now.
+    fn fetch_tile_row(&self, vram: &Ram) -> [u8; 8] {
+        let lcdc = self.regs[LCDC - START];
+        let (map, x, y) = if self.fetcher.window {
+            let map = if lcdc & LCDC_WINDOW_MAP != 0 {TILE_MAP_1} else {TILE_MAP_0};
+            (map, self.fetcher.x, self.window_line)
+        } else {
+            let map = if lcdc & LCDC_BG_MAP != 0 {TILE_MAP_1} else {TILE_MAP_0};
+            let x = (self.regs[SCX - START] / 8).wrapping_add(self.fetcher.x);
+            (map, x, self.ly().wrapping_add(self.regs[SCY - START]))
+        };
+        let tile = vram.read(map + (y as usize / 8) * 32 + (x as usize % 32));
+        let tile_addr = if lcdc & LCDC_TILE_DATA != 0 {
+            tile as usize * 16
+        } else {
+            (TILE_DATA_SIGNED_BASE as isize + (tile as i8) as isize * 16) as usize
+        };
+        decode_row(vram, tile_addr + (y as usize % 8) * 2, false)
+    }
+
+    /// Mixes the next object into the object FIFO, pixels already there keep priority.
+    fn fetch_sprite(&mut self, vram: &Ram) {
+        let sprite = self.sprites[self.next_sprite];
+        self.next_sprite += 1;
+
+        let height = self.sprite_height();
+        let mut line = (self.ly() as i16 - sprite.y) as u8;
+        if sprite.flags & OBJ_Y_FLIP != 0 {
+            line = height - 1 - line;
+        }
+        // 8x16 objects ignore the lowest bit of the tile index
+        let tile = if height == 16 {sprite.tile & 0xFE} else {sprite.tile};
+        let row = decode_row(vram, tile as usize * 16 + line as usize * 2, sprite.flags & OBJ_X_FLIP != 0);
+
+        while self.obj_fifo.len() < 8 {
+            self.obj_fifo.push_back(ObjPixel::default());
+        }
+        // Objects hanging off the left edge lose their leftmost pixels
+        let clipped = (self.lx as usize + 8).saturating_sub(sprite.x as usize);
+        for (slot, &color) in self.obj_fifo.iter_mut().zip(row.iter().skip(clipped)) {
+            if slot.color == 0 {
+                *slot = ObjPixel {color, flags: sprite.flags};
+            }
+        }
+    }
+
+    fn shift_pixel(&mut self) {
+        let bg_color = match self.bg_fifo.pop_front() {
+            Some(color) => color,
+            None => return,
+        };
+        if self.discard > 0 {
+            self.discard -= 1;
+            return;
+        }
+        let obj = self.obj_fifo.pop_front().unwrap_or_default();
+
+        let lcdc = self.regs[LCDC - START];
+        // With BG disabled the DMG shows neither background nor window
+        let bg_color = if lcdc & LCDC_BG_ENABLE != 0 {bg_color} else {0};
+        let obj_visible = obj.color != 0 && lcdc & LCDC_OBJ_ENABLE != 0
+            && !(obj.flags & OBJ_BEHIND_BG != 0 && bg_color != 0);
+        let shade = if obj_visible {
+            let palette = if obj.flags & OBJ_PALETTE != 0 {OBP1} else {OBP0};
+            apply_palette(self.regs[palette - START], obj.color)
+        } else {
+            apply_palette(self.regs[BGP - START], bg_color)
+        };
+        self.framebuffer[self.ly() as usize * SCREEN_WIDTH + self.lx as usize] = shade;
+        self.lx += 1;
+    }
+
+    fn stat_line(&self) -> bool {
+        let stat = self.regs[STAT - START];
+        (stat & STAT_LYC_INTERRUPT != 0 && self.ly() == self.regs[LYC - START])
+            || (stat & STAT_OAM_INTERRUPT != 0 && self.mode == Mode::OamScan)
+            || (stat & STAT_VBLANK_INTERRUPT != 0 && self.mode == Mode::VBlank)
+            || (stat & STAT_HBLANK_INTERRUPT != 0 && self.mode == Mode::HBlank)
+    }
+}
+
+/// Reads the two bitplanes of a tile row at addr, leftmost pixel first.
+fn decode_row(vram: &Ram, addr: usize, flip: bool) -> [u8; 8] {
+    let low = vram.read(addr);
+    let high = vram.read(addr + 1);
+    let mut row = [0; 8];
+    for (i, color) in row.iter_mut().enumerate() {
+        let bit = if flip {i} else {7 - i};
+        *color = (((high >> bit) & 1) << 1) | ((low >> bit) & 1);
+    }
+    row
 }
 
-impl Default for LCD {
+fn apply_palette(palette: u8, color: u8) -> u8 {
+    (palette >> (color * 2)) & 0b11
+}
+
+impl Default for Lcd {
     fn default() -> Self {
-        LCD {
-                regs: [0; CAPACITY],
-        }
+        Lcd::new()
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run_lines(lcd: &mut Lcd, vram: &Ram, oam: &Ram, lines: u32) -> u8 {
+        let mut interrupts = 0;
+        for _ in 0..lines * DOTS_PER_LINE as u32 / 4 {
+            interrupts |= lcd.step(4, vram, oam);
+        }
+        interrupts
+    }
+
+    #[test]
+    fn modes_within_a_line() {
+        let mut lcd = Lcd::new();
+        let vram = Ram::new(0x2000);
+        let oam = Ram::new(160);
+        assert_eq!(lcd.mode(), Mode::OamScan);
+        lcd.step(80, &vram, &oam);
+        assert_eq!(lcd.mode(), Mode::Drawing);
+        assert_eq!(lcd.read(STAT) & 0b11, 3);
+        lcd.step(172, &vram, &oam);
+        assert_eq!(lcd.mode(), Mode::HBlank);
+        lcd.step(200, &vram, &oam);
+        assert_eq!(lcd.ly(), 0);
+        lcd.step(4, &vram, &oam);
+        assert_eq!(lcd.ly(), 1);
+        assert_eq!(lcd.mode(), Mode::OamScan);
+    }
+
+    #[test]
+    fn vblank() {
+        let mut lcd = Lcd::new();
+        let vram = Ram::new(0x2000);
+        let oam = Ram::new(160);
+        assert_eq!(run_lines(&mut lcd, &vram, &oam, 143), 0);
+        assert_eq!(run_lines(&mut lcd, &vram, &oam, 1), INT_VBLANK);
+        assert_eq!(lcd.ly(), 144);
+        assert_eq!(lcd.mode(), Mode::VBlank);
+        run_lines(&mut lcd, &vram, &oam, 10);
+        assert_eq!(lcd.ly(), 0);
+        assert_eq!(lcd.mode(), Mode::OamScan);
+    }
+
+    #[test]
+    fn lyc_coincidence_interrupt() {
+        let mut lcd = Lcd::new();
+        let vram = Ram::new(0x2000);
+        let oam = Ram::new(160);
+        lcd.write(LYC, 3);
+        lcd.write(STAT, STAT_LYC_INTERRUPT);
+        assert_eq!(run_lines(&mut lcd, &vram, &oam, 2), 0);
+        assert_eq!(lcd.read(STAT) & STAT_COINCIDENCE, 0);
+        assert_eq!(run_lines(&mut lcd, &vram, &oam, 1), INT_STAT);
+        assert_eq!(lcd.read(STAT) & STAT_COINCIDENCE, STAT_COINCIDENCE);
+    }
+
+    #[test]
+    fn stat_interrupt_blocking() {
+        let mut lcd = Lcd::new();
+        let vram = Ram::new(0x2000);
+        let oam = Ram::new(160);
+        // HBlank and OAM scan sources overlap at the line boundary, the line never goes low
+        lcd.write(STAT, STAT_HBLANK_INTERRUPT | STAT_OAM_INTERRUPT);
+        lcd.step(252, &vram, &oam);
+        assert_eq!(lcd.step(4, &vram, &oam), 0);
+        assert_eq!(lcd.step(200, &vram, &oam), 0);
+        assert_eq!(lcd.mode(), Mode::OamScan);
+    }
+
+    #[test]
+    fn disabling_resets_ly() {
+        let mut lcd = Lcd::new();
+        let vram = Ram::new(0x2000);
+        let oam = Ram::new(160);
+        run_lines(&mut lcd, &vram, &oam, 5);
+        lcd.write(LCDC, 0x11);
+        assert_eq!(lcd.ly(), 0);
+        assert_eq!(lcd.read(STAT) & 0b11, 0);
+        assert_eq!(run_lines(&mut lcd, &vram, &oam, 200), 0);
+        assert_eq!(lcd.ly(), 0);
+    }
+
+    fn pixel(lcd: &Lcd, x: usize, y: usize) -> u8 {
+        lcd.framebuffer()[y * SCREEN_WIDTH + x]
+    }
+
+    #[test]
+    fn background_scroll_and_palette() {
+        let mut lcd = Lcd::new();
+        let mut vram = Ram::new(0x2000);
+        let oam = Ram::new(160);
+        // tile 1 is solid color 3, placed second in the first row of the map
+        for i in 0..16 {
+            vram.write(0x10 + i, 0xFF);
+        }
+        vram.write(TILE_MAP_0 + 1, 1);
+        lcd.write(BGP, 0b1110_0100);
+        lcd.write(SCX, 4);
+        run_lines(&mut lcd, &vram, &oam, 1);
+        assert_eq!(pixel(&lcd, 3, 0), 0);
+        assert_eq!(pixel(&lcd, 4, 0), 3);
+        assert_eq!(pixel(&lcd, 11, 0), 3);
+        assert_eq!(pixel(&lcd, 12, 0), 0);
+        // the palette maps color 3 to shade 1
+        lcd.write(BGP, 0b0100_0000);
+        run_lines(&mut lcd, &vram, &oam, 1);
+        assert_eq!(pixel(&lcd, 4, 1), 1);
+    }
+
+    #[test]
+    fn signed_tile_data() {
+        let mut lcd = Lcd::new();
+        let mut vram = Ram::new(0x2000);
+        let oam = Ram::new(160);
+        lcd.write(LCDC, LCDC_ENABLE | LCDC_BG_ENABLE);
+        lcd.write(BGP, 0b1110_0100);
+        // tile 0x80 lives at 0x8800, tile 0 at 0x9000
+        vram.write(TILE_MAP_0, 0x80);
+        vram.write(0x0800, 0b1000_0000);
+        vram.write(TILE_DATA_SIGNED_BASE + 1, 0b1000_0000);
+        vram.write(TILE_MAP_0 + 1, 0x00);
+        run_lines(&mut lcd, &vram, &oam, 1);
+        assert_eq!(pixel(&lcd, 0, 0), 1);
+        assert_eq!(pixel(&lcd, 8, 0), 2);
+    }
+
+    #[test]
+    fn window_line_counter() {
+        let mut lcd = Lcd::new();
+        let mut vram = Ram::new(0x2000);
+        let oam = Ram::new(160);
+        // tile 1 has color 1 on its first row, 2 on the second and 3 on the third
+        vram.write(0x10, 0xFF);
+        vram.write(0x13, 0xFF);
+        vram.write(0x14, 0xFF);
+        vram.write(0x15, 0xFF);
+        vram.write(TILE_MAP_1, 1);
+        lcd.write(LCDC, LCDC_ENABLE | LCDC_WINDOW_MAP | LCDC_WINDOW_ENABLE | LCDC_TILE_DATA | LCDC_BG_ENABLE);
+        lcd.write(BGP, 0b1110_0100);
+        lcd.write(WY, 0);
+        lcd.write(WX, 7);
+        run_lines(&mut lcd, &vram, &oam, 1);
+        assert_eq!(pixel(&lcd, 0, 0), 1);
+        // hiding the window for a line doesn't advance its line counter
+        lcd.write(WX, 200);
+        run_lines(&mut lcd, &vram, &oam, 1);
+        assert_eq!(pixel(&lcd, 0, 1), 0);
+        lcd.write(WX, 7);
+        run_lines(&mut lcd, &vram, &oam, 1);
+        assert_eq!(pixel(&lcd, 0, 2), 2);
+        // WX shifts the window right, the background shows to its left
+        lcd.write(WX, 10);
+        run_lines(&mut lcd, &vram, &oam, 1);
+        assert_eq!(pixel(&lcd, 2, 3), 0);
+        assert_eq!(pixel(&lcd, 3, 3), 3);
+    }
+
+    fn set_sprite(oam: &mut Ram, index: usize, y: u8, x: u8, tile: u8, flags: u8) {
+        oam.write(index * 4, y);
+        oam.write(index * 4 + 1, x);
+        oam.write(index * 4 + 2, tile);
+        oam.write(index * 4 + 3, flags);
+    }
+
+    fn sprite_lcd() -> Lcd {
+        let mut lcd = Lcd::new();
+        lcd.write(LCDC, LCDC_ENABLE | LCDC_TILE_DATA | LCDC_BG_ENABLE | LCDC_OBJ_ENABLE);
+        lcd.write(BGP, 0b1110_0100);
+        lcd.write(OBP0, 0b1110_0100);
+        lcd.write(OBP1, 0b0001_1011);
+        lcd
+    }
+
+    #[test]
+    fn sprite_priority() {
+        let mut lcd = sprite_lcd();
+        let mut vram = Ram::new(0x2000);
+        let mut oam = Ram::new(160);
+        // tile 1 is solid color 1, tile 2 solid color 2
+        for line in 0..8 {
+            vram.write(0x10 + line * 2, 0xFF);
+            vram.write(0x20 + line * 2 + 1, 0xFF);
+        }
+        set_sprite(&mut oam, 0, 16, 12, 1, 0);
+        set_sprite(&mut oam, 1, 16, 8, 2, 0);
+        // same X as the first one but later in OAM, never visible
+        set_sprite(&mut oam, 2, 16, 12, 2, OBJ_PALETTE);
+        run_lines(&mut lcd, &vram, &oam, 1);
+        assert_eq!(pixel(&lcd, 0, 0), 2);
+        assert_eq!(pixel(&lcd, 7, 0), 2);
+        assert_eq!(pixel(&lcd, 8, 0), 1);
+        assert_eq!(pixel(&lcd, 11, 0), 1);
+        assert_eq!(pixel(&lcd, 12, 0), 0);
+    }
+
+    #[test]
+    fn sprite_flips_and_tall_sprites() {
+        let mut lcd = sprite_lcd();
+        lcd.write(LCDC, LCDC_ENABLE | LCDC_TILE_DATA | LCDC_BG_ENABLE | LCDC_OBJ_ENABLE | LCDC_OBJ_SIZE);
+        let mut vram = Ram::new(0x2000);
+        let mut oam = Ram::new(160);
+        // leftmost pixel of the first row of tile 2 and of the last row of tile 3
+        vram.write(0x20, 0b1000_0000);
+        vram.write(0x3F, 0b1000_0000);
+        // the lowest bit of the tile index is ignored
+        set_sprite(&mut oam, 0, 16, 8, 3, 0);
+        set_sprite(&mut oam, 1, 16, 16, 2, OBJ_X_FLIP | OBJ_Y_FLIP | OBJ_PALETTE);
+        run_lines(&mut lcd, &vram, &oam, 16);
+        assert_eq!(pixel(&lcd, 0, 0), 1);
+        assert_eq!(pixel(&lcd, 0, 15), 2);
+        // flipped in both directions, through the inverted palette
+        assert_eq!(pixel(&lcd, 15, 0), 1);
+        assert_eq!(pixel(&lcd, 15, 15), 2);
+        assert_eq!(pixel(&lcd, 8, 0), 0);
+        assert_eq!(pixel(&lcd, 8, 15), 0);
+    }
+
+    #[test]
+    fn ten_sprites_per_line() {
+        let mut lcd = sprite_lcd();
+        let mut vram = Ram::new(0x2000);
+        let mut oam = Ram::new(160);
+        vram.write(0x10, 0xFF);
+        // an offscreen object still counts towards the limit
+        set_sprite(&mut oam, 0, 16, 0, 1, 0);
+        for index in 1..12 {
+            set_sprite(&mut oam, index, 16, 8 * index as u8, 1, 0);
+        }
+        run_lines(&mut lcd, &vram, &oam, 1);
+        for x in (0..72).step_by(8) {
+            assert_eq!(pixel(&lcd, x, 0), 1);
+        }
+        assert_eq!(pixel(&lcd, 72, 0), 0);
+        assert_eq!(pixel(&lcd, 80, 0), 0);
+    }
+
+    #[test]
+    fn sprite_behind_background() {
+        let mut lcd = sprite_lcd();
+        let mut vram = Ram::new(0x2000);
+        let mut oam = Ram::new(160);
+        // tile 1 is solid color 1 for both the background and the object
+        for line in 0..8 {
+            vram.write(0x10 + line * 2, 0xFF);
+        }
+        vram.write(TILE_MAP_0, 1);
+        set_sprite(&mut oam, 0, 16, 12, 1, OBJ_BEHIND_BG | OBJ_PALETTE);
+        run_lines(&mut lcd, &vram, &oam, 1);
+        // hidden behind background color 1, visible over color 0
+        assert_eq!(pixel(&lcd, 4, 0), 1);
+        assert_eq!(pixel(&lcd, 8, 0), 2);
+        // with background disabled nothing hides the object
+        lcd.write(LCDC, LCDC_ENABLE | LCDC_TILE_DATA | LCDC_OBJ_ENABLE);
+        run_lines(&mut lcd, &vram, &oam, 1);
+        assert_eq!(pixel(&lcd, 4, 1), 2);
+    }
+
+    /// Length of mode 3 on the first line.
+    fn drawing_dots(lcd: &mut Lcd, vram: &Ram, oam: &Ram) -> u16 {
+        lcd.step(80, vram, oam);
+        let mut dots = 0;
+        while lcd.mode() == Mode::Drawing {
+            lcd.step(1, vram, oam);
+            dots += 1;
+        }
+        dots
+    }
+
+    #[test]
+    fn drawing_length_penalties() {
+        let vram = Ram::new(0x2000);
+        let mut oam = Ram::new(160);
+        assert_eq!(drawing_dots(&mut sprite_lcd(), &vram, &oam), 172);
+
+        // fine scroll discards the first SCX % 8 pixels
+        let mut lcd = sprite_lcd();
+        lcd.write(SCX, 0x13);
+        assert_eq!(drawing_dots(&mut lcd, &vram, &oam), 175);
+
+        // the window restarts the fetcher
+        let mut lcd = sprite_lcd();
+        lcd.write(LCDC, lcd.read(LCDC) | LCDC_WINDOW_ENABLE);
+        lcd.write(WY, 0);
+        lcd.write(WX, 87);
+        assert_eq!(drawing_dots(&mut lcd, &vram, &oam), 178);
+
+        // objects cost 6 dots plus the wait for the background fetcher, 11 at most
+        set_sprite(&mut oam, 0, 16, 40, 0, 0);
+        assert_eq!(drawing_dots(&mut sprite_lcd(), &vram, &oam), 183);
+        set_sprite(&mut oam, 0, 16, 45, 0, 0);
+        assert_eq!(drawing_dots(&mut sprite_lcd(), &vram, &oam), 178);
+        let mut lcd = sprite_lcd();
+        lcd.write(SCX, 5);
+        set_sprite(&mut oam, 0, 16, 40, 0, 0);
+        assert_eq!(drawing_dots(&mut lcd, &vram, &oam), 183);
+        // disabled objects are not fetched at all
+        let mut lcd = sprite_lcd();
+        lcd.write(LCDC, lcd.read(LCDC) & !LCDC_OBJ_ENABLE);
+        assert_eq!(drawing_dots(&mut lcd, &vram, &oam), 172);
+    }
+
+    #[test]
+    fn mid_line_palette_change() {
+        let mut lcd = sprite_lcd();
+        let mut vram = Ram::new(0x2000);
+        let oam = Ram::new(160);
+        for i in 0..16 {
+            vram.write(0x10 + i, 0xFF);
+        }
+        for i in 0..32 {
+            vram.write(TILE_MAP_0 + i, 1);
+        }
+        // 12 dots into mode 3 the first pixel is shifted out, then one pixel per dot
+        lcd.step(80 + 12 + 50, &vram, &oam);
+        lcd.write(BGP, 0b0100_0000);
+        run_lines(&mut lcd, &vram, &oam, 1);
+        assert_eq!(pixel(&lcd, 49, 0), 3);
+        assert_eq!(pixel(&lcd, 50, 0), 1);
+        assert_eq!(pixel(&lcd, 159, 0), 1);
+    }
+}
@@ -1,5 +1,9 @@
 pub mod sound;
 pub mod lcd;
+pub mod joypad;
+pub mod serial;
+pub mod timer;
+#[allow(clippy::module_inception)]
 pub mod io;
 
 pub use self::io::IO;
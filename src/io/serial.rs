@@ -0,0 +1,246 @@
+use std::cell::RefCell;
+use std::fmt::Debug;
+use std::io::{self, Write};
+use std::rc::Rc;
+
+pub const SB: u16 = 0xFF01;
+pub const SC: u16 = 0xFF02;
+
+pub const INT_SERIAL: u8 = 0b0000_1000;
+
+const SC_TRANSFER: u8 = 0b1000_0000;
+const SC_INTERNAL_CLOCK: u8 = 0b0000_0001;
+// Unused bits read as 1 on DMG
+const SC_UNUSED: u8 = 0b0111_1110;
+
+// 8192 Hz internal clock, one bit every 512 clock cycles
+const CYCLES_PER_BIT: u32 = 512;
+
+/// Whatever sits at the other end of the link cable.
+pub trait SerialDevice: Debug {
+    /// This side drives the clock and starts shifting out `byte`.
+    /// Returns the byte coming back, 0xFF when nothing drives the line.
+    fn transfer(&mut self, byte: u8) -> u8;
+
+    /// Called on every step with the clock cycles that passed. `ready` tells whether this
+    /// side waits for an external clock with `byte` in SB. Returns the incoming byte once
+    /// the other side clocked a transfer, which only completes when `ready` was set.
+    fn poll(&mut self, _cycles: u32, _byte: u8, _ready: bool) -> Option<u8> {
+        None
+    }
+}
+
+/// Nothing plugged in, the other side never clocks and reads all ones.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NullDevice;
+
+impl SerialDevice for NullDevice {
+    fn transfer(&mut self, _byte: u8) -> u8 {
+        0xFF
+    }
+}
+
+/// Collects the bytes sent out, clones share the same buffer.
+#[derive(Debug, Default, Clone)]
+pub struct CaptureDevice {
+    output: Rc<RefCell<Vec<u8>>>,
+}
+
+impl CaptureDevice {
+    pub fn new() -> CaptureDevice {
+        CaptureDevice::default()
+    }
+
+    pub fn output(&self) -> Vec<u8> {
+        self.output.borrow().clone()
+    }
+
+    pub fn take_output(&self) -> Vec<u8> {
+        self.output.take()
+    }
+}
+
+impl SerialDevice for CaptureDevice {
+    fn transfer(&mut self, byte: u8) -> u8 {
+        self.output.borrow_mut().push(byte);
+        0xFF
+    }
+}
+
+/// Prints the bytes sent out as text, test ROMs report their results this way.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct StdoutDevice;
+
+impl SerialDevice for StdoutDevice {
+    fn transfer(&mut self, byte: u8) -> u8 {
+        let mut stdout = io::stdout();
+        // A broken stdout shouldn't take the emulation down with it
+        let _ = stdout.write_all(&[byte]).and_then(|_| stdout.flush());
+        0xFF
+    }
+}
+
+#[derive(Debug)]
+pub struct Serial {
+    sb: u8,
+    sc: u8,
+    device: Box<dyn SerialDevice>,
+    // Byte being shifted in during an internally clocked transfer
+    incoming: u8,
+    bits_left: u8,
+    cycles: u32,
+    interrupt: bool,
+}
+
+impl Serial {
+    pub fn new() -> Serial {
+        Serial {
+            sb: 0,
+            sc: 0,
+            device: Box::new(NullDevice),
+            incoming: 0xFF,
+            bits_left: 0,
+            cycles: 0,
+            interrupt: false,
+        }
+    }
+
+    pub fn read(&self, addr: u16) -> u8 {
+        match addr {
+            SB => self.sb,
+            SC => self.sc | SC_UNUSED,
+            _ => 0xFF,
+        }
+    }
+
+    pub fn write(&mut self, addr: u16, value: u8) {
+        match addr {
+            SB => self.sb = value,
+            SC => {
+                self.sc = value & (SC_TRANSFER | SC_INTERNAL_CLOCK);
+                if self.sc == SC_TRANSFER | SC_INTERNAL_CLOCK {
+                    self.incoming = self.device.transfer(self.sb);
+                    self.bits_left = 8;
+                    self.cycles = 0;
+                } else {
+                    self.bits_left = 0;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    pub fn set_device(&mut self, device: Box<dyn SerialDevice>) {
+        self.device = device;
+    }
+
+    pub fn take_device(&mut self) -> Box<dyn SerialDevice> {
+        std::mem::replace(&mut self.device, Box::new(NullDevice))
+    }
+
+    pub fn is_transferring(&self) -> bool {
+        self.sc & SC_TRANSFER != 0
+    }
+
+    /// Advances the transfer and returns the serial interrupt in the IF layout once it completes.
+    pub fn step(&mut self, cycles: u8) -> u8 {
+        let ready = self.sc == SC_TRANSFER;
+        if let Some(incoming) = self.device.poll(cycles as u32, self.sb, ready) {
+            if ready {
+                // The other side drove all 8 bits already
+                self.sb = incoming;
+                self.finish();
+            }
+        }
+        if self.is_transferring() && self.sc & SC_INTERNAL_CLOCK != 0 {
+            self.shift(cycles as u32);
+        }
+        if std::mem::take(&mut self.interrupt) {INT_SERIAL} else {0}
+    }
+
+    fn shift(&mut self, cycles: u32) {
+        self.cycles += cycles;
+        while self.bits_left > 0 && self.cycles >= CYCLES_PER_BIT {
+            self.cycles -= CYCLES_PER_BIT;
+            self.bits_left -= 1;
+            let bit = (self.incoming >> self.bits_left) & 1;
+            self.sb = (self.sb << 1) | bit;
+            if self.bits_left == 0 {
+                self.finish();
+            }
+        }
+    }
+
+    fn finish(&mut self) {
+        self.sc &= !SC_TRANSFER;
+        self.interrupt = true;
+    }
+}
+
+impl Default for Serial {
+    fn default() -> Self {
+        Serial::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug)]
+    struct Loopback(Option<u8>);
+
+    impl SerialDevice for Loopback {
+        fn transfer(&mut self, byte: u8) -> u8 {
+            byte ^ 0xFF
+        }
+
+        fn poll(&mut self, _cycles: u32, _byte: u8, _ready: bool) -> Option<u8> {
+            self.0.take()
+        }
+    }
+
+    #[test]
+    fn internal_clock_transfer() {
+        let capture = CaptureDevice::new();
+        let mut serial = Serial::new();
+        serial.set_device(Box::new(capture.clone()));
+        serial.write(SB, 0x42);
+        serial.write(SC, 0x81);
+        assert_eq!(capture.output(), vec![0x42]);
+        assert_eq!(serial.read(SC), 0xFF);
+        // bits shift in one by one, MSB first
+        assert_eq!(serial.step(255), 0);
+        assert_eq!(serial.step(255), 0);
+        assert_eq!(serial.step(2), 0);
+        assert_eq!(serial.read(SB), 0x85);
+        for _ in 0..7 * 4 - 1 {
+            assert_eq!(serial.step(128), 0);
+        }
+        assert_eq!(serial.step(128), INT_SERIAL);
+        assert_eq!(serial.read(SB), 0xFF);
+        assert_eq!(serial.read(SC), 0x7F);
+        assert_eq!(serial.step(255), 0);
+    }
+
+    #[test]
+    fn external_clock_transfer() {
+        let mut serial = Serial::new();
+        serial.set_device(Box::new(Loopback(None)));
+        serial.write(SB, 0x42);
+        serial.write(SC, 0x80);
+        // without a partner an externally clocked transfer never finishes
+        assert_eq!(serial.step(255), 0);
+        assert!(serial.is_transferring());
+
+        serial.set_device(Box::new(Loopback(Some(0x24))));
+        assert_eq!(serial.step(4), INT_SERIAL);
+        assert_eq!(serial.read(SB), 0x24);
+        assert!(!serial.is_transferring());
+
+        // a clock while not armed is ignored
+        serial.set_device(Box::new(Loopback(Some(0x11))));
+        assert_eq!(serial.step(4), 0);
+        assert_eq!(serial.read(SB), 0x24);
+    }
+}
@@ -1,34 +1,1244 @@
+use crate::audio::Resampler;
+
 pub const START: usize = 0xFF10;
 pub const END: usize = 0xFF27;
 
+pub const WAVE_START: usize = 0xFF30;
+pub const WAVE_END: usize = 0xFF40;
+
 const CAPACITY: usize = END-START;
+const WAVE_CAPACITY: usize = WAVE_END-WAVE_START;
+
+const NR10: usize = 0xFF10;
+const NR14: usize = 0xFF14;
+// Channel 2 has no sweep, NR21 sits where NR20 would be
+const NR20: usize = 0xFF15;
+const NR21: usize = 0xFF16;
+const NR24: usize = 0xFF19;
+const NR30: usize = 0xFF1A;
+const NR34: usize = 0xFF1E;
+// Same for channel 4
+const NR40: usize = 0xFF1F;
+const NR41: usize = 0xFF20;
+const NR44: usize = 0xFF23;
+const NR50: usize = 0xFF24;
+const NR51: usize = 0xFF25;
+const NR52: usize = 0xFF26;
+
+const POWER: u8 = 0b1000_0000;
+const TRIGGER: u8 = 0b1000_0000;
+const LENGTH_ENABLE: u8 = 0b0100_0000;
+
+// The frame sequencer steps on falling edges of this system counter bit (DIV bit 4), at 512 Hz
+const FRAME_SEQUENCER_BIT: u16 = 1 << 12;
+
+// Bits reading back as 1, write-only and unused ones
+const READ_MASKS: [u8; CAPACITY] = [
+    0x80, 0x3F, 0x00, 0xFF, 0xBF, // NR10-NR14
+    0xFF, 0x3F, 0x00, 0xFF, 0xBF, // NR21-NR24
+    0x7F, 0xFF, 0x9F, 0xFF, 0xBF, // NR30-NR34
+    0xFF, 0xFF, 0x00, 0x00, 0xBF, // NR41-NR44
+    0x00, 0x00, 0x70,             // NR50-NR52
+];
+
+// One bit per step, played from the MSB
+const DUTY_PATTERNS: [u8; 4] = [0b0000_0001, 0b1000_0001, 0b1000_0111, 0b0111_1110];
+const NOISE_DIVISORS: [u32; 8] = [8, 16, 32, 48, 64, 80, 96, 112];
+
+/// Counts down at 256 Hz and silences its channel when it runs out.
+#[derive(Debug, Default)]
+struct Length {
+    counter: u16,
+    enabled: bool,
+}
+
+impl Length {
+    fn load(&mut self, max: u16, value: u8) {
+        self.counter = max - value as u16;
+    }
+
+    // `extra_clock` is set when the next frame sequencer step doesn't clock lengths, enabling
+    // the counter then clocks it right away. Returns whether that made it run out
+    fn set_enabled(&mut self, enabled: bool, extra_clock: bool) -> bool {
+        let was_enabled = self.enabled;
+        self.enabled = enabled;
+        if !extra_clock || was_enabled || !enabled || self.counter == 0 {
+            return false;
+        }
+        self.counter -= 1;
+        self.counter == 0
+    }
+
+    fn trigger(&mut self, max: u16, extra_clock: bool) {
+        if self.counter == 0 {
+            self.counter = if self.enabled && extra_clock {max - 1} else {max};
+        }
+    }
+
+    // Powering off leaves the counter alone on DMG
+    fn powered_off(&self) -> Length {
+        Length {counter: self.counter, enabled: false}
+    }
+
+    // Returns whether the channel has to be turned off
+    fn clock(&mut self) -> bool {
+        if !self.enabled || self.counter == 0 {
+            return false;
+        }
+        self.counter -= 1;
+        self.counter == 0
+    }
+}
+
+/// Volume changing by one every `pace` 64 Hz ticks.
+#[derive(Debug, Default)]
+struct Envelope {
+    initial: u8,
+    increase: bool,
+    pace: u8,
+    volume: u8,
+    timer: u8,
+    // Cleared once the volume hits 0 or 15
+    updating: bool,
+}
+
+impl Envelope {
+    fn write(&mut self, value: u8, channel_enabled: bool) {
+        let (pace, increase) = (self.pace, self.increase);
+        self.initial = value >> 4;
+        self.increase = value & 0b1000 != 0;
+        self.pace = value & 0b111;
+        if channel_enabled {
+            self.zombie_update(pace, increase);
+        }
+    }
+
+    // Writing NRx2 of a playing channel changes the volume in odd ways ("zombie mode")
+    fn zombie_update(&mut self, old_pace: u8, old_increase: bool) {
+        if old_pace == 0 && self.updating {
+            self.volume = self.volume.wrapping_add(1);
+        } else if !old_increase {
+            self.volume = self.volume.wrapping_add(2);
+        }
+        if old_increase != self.increase {
+            self.volume = 16u8.wrapping_sub(self.volume);
+        }
+        self.volume &= 0x0F;
+    }
+
+    // The DAC is on as long as the upper 5 bits of NRx2 are not all 0
+    fn dac_enabled(&self) -> bool {
+        self.initial != 0 || self.increase
+    }
+
+    fn trigger(&mut self) {
+        self.volume = self.initial;
+        self.timer = self.pace;
+        self.updating = true;
+    }
+
+    fn clock(&mut self) {
+        if self.pace == 0 {
+            return;
+        }
+        self.timer = self.timer.saturating_sub(1);
+        if self.timer > 0 {
+            return;
+        }
+        self.timer = self.pace;
+        if self.increase && self.volume < 15 {
+            self.volume += 1;
+        } else if !self.increase && self.volume > 0 {
+            self.volume -= 1;
+        } else {
+            self.updating = false;
+        }
+    }
+}
+
+/// Channel 1 period sweep, working on a shadow copy of the period.
+#[derive(Debug, Default)]
+struct Sweep {
+    pace: u8,
+    decrease: bool,
+    step: u8,
+    timer: u8,
+    shadow: u16,
+    enabled: bool,
+    // A period got calculated in decrease mode since the trigger
+    decreased: bool,
+}
+
+impl Sweep {
+    fn write(&mut self, value: u8) {
+        self.pace = (value >> 4) & 0b111;
+        self.decrease = value & 0b1000 != 0;
+        self.step = value & 0b111;
+    }
+
+    fn reload_timer(&mut self) {
+        // A pace of 0 is treated as 8
+        self.timer = if self.pace == 0 {8} else {self.pace};
+    }
+
+    fn next_period(&mut self) -> u16 {
+        let delta = self.shadow >> self.step;
+        self.decreased |= self.decrease;
+        if self.decrease {self.shadow - delta} else {self.shadow + delta}
+    }
+}
+
+/// Channels 1 and 2.
+#[derive(Debug, Default)]
+struct Square {
+    enabled: bool,
+    duty: u8,
+    position: u8,
+    period: u16,
+    timer: u32,
+    length: Length,
+    envelope: Envelope,
+    sweep: Sweep,
+}
+
+impl Square {
+    const LENGTH: u16 = 64;
+    const MAX_PERIOD: u16 = 0x7FF;
+
+    // `reg` is the register index within the channel, NRx0 to NRx4
+    fn write(&mut self, reg: usize, value: u8, extra_length_clock: bool) {
+        match reg {
+            0 => {
+                self.sweep.write(value);
+                // Leaving decrease mode after it was used disables the channel
+                if self.sweep.decreased && !self.sweep.decrease {
+                    self.enabled = false;
+                }
+            }
+            1 => {
+                self.duty = value >> 6;
+                self.length.load(Square::LENGTH, value & 0x3F);
+            }
+            2 => {
+                self.envelope.write(value, self.enabled);
+                if !self.envelope.dac_enabled() {
+                    self.enabled = false;
+                }
+            }
+            3 => self.period = (self.period & 0x700) | value as u16,
+            _ => {
+                self.period = (self.period & 0xFF) | ((value as u16 & 0b111) << 8);
+                let expired = self.length.set_enabled(value & LENGTH_ENABLE != 0, extra_length_clock);
+                if value & TRIGGER != 0 {
+                    self.trigger(extra_length_clock);
+                } else if expired {
+                    self.enabled = false;
+                }
+            }
+        }
+    }
+
+    fn trigger(&mut self, extra_length_clock: bool) {
+        self.enabled = self.envelope.dac_enabled();
+        self.length.trigger(Square::LENGTH, extra_length_clock);
+        self.timer = self.timer_period();
+        self.envelope.trigger();
+
+        self.sweep.shadow = self.period;
+        self.sweep.reload_timer();
+        self.sweep.enabled = self.sweep.pace != 0 || self.sweep.step != 0;
+        self.sweep.decreased = false;
+        // The overflow check runs right away when there's a step
+        if self.sweep.step != 0 && self.sweep.next_period() > Square::MAX_PERIOD {
+            self.enabled = false;
+        }
+    }
+
+    fn timer_period(&self) -> u32 {
+        (2048 - self.period as u32) * 4
+    }
+
+    fn step(&mut self, mut cycles: u32) {
+        while cycles >= self.timer {
+            cycles -= self.timer;
+            self.timer = self.timer_period();
+            self.position = (self.position + 1) % 8;
+        }
+        self.timer -= cycles;
+    }
+
+    fn clock_sweep(&mut self) {
+        self.sweep.timer = self.sweep.timer.saturating_sub(1);
+        if self.sweep.timer > 0 {
+            return;
+        }
+        self.sweep.reload_timer();
+        if !self.sweep.enabled || self.sweep.pace == 0 {
+            return;
+        }
+        let period = self.sweep.next_period();
+        if period > Square::MAX_PERIOD {
+            self.enabled = false;
+        } else if self.sweep.step != 0 {
+            self.sweep.shadow = period;
+            self.period = period;
+            // The new period gets checked for overflow once more
+            if self.sweep.next_period() > Square::MAX_PERIOD {
+                self.enabled = false;
+            }
+        }
+    }
+
+    fn output(&self) -> u8 {
+        let high = DUTY_PATTERNS[self.duty as usize] & (0x80 >> self.position) != 0;
+        if self.enabled && high {self.envelope.volume} else {0}
+    }
+}
+
+/// Channel 3, playing the 32 4-bit samples of wave RAM.
+#[derive(Debug, Default)]
+struct Wave {
+    enabled: bool,
+    dac_enabled: bool,
+    volume_code: u8,
+    period: u16,
+    timer: u32,
+    position: u8,
+    sample: u8,
+    length: Length,
+    ram: [u8; WAVE_CAPACITY],
+}
+
+impl Wave {
+    const LENGTH: u16 = 256;
+
+    // Reading wave RAM takes a couple of cycles before the sample plays
+    const TRIGGER_DELAY: u32 = 6;
+
+    fn write(&mut self, reg: usize, value: u8, extra_length_clock: bool) {
+        match reg {
+            0 => {
+                self.dac_enabled = value & 0x80 != 0;
+                if !self.dac_enabled {
+                    self.enabled = false;
+                }
+            }
+            1 => self.length.load(Wave::LENGTH, value),
+            2 => self.volume_code = (value >> 5) & 0b11,
+            3 => self.period = (self.period & 0x700) | value as u16,
+            _ => {
+                self.period = (self.period & 0xFF) | ((value as u16 & 0b111) << 8);
+                let expired = self.length.set_enabled(value & LENGTH_ENABLE != 0, extra_length_clock);
+                if value & TRIGGER != 0 {
+                    // The DMG mangles wave RAM when retriggered right as a sample is read
+                    if self.enabled && self.timer <= 2 {
+                        self.corrupt_ram();
+                    }
+                    self.enabled = self.dac_enabled;
+                    self.length.trigger(Wave::LENGTH, extra_length_clock);
+                    self.timer = self.timer_period() + Wave::TRIGGER_DELAY;
+                    self.position = 0;
+                } else if expired {
+                    self.enabled = false;
+                }
+            }
+        }
+    }
+
+    // The first bytes get overwritten with the ones around the byte being read
+    fn corrupt_ram(&mut self) {
+        let index = (self.position as usize + 1) % 32 / 2;
+        if index < 4 {
+            self.ram[0] = self.ram[index];
+        } else {
+            let start = index & !0b11;
+            self.ram.copy_within(start..start + 4, 0);
+        }
+    }
+
+    fn timer_period(&self) -> u32 {
+        (2048 - self.period as u32) * 2
+    }
+
+    fn step(&mut self, mut cycles: u32) {
+        while cycles >= self.timer {
+            cycles -= self.timer;
+            self.timer = self.timer_period();
+            self.position = (self.position + 1) % 32;
+            // Upper nibble first
+            let byte = self.ram[self.position as usize / 2];
+            self.sample = if self.position.is_multiple_of(2) {byte >> 4} else {byte & 0x0F};
+        }
+        self.timer -= cycles;
+    }
+
+    /// Index of the wave RAM byte the channel reads during the next M-cycle, None when it
+    /// doesn't read one then.
+    fn byte_read_next(&self) -> Option<usize> {
+        if !self.enabled || self.timer > 4 {
+            return None;
+        }
+        Some((self.position as usize + 1) % 32 / 2)
+    }
+
+    fn output(&self) -> u8 {
+        if !self.enabled {
+            return 0;
+        }
+        match self.volume_code {
+            0 => 0,
+            1 => self.sample,
+            2 => self.sample >> 1,
+            _ => self.sample >> 2,
+        }
+    }
+}
+
+/// Channel 4, pseudo random noise out of a linear feedback shift register.
+#[derive(Debug, Default)]
+struct Noise {
+    enabled: bool,
+    shift: u8,
+    short_mode: bool,
+    divisor: u8,
+    lfsr: u16,
+    timer: u32,
+    length: Length,
+    envelope: Envelope,
+}
+
+impl Noise {
+    const LENGTH: u16 = 64;
+
+    fn write(&mut self, reg: usize, value: u8, extra_length_clock: bool) {
+        match reg {
+            1 => self.length.load(Noise::LENGTH, value & 0x3F),
+            2 => {
+                self.envelope.write(value, self.enabled);
+                if !self.envelope.dac_enabled() {
+                    self.enabled = false;
+                }
+            }
+            3 => {
+                self.shift = value >> 4;
+                self.short_mode = value & 0b1000 != 0;
+                self.divisor = value & 0b111;
+            }
+            4 => {
+                let expired = self.length.set_enabled(value & LENGTH_ENABLE != 0, extra_length_clock);
+                if value & TRIGGER != 0 {
+                    self.enabled = self.envelope.dac_enabled();
+                    self.length.trigger(Noise::LENGTH, extra_length_clock);
+                    self.timer = self.timer_period();
+                    self.envelope.trigger();
+                    self.lfsr = 0x7FFF;
+                } else if expired {
+                    self.enabled = false;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn timer_period(&self) -> u32 {
+        NOISE_DIVISORS[self.divisor as usize] << self.shift
+    }
+
+    fn step(&mut self, mut cycles: u32) {
+        while cycles >= self.timer {
+            cycles -= self.timer;
+            self.timer = self.timer_period();
+            // Shifts of 14 and 15 leave the LFSR without a clock
+            if self.shift < 14 {
+                self.clock_lfsr();
+            }
+        }
+        self.timer -= cycles;
+    }
+
+    fn clock_lfsr(&mut self) {
+        let feedback = (self.lfsr ^ (self.lfsr >> 1)) & 1;
+        self.lfsr = (self.lfsr >> 1) | (feedback << 14);
+        if self.short_mode {
+            self.lfsr = (self.lfsr & !(1 << 6)) | (feedback << 6);
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if self.enabled && self.lfsr & 1 == 0 {self.envelope.volume} else {0}
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Channel {
+    Square1,
+    Square2,
+    Wave,
+    Noise,
+}
+
+impl Channel {
+    pub const ALL: [Channel; 4] = [Channel::Square1, Channel::Square2, Channel::Wave, Channel::Noise];
+}
+
+/// A write to one of the sound registers or wave RAM.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RegisterWrite {
+    /// Clock cycles since the log was started.
+    pub cycle: u64,
+    pub addr: u16,
+    pub value: u8,
+}
+
+/// Everything around the APU that isn't hardware: mute and solo, resampling of the mixed
+/// output, capture of each channel on its own and the register write log. Kept apart so it
+/// survives a reset.
+#[derive(Debug, Default)]
+pub struct Mixer {
+    muted: [bool; 4],
+    soloed: [bool; 4],
+    resampler: Option<Resampler>,
+    // One per channel, fed the DAC output before panning and volume
+    capture: Option<Vec<Resampler>>,
+    write_log: Option<Vec<RegisterWrite>>,
+    log_cycles: u64,
+}
+
+impl Mixer {
+    pub fn new() -> Mixer {
+        Mixer::default()
+    }
+
+    // When any channel is soloed only soloed channels play
+    fn is_audible(&self, channel: usize) -> bool {
+        let solo = self.soloed.contains(&true);
+        !self.muted[channel] && (!solo || self.soloed[channel])
+    }
+
+    fn push(&mut self, channels: [f32; 4], output: (f32, f32)) {
+        if let Some(capture) = &mut self.capture {
+            for (resampler, &sample) in capture.iter_mut().zip(channels.iter()) {
+                resampler.push((sample, sample));
+            }
+        }
+        if let Some(resampler) = &mut self.resampler {
+            resampler.push(output);
+        }
+    }
+}
 
 #[derive(Debug)]
 pub struct SoundController {
     regs: [u8; CAPACITY],
+    power: bool,
+    frame_step: u8,
+    square1: Square,
+    square2: Square,
+    wave: Wave,
+    noise: Noise,
+    mixer: Mixer,
 }
 
 impl SoundController {
     pub fn new() -> SoundController {
-        SoundController {
+        let mut sound = SoundController {
             regs: [0; CAPACITY],
-        }
+            power: false,
+            frame_step: 0,
+            square1: Square::default(),
+            square2: Square::default(),
+            wave: Wave::default(),
+            noise: Noise::default(),
+            mixer: Mixer::new(),
+        };
+        // Left behind by the boot ROM after its chime, channel 1 is still on
+        // with its envelope faded out
+        sound.write(NR52, POWER);
+        sound.write(0xFF11, 0x80);
+        sound.write(0xFF12, 0xF3);
+        sound.write(0xFF13, 0xC1);
+        sound.write(NR14, TRIGGER | 0x07);
+        sound.square1.envelope.volume = 0;
+        sound.square1.envelope.updating = false;
+        sound.write(NR50, 0x77);
+        sound.write(NR51, 0xF3);
+        sound
     }
 
     pub fn write(&mut self, addr: usize, value: u8) {
-       self.regs[addr - START] = value; 
+        self.log_write(addr, value);
+        if addr == NR52 {
+            self.write_power(value);
+            return;
+        }
+        if !self.power {
+            // Only the length counters stay writable on DMG
+            match addr {
+                0xFF11 => self.square1.length.load(Square::LENGTH, value & 0x3F),
+                0xFF16 => self.square2.length.load(Square::LENGTH, value & 0x3F),
+                0xFF1B => self.wave.length.load(Wave::LENGTH, value),
+                0xFF20 => self.noise.length.load(Noise::LENGTH, value & 0x3F),
+                _ => {}
+            }
+            return;
+        }
+        self.regs[addr - START] = value;
+        // Lengths are clocked on even steps, `frame_step` being the next one
+        let extra_length_clock = !self.frame_step.is_multiple_of(2);
+        match addr {
+            NR10..=NR14 => self.square1.write(addr - NR10, value, extra_length_clock),
+            NR21..=NR24 => self.square2.write(addr - NR20, value, extra_length_clock),
+            NR30..=NR34 => self.wave.write(addr - NR30, value, extra_length_clock),
+            NR41..=NR44 => self.noise.write(addr - NR40, value, extra_length_clock),
+            _ => {}
+        }
+    }
+
+    fn write_power(&mut self, value: u8) {
+        let power = value & POWER != 0;
+        if !power && self.power {
+            // Clears every register but keeps wave RAM and the length counters
+            self.regs = [0; CAPACITY];
+            self.square1 = Square {length: self.square1.length.powered_off(), ..Default::default()};
+            self.square2 = Square {length: self.square2.length.powered_off(), ..Default::default()};
+            self.wave = Wave {length: self.wave.length.powered_off(), ram: self.wave.ram, ..Default::default()};
+            self.noise = Noise {length: self.noise.length.powered_off(), ..Default::default()};
+        } else if power && !self.power {
+            self.frame_step = 0;
+        }
+        self.power = power;
+        self.regs[NR52 - START] = value & POWER;
     }
 
     pub fn read(&self, addr: usize) -> u8 {
-       return self.regs[addr - START]; 
+        match addr {
+            NR52 => {
+                let status = [self.square1.enabled, self.square2.enabled, self.wave.enabled, self.noise.enabled]
+                    .iter()
+                    .enumerate()
+                    .fold(0, |status, (i, &enabled)| status | ((enabled as u8) << i));
+                self.regs[addr - START] | READ_MASKS[addr - START] | status
+            }
+            _ => self.regs[addr - START] | READ_MASKS[addr - START],
+        }
+    }
+
+    /// `cycle` is how many clock cycles into the current instruction the access happens. While
+    /// channel 3 plays, the DMG only reaches wave RAM in the M-cycle the channel reads it and
+    /// then only the byte being read, other reads return 0xFF and other writes are dropped.
+    pub fn write_wave(&mut self, addr: usize, value: u8) {
+        self.log_write(addr, value);
+        if let Some(index) = self.wave_index(addr) {
+            self.wave.ram[index] = value;
+        }
+    }
+
+    pub fn read_wave(&self, addr: usize) -> u8 {
+        self.wave_index(addr).map_or(0xFF, |index| self.wave.ram[index])
+    }
+
+    fn wave_index(&self, addr: usize) -> Option<usize> {
+        if self.wave.enabled {self.wave.byte_read_next()} else {Some(addr - WAVE_START)}
+    }
+
+    /// Advances the channels by the given clock cycles, `div_counter` is the system counter
+    /// at the start of the step and clocks the frame sequencer.
+    pub fn step(&mut self, cycles: u8, div_counter: u16) {
+        self.mixer.log_cycles += cycles as u64;
+        for m_cycle in 0..cycles as u16 / 4 {
+            if !self.power {
+                self.sample();
+                continue;
+            }
+            let counter = div_counter.wrapping_add(m_cycle * 4);
+            if counter & FRAME_SEQUENCER_BIT != 0 && counter.wrapping_add(4) & FRAME_SEQUENCER_BIT == 0 {
+                self.clock_frame_sequencer();
+            }
+            self.square1.step(4);
+            self.square2.step(4);
+            self.wave.step(4);
+            self.noise.step(4);
+            self.sample();
+        }
+    }
+
+    fn sample(&mut self) {
+        if self.mixer.resampler.is_none() && self.mixer.capture.is_none() {
+            return;
+        }
+        let channels = self.channel_outputs();
+        let output = self.mix(channels);
+        self.mixer.push(channels, output);
+    }
+
+    /// Starts resampling the output to `rate` Hz, `None` stops it and drops buffered samples.
+    pub fn set_sample_rate(&mut self, rate: Option<u32>) {
+        self.mixer.resampler = rate.map(Resampler::new);
+    }
+
+    pub fn resampler(&self) -> Option<&Resampler> {
+        self.mixer.resampler.as_ref()
+    }
+
+    pub fn resampler_mut(&mut self) -> Option<&mut Resampler> {
+        self.mixer.resampler.as_mut()
+    }
+
+    /// Number of resampled stereo samples waiting to be read.
+    pub fn samples_available(&self) -> usize {
+        self.resampler().map_or(0, |resampler| resampler.len())
+    }
+
+    /// Moves resampled samples into `buffer` as interleaved left/right pairs in -1.0..=1.0
+    /// and returns the number of values written, 0 while no sample rate is set.
+    pub fn read_samples_f32(&mut self, buffer: &mut [f32]) -> usize {
+        self.resampler_mut().map_or(0, |resampler| resampler.read_f32(buffer))
+    }
+
+    /// Same as `read_samples_f32` for 16 bit samples.
+    pub fn read_samples_i16(&mut self, buffer: &mut [i16]) -> usize {
+        self.resampler_mut().map_or(0, |resampler| resampler.read_i16(buffer))
+    }
+
+    pub fn set_muted(&mut self, channel: Channel, muted: bool) {
+        self.mixer.muted[channel as usize] = muted;
+    }
+
+    pub fn is_muted(&self, channel: Channel) -> bool {
+        self.mixer.muted[channel as usize]
+    }
+
+    /// While any channel is soloed, the channels that aren't stay silent.
+    pub fn set_solo(&mut self, channel: Channel, solo: bool) {
+        self.mixer.soloed[channel as usize] = solo;
+    }
+
+    pub fn is_solo(&self, channel: Channel) -> bool {
+        self.mixer.soloed[channel as usize]
+    }
+
+    /// Starts capturing every channel at `rate` Hz as it leaves its DAC, before panning,
+    /// volume, mute and solo. `None` stops it and drops buffered samples.
+    pub fn set_channel_capture(&mut self, rate: Option<u32>) {
+        self.mixer.capture = rate.map(|rate| Channel::ALL.iter().map(|_| Resampler::unfiltered(rate)).collect());
+    }
+
+    /// The capture of one channel, its samples are mono so read them with `read_mono_*`.
+    pub fn channel_capture_mut(&mut self, channel: Channel) -> Option<&mut Resampler> {
+        self.mixer.capture.as_mut().map(|capture| &mut capture[channel as usize])
+    }
+
+    /// Starts logging every register and wave RAM write. The log opens with writes recreating
+    /// the current state, so playing it back doesn't depend on what happened before.
+    pub fn set_write_log(&mut self, enabled: bool) {
+        if !enabled {
+            self.mixer.write_log = None;
+            return;
+        }
+        self.mixer.log_cycles = 0;
+        self.mixer.write_log = Some(Vec::new());
+        self.log_state();
+    }
+
+    // Logs the writes that bring a powered on controller into the current state
+    fn log_state(&mut self) {
+        self.log_write(NR52, self.regs[NR52 - START]);
+        // Wave RAM before NR30 so channel 3 is still off while it's filled
+        for addr in WAVE_START..WAVE_END {
+            self.log_write(addr, self.wave.ram[addr - WAVE_START]);
+        }
+        for addr in START..NR52 {
+            let value = self.regs[addr - START];
+            // Without retriggering anything
+            let value = if matches!(addr, NR14 | NR24 | NR34 | NR44) {value & !TRIGGER} else {value};
+            self.log_write(addr, value);
+        }
+    }
+
+    /// Drains the writes logged so far.
+    pub fn take_writes(&mut self) -> Vec<RegisterWrite> {
+        self.mixer.write_log.as_mut().map(std::mem::take).unwrap_or_default()
+    }
+
+    /// Clock cycles since the log was started, the timestamp of the next write.
+    pub fn log_cycles(&self) -> u64 {
+        self.mixer.log_cycles
+    }
+
+    fn log_write(&mut self, addr: usize, value: u8) {
+        let cycle = self.mixer.log_cycles;
+        if let Some(log) = &mut self.mixer.write_log {
+            log.push(RegisterWrite {cycle, addr: addr as u16, value});
+        }
+    }
+
+    /// Hands over mute, solo, resampling and capture state, leaving the defaults behind.
+    pub fn take_mixer(&mut self) -> Mixer {
+        std::mem::take(&mut self.mixer)
+    }
+
+    /// A running write log carries on with the state of this controller.
+    pub fn set_mixer(&mut self, mixer: Mixer) {
+        self.mixer = mixer;
+        if self.mixer.write_log.is_some() {
+            self.log_state();
+        }
+    }
+
+    /// DIV was written while the system counter was `old_counter`, the reset is a falling edge
+    /// for the frame sequencer when its bit was set.
+    pub fn div_reset(&mut self, old_counter: u16) {
+        if self.power && old_counter & FRAME_SEQUENCER_BIT != 0 {
+            self.clock_frame_sequencer();
+        }
+    }
+
+    /// Current stereo output after NR51 panning and NR50 volume, both sides in -1.0..=1.0.
+    pub fn output(&self) -> (f32, f32) {
+        self.mix(self.channel_outputs())
+    }
+
+    fn mix(&self, channels: [f32; 4]) -> (f32, f32) {
+        if !self.power {
+            return (0.0, 0.0);
+        }
+        let panning = self.regs[NR51 - START];
+        let (mut left, mut right) = (0.0, 0.0);
+        for (i, output) in channels.iter().enumerate() {
+            if !self.mixer.is_audible(i) {
+                continue;
+            }
+            if panning & (0x10 << i) != 0 {
+                left += output;
+            }
+            if panning & (0x01 << i) != 0 {
+                right += output;
+            }
+        }
+        let volume = self.regs[NR50 - START];
+        let left_volume = ((volume >> 4) & 0b111) as f32 + 1.0;
+        let right_volume = (volume & 0b111) as f32 + 1.0;
+        (left / 4.0 * left_volume / 8.0, right / 4.0 * right_volume / 8.0)
+    }
+
+    // Each channel after its DAC, in -1.0..=1.0
+    fn channel_outputs(&self) -> [f32; 4] {
+        [
+            dac(self.square1.output(), self.square1.envelope.dac_enabled()),
+            dac(self.square2.output(), self.square2.envelope.dac_enabled()),
+            dac(self.wave.output(), self.wave.dac_enabled),
+            dac(self.noise.output(), self.noise.envelope.dac_enabled()),
+        ]
+    }
+
+    // 512 Hz, lengths on even steps, sweep on 2 and 6, envelopes on 7
+    fn clock_frame_sequencer(&mut self) {
+        if self.frame_step.is_multiple_of(2) {
+            self.clock_lengths();
+        }
+        if self.frame_step == 2 || self.frame_step == 6 {
+            self.square1.clock_sweep();
+        }
+        if self.frame_step == 7 {
+            self.square1.envelope.clock();
+            self.square2.envelope.clock();
+            self.noise.envelope.clock();
+        }
+        self.frame_step = (self.frame_step + 1) % 8;
+    }
+
+    fn clock_lengths(&mut self) {
+        if self.square1.length.clock() {
+            self.square1.enabled = false;
+        }
+        if self.square2.length.clock() {
+            self.square2.enabled = false;
+        }
+        if self.wave.length.clock() {
+            self.wave.enabled = false;
+        }
+        if self.noise.length.clock() {
+            self.noise.enabled = false;
+        }
     }
 }
 
 impl Default for SoundController {
     fn default() -> Self {
-        SoundController {
-                regs: [0; CAPACITY],
-        }
+        SoundController::new()
     }
 }
 
+// Digital 0 comes out as 1.0 and 15 as -1.0, a DAC that's off outputs nothing
+fn dac(digital: u8, enabled: bool) -> f32 {
+    if enabled {1.0 - digital as f32 / 7.5} else {0.0}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Steps across a falling edge of the frame sequencer bit
+    fn clock_frame_sequencer(sound: &mut SoundController, times: usize) {
+        for _ in 0..times {
+            sound.step(4, FRAME_SEQUENCER_BIT | 0x0FFC);
+        }
+    }
+
+    #[test]
+    fn square_duty_and_status() {
+        let mut sound = SoundController::new();
+        // channel 1 is left on by the boot ROM
+        assert_eq!(sound.read(NR52), 0xF1);
+        // 50% duty, full volume, the fastest period steps every 4 cycles
+        sound.write(0xFF16, 0b1000_0000);
+        sound.write(0xFF17, 0xF0);
+        sound.write(0xFF18, 0xFF);
+        sound.write(NR24, TRIGGER | 0b111);
+        assert_eq!(sound.read(NR52), 0xF3);
+
+        let mut outputs = Vec::new();
+        for _ in 0..8 {
+            sound.step(4, 0);
+            outputs.push(sound.square2.output());
+        }
+        assert_eq!(outputs, vec![0, 0, 0, 0, 15, 15, 15, 15]);
+
+        // turning the DAC off disables the channel
+        sound.write(0xFF17, 0x00);
+        assert_eq!(sound.read(NR52), 0xF1);
+    }
+
+    #[test]
+    fn length_counter() {
+        let mut sound = SoundController::new();
+        sound.write(0xFF21, 0xF0);
+        sound.write(0xFF20, 62);
+        sound.write(NR44, TRIGGER | LENGTH_ENABLE);
+        assert_eq!(sound.read(NR52), 0xF9);
+        // lengths are clocked on every other step
+        clock_frame_sequencer(&mut sound, 2);
+        assert_eq!(sound.read(NR52), 0xF9);
+        clock_frame_sequencer(&mut sound, 1);
+        assert_eq!(sound.read(NR52), 0xF1);
+
+        // retriggering with an expired length starts over from the maximum
+        clock_frame_sequencer(&mut sound, 1);
+        sound.write(NR44, TRIGGER | LENGTH_ENABLE);
+        assert_eq!(sound.noise.length.counter, 64);
+    }
+
+    #[test]
+    fn envelope_and_div_reset() {
+        let mut sound = SoundController::new();
+        sound.write(0xFF12, 0x19);
+        sound.write(NR14, TRIGGER);
+        assert_eq!(sound.square1.envelope.volume, 1);
+        clock_frame_sequencer(&mut sound, 7);
+        assert_eq!(sound.square1.envelope.volume, 1);
+        // resetting DIV with the frame sequencer bit high clocks it as well
+        sound.div_reset(FRAME_SEQUENCER_BIT);
+        assert_eq!(sound.square1.envelope.volume, 2);
+        sound.div_reset(0x0FFF);
+        clock_frame_sequencer(&mut sound, 8);
+        assert_eq!(sound.square1.envelope.volume, 3);
+    }
+
+    #[test]
+    fn sweep() {
+        let mut sound = SoundController::new();
+        sound.write(0xFF12, 0xF0);
+        // pace 1, increase by period/2
+        sound.write(NR10, 0x11);
+        sound.write(0xFF13, 0x00);
+        sound.write(NR14, TRIGGER | 0x02);
+        clock_frame_sequencer(&mut sound, 3);
+        assert_eq!(sound.square1.period, 0x300);
+        assert_eq!(sound.read(NR52) & 1, 1);
+        // 0x480, then 0x6C0 which would overflow on the next calculation
+        clock_frame_sequencer(&mut sound, 4);
+        assert_eq!(sound.square1.period, 0x480);
+        assert_eq!(sound.read(NR52) & 1, 1);
+        clock_frame_sequencer(&mut sound, 4);
+        assert_eq!(sound.square1.period, 0x6C0);
+        assert_eq!(sound.read(NR52) & 1, 0);
+
+        // a trigger that would overflow right away never starts the channel
+        sound.write(0xFF13, 0xFF);
+        sound.write(NR14, TRIGGER | 0x07);
+        assert_eq!(sound.read(NR52) & 1, 0);
+    }
+
+    #[test]
+    fn noise_lfsr() {
+        let mut sound = SoundController::new();
+        sound.write(0xFF21, 0xF0);
+        // 7 bit mode, divisor 8 without shift
+        sound.write(0xFF22, 0b1000);
+        sound.write(NR44, TRIGGER);
+        sound.step(8, 0);
+        assert_eq!(sound.noise.lfsr, 0x3FBF);
+        assert_eq!(sound.noise.output(), 0);
+        // a short LFSR repeats every 127 clocks
+        for _ in 0..127 {
+            sound.step(8, 0);
+        }
+        assert_eq!(sound.noise.lfsr & 0x7F, 0x3F);
+    }
+
+    #[test]
+    fn wave_and_mixing() {
+        let mut sound = SoundController::new();
+        for addr in WAVE_START..WAVE_END {
+            sound.write_wave(addr, 0xFF);
+        }
+        sound.write(NR30, 0x80);
+        sound.write(0xFF1C, 0b0010_0000);
+        sound.write(0xFF1D, 0xFF);
+        sound.write(NR34, TRIGGER | 0b111);
+        // the first sample plays after the trigger delay
+        sound.step(4, 0);
+        assert_eq!(sound.wave.output(), 0);
+        sound.step(4, 0);
+        assert_eq!(sound.wave.output(), 15);
+        sound.write(0xFF1C, 0b0110_0000);
+        assert_eq!(sound.wave.output(), 3);
+
+        // only channel 3 on the left at full volume, nothing on the right
+        sound.write(0xFF1C, 0b0010_0000);
+        sound.write(NR51, 0x40);
+        sound.write(NR50, 0x70);
+        assert_eq!(sound.output(), (-0.25, 0.0));
+        sound.write(NR50, 0x30);
+        assert_eq!(sound.output(), (-0.125, 0.0));
+
+        sound.write(NR52, 0);
+        assert_eq!(sound.output(), (0.0, 0.0));
+    }
+
+    #[test]
+    fn wave_ram_while_on() {
+        let mut sound = SoundController::new();
+        for (i, addr) in (WAVE_START..WAVE_END).enumerate() {
+            sound.write_wave(addr, 0x10 + i as u8);
+        }
+        // a byte every 16 cycles, the first one after the trigger delay
+        sound.write(NR30, 0x80);
+        sound.write(0xFF1D, 0xF8);
+        sound.write(NR34, TRIGGER | 0b111);
+        assert_eq!(sound.read_wave(WAVE_START + 5), 0xFF);
+
+        // accesses see the byte the channel reads in the same M-cycle, whatever their address
+        sound.step(20, 0);
+        assert_eq!(sound.read_wave(WAVE_START + 5), 0x10);
+        sound.step(4, 0);
+        assert_eq!(sound.read_wave(WAVE_START), 0xFF);
+        // writes only land on the byte being read, and only while it's read
+        sound.write_wave(WAVE_START + 9, 0xCD);
+        sound.step(8, 0);
+        assert_eq!(sound.read_wave(WAVE_START), 0xFF);
+        sound.step(4, 0);
+        assert_eq!(sound.read_wave(WAVE_START), 0x11);
+        sound.write_wave(WAVE_START + 9, 0xAB);
+        assert_eq!(sound.wave.ram[..3], [0x10, 0xAB, 0x12]);
+        assert_eq!(sound.wave.ram[9], 0x19);
+
+        sound.write(NR30, 0);
+        assert_eq!(sound.read_wave(WAVE_START + 1), 0xAB);
+    }
+
+    #[test]
+    fn read_masks_and_power_off() {
+        let mut sound = SoundController::new();
+        sound.write(NR10, 0);
+        assert_eq!(sound.read(NR10), 0x80);
+        sound.write(0xFF1C, 0xFF);
+        assert_eq!(sound.read(0xFF1C), 0xFF);
+        sound.write(0xFF13, 0x12);
+        assert_eq!(sound.read(0xFF13), 0xFF);
+
+        sound.write_wave(WAVE_START, 0x42);
+        sound.write(0xFF20, 60);
+        sound.write(NR52, 0);
+        assert_eq!(sound.read(NR52), 0x70);
+        assert_eq!(sound.read(NR50), 0x00);
+        assert_eq!(sound.read(0xFF11), 0x3F);
+        // writes are ignored while off, except for the length counters
+        sound.write(NR50, 0x77);
+        assert_eq!(sound.read(NR50), 0x00);
+        assert_eq!(sound.noise.length.counter, 4);
+        sound.write(0xFF20, 62);
+        assert_eq!(sound.noise.length.counter, 2);
+        assert_eq!(sound.read_wave(WAVE_START), 0x42);
+
+        sound.write(NR52, POWER);
+        sound.write(NR50, 0x77);
+        assert_eq!(sound.read(NR50), 0x77);
+    }
+
+    #[test]
+    fn zombie_envelope() {
+        let mut sound = SoundController::new();
+        // volume 8 decreasing with pace 0, so it never changes on its own
+        sound.write(0xFF17, 0x80);
+        sound.write(NR24, TRIGGER);
+        // pace was 0 while still updating: +1
+        sound.write(0xFF17, 0x80);
+        assert_eq!(sound.square2.envelope.volume, 9);
+        // from decrease to increase: +1 then 16 - volume
+        sound.write(0xFF17, 0x88);
+        assert_eq!(sound.square2.envelope.volume, 6);
+        // a disabled channel isn't affected
+        sound.write(0xFF17, 0x00);
+        assert_eq!(sound.square2.envelope.volume, 9);
+        sound.write(0xFF17, 0x80);
+        assert_eq!(sound.square2.envelope.volume, 9);
+    }
+
+    #[test]
+    fn length_extra_clock() {
+        let mut sound = SoundController::new();
+        sound.write(0xFF21, 0xF0);
+        sound.write(0xFF20, 62);
+        sound.write(NR44, TRIGGER);
+        // the next step doesn't clock lengths, enabling the counter clocks it once
+        clock_frame_sequencer(&mut sound, 1);
+        sound.write(NR44, LENGTH_ENABLE);
+        assert_eq!(sound.noise.length.counter, 1);
+        assert_eq!(sound.read(NR52) & 0b1000, 0b1000);
+        sound.write(NR44, 0);
+        sound.write(NR44, LENGTH_ENABLE);
+        assert_eq!(sound.read(NR52) & 0b1000, 0);
+
+        // triggering with an empty counter loads one less than the maximum
+        sound.write(NR44, TRIGGER | LENGTH_ENABLE);
+        assert_eq!(sound.noise.length.counter, 63);
+        // no extra clock when the next step clocks lengths anyway
+        clock_frame_sequencer(&mut sound, 1);
+        sound.write(NR44, 0);
+        sound.write(NR44, LENGTH_ENABLE);
+        assert_eq!(sound.noise.length.counter, 63);
+    }
+
+    #[test]
+    fn sweep_leaving_decrease_mode() {
+        let mut sound = SoundController::new();
+        sound.write(0xFF12, 0xF0);
+        sound.write(NR10, 0x19);
+        sound.write(NR14, TRIGGER | 0x04);
+        assert_eq!(sound.read(NR52) & 1, 1);
+        sound.write(NR10, 0x11);
+        assert_eq!(sound.read(NR52) & 1, 0);
+    }
+
+    #[test]
+    fn wave_retrigger_corruption() {
+        let mut sound = SoundController::new();
+        for (i, addr) in (WAVE_START..WAVE_END).enumerate() {
+            sound.write_wave(addr, i as u8);
+        }
+        sound.write(NR30, 0x80);
+        // 4 cycles per sample, one more read lands on byte 5
+        sound.write(0xFF1D, 0xFE);
+        sound.write(NR34, TRIGGER | 0b111);
+        sound.step(8 + 4 * 9, 0);
+        assert_eq!(sound.wave.position, 9);
+        sound.write(NR34, TRIGGER | 0b111);
+        assert_eq!(sound.wave.ram[..5], [4, 5, 6, 7, 4]);
+    }
+
+    #[test]
+    fn mute_solo_and_capture() {
+        let mut sound = SoundController::new();
+        sound.set_channel_capture(Some(1024));
+        // channel 1 is on both sides, channel 3 only on the left
+        sound.write(0xFF12, 0xF0);
+        sound.write(NR14, TRIGGER);
+        for addr in WAVE_START..WAVE_END {
+            sound.write_wave(addr, 0xFF);
+        }
+        sound.write(NR30, 0x80);
+        sound.write(0xFF1C, 0b0010_0000);
+        sound.write(0xFF1D, 0xFF);
+        sound.write(NR34, TRIGGER | 0b111);
+        sound.write(NR51, 0x51);
+        sound.write(NR50, 0x77);
+        sound.step(4 * 10, 0);
+        let (channel1, channel3) = (sound.channel_outputs()[0], sound.channel_outputs()[2]);
+
+        assert_eq!(sound.output(), ((channel1 + channel3) / 4.0, channel1 / 4.0));
+        sound.set_muted(Channel::Square1, true);
+        assert_eq!(sound.output(), (channel3 / 4.0, 0.0));
+        // solo wins over everything not soloed, mute still applies
+        sound.set_muted(Channel::Square1, false);
+        sound.set_solo(Channel::Square1, true);
+        assert_eq!(sound.output(), (channel1 / 4.0, channel1 / 4.0));
+        sound.set_muted(Channel::Square1, true);
+        assert_eq!(sound.output(), (0.0, 0.0));
+        assert!(sound.is_muted(Channel::Square1) && sound.is_solo(Channel::Square1));
+
+        // captures are taken before mute and solo, channel 2's DAC is off
+        for _ in 0..2048 {
+            sound.step(4, 0);
+        }
+        let mut buffer = [0.0; 2];
+        assert_eq!(sound.channel_capture_mut(Channel::Wave).unwrap().read_mono_f32(&mut buffer), 2);
+        assert_eq!(buffer[1], channel3);
+        sound.channel_capture_mut(Channel::Square2).unwrap().read_mono_f32(&mut buffer);
+        assert_eq!(buffer, [0.0, 0.0]);
+
+        // the mixer moves along with its settings
+        let mixer = sound.take_mixer();
+        assert!(!sound.is_muted(Channel::Square1));
+        sound.set_mixer(mixer);
+        assert!(sound.is_muted(Channel::Square1));
+    }
+
+    #[test]
+    fn write_log() {
+        let mut sound = SoundController::new();
+        sound.write(NR50, 0x11);
+        sound.write(NR24, TRIGGER | 0x42);
+        assert!(sound.take_writes().is_empty());
+
+        sound.set_write_log(true);
+        let writes = sound.take_writes();
+        assert_eq!(writes.len(), 1 + WAVE_CAPACITY + CAPACITY - 1);
+        assert_eq!(writes[0], RegisterWrite {cycle: 0, addr: NR52 as u16, value: POWER});
+        assert_eq!(writes[1].addr, WAVE_START as u16);
+        assert!(writes.contains(&RegisterWrite {cycle: 0, addr: NR50 as u16, value: 0x11}));
+        assert!(writes.contains(&RegisterWrite {cycle: 0, addr: NR24 as u16, value: 0x42}));
+
+        sound.step(8, 0);
+        sound.write_wave(WAVE_START + 1, 0x12);
+        sound.step(4, 0);
+        // writes ignored while powered off still get logged
+        sound.write(NR52, 0);
+        sound.write(NR50, 0x77);
+        assert_eq!(sound.take_writes(), vec![
+            RegisterWrite {cycle: 8, addr: WAVE_START as u16 + 1, value: 0x12},
+            RegisterWrite {cycle: 12, addr: NR52 as u16, value: 0},
+            RegisterWrite {cycle: 12, addr: NR50 as u16, value: 0x77},
+        ]);
+        assert_eq!(sound.log_cycles(), 12);
+        sound.set_write_log(false);
+        sound.write(NR52, POWER);
+        assert!(sound.take_writes().is_empty());
+    }
+
+    #[test]
+    fn write_log_moves_with_the_mixer() {
+        let mut sound = SoundController::new();
+        sound.set_write_log(true);
+        sound.write(NR50, 0x11);
+        sound.step(8, 0);
+        sound.take_writes();
+
+        let mut reset = SoundController::new();
+        reset.set_mixer(sound.take_mixer());
+        let writes = reset.take_writes();
+        assert_eq!(writes.len(), 1 + WAVE_CAPACITY + CAPACITY - 1);
+        assert_eq!(writes[0], RegisterWrite {cycle: 8, addr: NR52 as u16, value: POWER});
+        assert!(writes.contains(&RegisterWrite {cycle: 8, addr: NR50 as u16, value: 0x77}));
+    }
+}
@@ -0,0 +1,233 @@
+pub const DIV: u16 = 0xFF04;
+pub const TIMA: u16 = 0xFF05;
+pub const TMA: u16 = 0xFF06;
+pub const TAC: u16 = 0xFF07;
+
+pub const INT_TIMER: u8 = 0b0000_0100;
+
+const TAC_ENABLE: u8 = 0b100;
+// Unused bits read as 1
+const TAC_UNUSED: u8 = 0b1111_1000;
+
+// System counter left behind by the DMG boot ROM
+const POST_BOOT_COUNTER: u16 = 0xABCC;
+
+/// DIV and TIMA, both driven by a 16 bit system counter ticking every clock cycle.
+/// DIV is its upper byte and TIMA counts the falling edges of the bit TAC selects.
+#[derive(Debug)]
+pub struct Timer {
+    counter: u16,
+    tima: u8,
+    tma: u8,
+    tac: u8,
+    // TIMA overflowed and reads 0, TMA gets loaded and the interrupt raised on the next M-cycle
+    overflow: bool,
+    // M-cycle in which TMA got loaded, writes to TIMA are lost and writes to TMA go through
+    reloading: bool,
+    interrupt: bool,
+}
+
+impl Timer {
+    pub fn new() -> Timer {
+        Timer {
+            counter: POST_BOOT_COUNTER,
+            tima: 0,
+            tma: 0,
+            tac: 0,
+            overflow: false,
+            reloading: false,
+            interrupt: false,
+        }
+    }
+
+    pub fn read(&self, addr: u16) -> u8 {
+        match addr {
+            DIV => (self.counter >> 8) as u8,
+            TIMA => self.tima,
+            TMA => self.tma,
+            TAC => self.tac | TAC_UNUSED,
+            _ => 0xFF,
+        }
+    }
+
+    pub fn write(&mut self, addr: u16, value: u8) {
+        match addr {
+            DIV => {
+                // Resetting the counter can produce a falling edge on its own
+                let signal = self.signal();
+                self.counter = 0;
+                self.detect_falling_edge(signal);
+            }
+            // Lost while TMA is being loaded
+            TIMA if !self.reloading => {
+                self.tima = value;
+                // Writing during the overflow cycle cancels the reload and the interrupt
+                self.overflow = false;
+            }
+            TMA => {
+                self.tma = value;
+                if self.reloading {
+                    self.tima = value;
+                }
+            }
+            TAC => {
+                // So can disabling the timer or switching to a bit that is low
+                let signal = self.signal();
+                self.tac = value & !TAC_UNUSED;
+                self.detect_falling_edge(signal);
+            }
+            _ => {}
+        }
+    }
+
+    /// The whole system counter, DIV being its upper byte.
+    pub fn counter(&self) -> u16 {
+        self.counter
+    }
+
+    /// Advances by the given clock cycles and returns the timer interrupt in the IF layout.
+    pub fn step(&mut self, cycles: u8) -> u8 {
+        for _ in 0..cycles / 4 {
+            self.tick();
+        }
+        if std::mem::take(&mut self.interrupt) {INT_TIMER} else {0}
+    }
+
+    // One M-cycle
+    fn tick(&mut self) {
+        self.reloading = false;
+        if self.overflow {
+            self.overflow = false;
+            self.tima = self.tma;
+            self.interrupt = true;
+            self.reloading = true;
+        }
+        let signal = self.signal();
+        self.counter = self.counter.wrapping_add(4);
+        self.detect_falling_edge(signal);
+    }
+
+    // Counter bit selected by TAC AND-ed with the enable bit
+    fn signal(&self) -> bool {
+        let bit = match self.tac & 0b11 {
+            0b00 => 9,
+            0b01 => 3,
+            0b10 => 5,
+            _ => 7,
+        };
+        self.tac & TAC_ENABLE != 0 && self.counter & (1 << bit) != 0
+    }
+
+    fn detect_falling_edge(&mut self, old_signal: bool) {
+        if !old_signal || self.signal() {
+            return;
+        }
+        let (tima, overflow) = self.tima.overflowing_add(1);
+        self.tima = tima;
+        if overflow {
+            self.overflow = true;
+        }
+    }
+}
+
+impl Default for Timer {
+    fn default() -> Self {
+        Timer::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn enabled_timer(tac: u8) -> Timer {
+        let mut timer = Timer::new();
+        timer.write(DIV, 0);
+        timer.write(TAC, tac);
+        timer
+    }
+
+    #[test]
+    fn div_is_the_upper_counter_byte() {
+        let mut timer = Timer::new();
+        assert_eq!(timer.read(DIV), 0xAB);
+        timer.write(DIV, 0x42);
+        assert_eq!(timer.read(DIV), 0);
+        timer.step(252);
+        assert_eq!(timer.read(DIV), 0);
+        timer.step(4);
+        assert_eq!(timer.read(DIV), 1);
+        assert_eq!(timer.read(TAC), 0xF8);
+    }
+
+    #[test]
+    fn tima_frequencies() {
+        for (tac, period) in [(0b100, 1024), (0b101, 16), (0b110, 64), (0b111, 256)] {
+            let mut timer = enabled_timer(tac);
+            for _ in 0..period / 4 - 1 {
+                timer.step(4);
+            }
+            assert_eq!(timer.read(TIMA), 0, "TAC {:#b}", tac);
+            timer.step(4);
+            assert_eq!(timer.read(TIMA), 1, "TAC {:#b}", tac);
+        }
+    }
+
+    #[test]
+    fn delayed_reload() {
+        let mut timer = enabled_timer(0b101);
+        timer.write(TMA, 0x42);
+        timer.write(TIMA, 0xFF);
+        timer.step(16);
+        // TIMA reads 0 for one M-cycle before TMA gets loaded
+        assert_eq!(timer.read(TIMA), 0);
+        assert_eq!(timer.step(4), INT_TIMER);
+        assert_eq!(timer.read(TIMA), 0x42);
+    }
+
+    #[test]
+    fn writes_around_the_reload() {
+        // writing TIMA during the overflow cycle cancels the reload
+        let mut timer = enabled_timer(0b101);
+        timer.write(TMA, 0x42);
+        timer.write(TIMA, 0xFF);
+        timer.step(16);
+        timer.write(TIMA, 0x10);
+        assert_eq!(timer.step(4), 0);
+        assert_eq!(timer.read(TIMA), 0x10);
+
+        // during the reload cycle it's ignored, while TMA writes go through to TIMA
+        let mut timer = enabled_timer(0b101);
+        timer.write(TIMA, 0xFF);
+        timer.step(20);
+        timer.write(TIMA, 0x10);
+        assert_eq!(timer.read(TIMA), 0x00);
+        timer.write(TMA, 0x24);
+        assert_eq!(timer.read(TIMA), 0x24);
+        timer.step(4);
+        timer.write(TIMA, 0x10);
+        assert_eq!(timer.read(TIMA), 0x10);
+    }
+
+    #[test]
+    fn div_and_tac_write_glitches() {
+        // resetting DIV while the selected bit is high counts as a falling edge
+        let mut timer = enabled_timer(0b101);
+        timer.step(8);
+        timer.write(DIV, 0);
+        assert_eq!(timer.read(TIMA), 1);
+        // with the bit low nothing happens
+        timer.step(4);
+        timer.write(DIV, 0);
+        assert_eq!(timer.read(TIMA), 1);
+
+        // disabling the timer while the selected bit is high does too
+        timer.step(8);
+        timer.write(TAC, 0b001);
+        assert_eq!(timer.read(TIMA), 2);
+        // and so does switching from a high to a low bit
+        timer.write(TAC, 0b101);
+        timer.write(TAC, 0b110);
+        assert_eq!(timer.read(TIMA), 3);
+    }
+}
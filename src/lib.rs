@@ -0,0 +1,20 @@
+extern crate bitvec;
+
+pub mod cpu;
+pub mod ram;
+pub mod bus;
+pub mod dma;
+pub mod io;
+pub mod cartridge;
+pub mod save;
+pub mod link;
+pub mod audio;
+pub mod vgm;
+pub mod gbs;
+pub mod error;
+mod emulator;
+
+pub use bus::Model;
+pub use error::{BusError, EmuError, Strictness};
+pub use emulator::{Config, Emulator, RumbleCallback, CYCLES_PER_FRAME, CYCLES_PER_SECOND};
+pub use io::joypad::Button;
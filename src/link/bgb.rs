@@ -0,0 +1,350 @@
+//! Link cable speaking the BGB 1.4 protocol, to connect with BGB and compatible emulators.
+//!
+//! Every packet is 8 bytes: a command, three data bytes and a little endian timestamp
+//! counting 2 MiHz ticks of the sender's emulated time.
+
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::thread;
+use std::time::Duration;
+
+use super::super::io::serial::SerialDevice;
+
+const CMD_VERSION: u8 = 1;
+const CMD_SYNC1: u8 = 104;
+const CMD_SYNC2: u8 = 105;
+const CMD_SYNC3: u8 = 106;
+const CMD_STATUS: u8 = 108;
+const CMD_WANT_DISCONNECT: u8 = 109;
+
+const VERSION: [u8; 3] = [1, 4, 0];
+
+// sync1 control byte: transfer running on the internal clock
+const CONTROL_MASTER: u8 = 0x81;
+const CONTROL_SLAVE: u8 = 0x80;
+// sync3 b2: 0 is a plain timestamp, 1 acknowledges a sync1 without an armed transfer
+const SYNC3_TIMESTAMP: u8 = 0;
+const SYNC3_PASSIVE: u8 = 1;
+const STATUS_RUNNING: u8 = 0b001;
+
+const TIMESTAMP_MASK: u32 = 0x7FFF_FFFF;
+// Timestamps tick at 2 MiHz, half the DMG clock
+const CYCLES_PER_TICK: u32 = 2;
+// How often the emulated time is sent over, about every 4 ms
+const SYNC_INTERVAL: u32 = 8192;
+// How far this side may run ahead of the other one, a frame's worth
+const MAX_LEAD: u32 = 70224 / CYCLES_PER_TICK;
+// Ticks the other side's clock runs for one byte, 8 bits at 512 clock cycles each
+const TRANSFER_TICKS: u32 = 8 * 512 / CYCLES_PER_TICK;
+
+const REPLY_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Packet {
+    command: u8,
+    b2: u8,
+    b3: u8,
+    b4: u8,
+    timestamp: u32,
+}
+
+impl Packet {
+    fn new(command: u8, b2: u8, b3: u8, b4: u8, timestamp: u32) -> Packet {
+        Packet {command, b2, b3, b4, timestamp}
+    }
+
+    fn to_bytes(self) -> [u8; 8] {
+        let t = self.timestamp.to_le_bytes();
+        [self.command, self.b2, self.b3, self.b4, t[0], t[1], t[2], t[3]]
+    }
+
+    fn from_bytes(bytes: [u8; 8]) -> Packet {
+        let timestamp = u32::from_le_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]);
+        Packet::new(bytes[0], bytes[1], bytes[2], bytes[3], timestamp)
+    }
+}
+
+/// Whether timestamp `a` is at or after `b`, with both wrapping at 31 bits.
+fn is_at_or_after(a: u32, b: u32) -> bool {
+    a.wrapping_sub(b) & TIMESTAMP_MASK < 0x4000_0000
+}
+
+pub struct BgbLink {
+    writer: Box<dyn Write + Send>,
+    packets: Receiver<Packet>,
+    // Emulated time of this side in 2 MiHz ticks, plus the clock cycles not making up a tick yet
+    time: u32,
+    cycles: u32,
+    last_sync: u32,
+    // Latest timestamp of the other side, None until it sent one
+    remote_time: Option<u32>,
+    // Transfer the other side started and its timestamp, answered once this side catches up
+    pending: Option<(u8, u32)>,
+    connected: bool,
+}
+
+impl fmt::Debug for BgbLink {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("BgbLink")
+            .field("time", &self.time)
+            .field("remote_time", &self.remote_time)
+            .field("pending", &self.pending)
+            .field("connected", &self.connected)
+            .finish_non_exhaustive()
+    }
+}
+
+impl BgbLink {
+    /// Starts the session over an established connection, BGB uses TCP.
+    pub fn new(stream: TcpStream) -> io::Result<BgbLink> {
+        stream.set_nodelay(true)?;
+        let mut reader = stream.try_clone()?;
+        let (sender, packets) = mpsc::channel();
+        thread::spawn(move || {
+            let mut buf = [0; 8];
+            while reader.read_exact(&mut buf).is_ok() {
+                if sender.send(Packet::from_bytes(buf)).is_err() {
+                    break;
+                }
+            }
+        });
+        let mut link = BgbLink {
+            writer: Box::new(stream),
+            packets,
+            time: 0,
+            cycles: 0,
+            last_sync: 0,
+            remote_time: None,
+            pending: None,
+            connected: true,
+        };
+        link.send(Packet::new(CMD_VERSION, VERSION[0], VERSION[1], VERSION[2], 0));
+        link.send(Packet::new(CMD_STATUS, STATUS_RUNNING, 0, 0, 0));
+        Ok(link)
+    }
+
+    /// Waits for BGB (or another instance) to connect, like BGB's "Listen".
+    pub fn listen(address: &str) -> io::Result<BgbLink> {
+        let (stream, _) = TcpListener::bind(address)?.accept()?;
+        BgbLink::new(stream)
+    }
+
+    /// Connects to a listening BGB, like BGB's "Connect".
+    pub fn connect(address: &str) -> io::Result<BgbLink> {
+        BgbLink::new(TcpStream::connect(address)?)
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.connected
+    }
+
+    fn send(&mut self, packet: Packet) {
+        if self.writer.write_all(&packet.to_bytes()).and_then(|_| self.writer.flush()).is_err() {
+            self.connected = false;
+        }
+    }
+
+    fn send_sync3(&mut self, b2: u8) {
+        self.last_sync = self.time;
+        self.send(Packet::new(CMD_SYNC3, b2, 0, 0, self.time));
+    }
+
+    fn advance(&mut self, cycles: u32) {
+        self.cycles += cycles;
+        self.time = (self.time + self.cycles / CYCLES_PER_TICK) & TIMESTAMP_MASK;
+        self.cycles %= CYCLES_PER_TICK;
+        if self.time.wrapping_sub(self.last_sync) & TIMESTAMP_MASK >= SYNC_INTERVAL {
+            self.send_sync3(SYNC3_TIMESTAMP);
+        }
+    }
+
+    fn update_remote_time(&mut self, timestamp: u32) {
+        // Whoever started later catches up with the other side's clock
+        if self.remote_time.is_none() && !is_at_or_after(self.time, timestamp) {
+            self.time = timestamp;
+            self.last_sync = timestamp;
+        }
+        self.remote_time = Some(timestamp);
+    }
+
+    /// Handles everything but the replies to a sync1 of this side.
+    fn handle(&mut self, packet: Packet) {
+        match packet.command {
+            CMD_VERSION if [packet.b2, packet.b3, packet.b4] != VERSION => {
+                self.send(Packet::new(CMD_WANT_DISCONNECT, 0, 0, 0, 0));
+                self.connected = false;
+            }
+            CMD_SYNC1 => {
+                self.update_remote_time(packet.timestamp);
+                self.pending = Some((packet.b2, packet.timestamp));
+            }
+            CMD_SYNC3 if packet.b2 == SYNC3_TIMESTAMP => self.update_remote_time(packet.timestamp),
+            CMD_WANT_DISCONNECT => self.connected = false,
+            // Joypad and status updates of the other side don't affect this one,
+            // neither do stray replies to transfers that already timed out
+            _ => {}
+        }
+    }
+
+    /// Answers a transfer of the other side as soon as this side is armed. A transfer armed
+    /// after the timestamp still takes part while the other side's clock shifts the byte out,
+    /// so both sides starting at the same moment doesn't depend on who polls first.
+    fn answer_pending(&mut self, byte: u8, ready: bool) -> Option<u8> {
+        let (data, timestamp) = self.pending?;
+        if ready {
+            self.pending = None;
+            self.send(Packet::new(CMD_SYNC2, byte, CONTROL_SLAVE, 0, 0));
+            return Some(data);
+        }
+        if is_at_or_after(self.time, (timestamp + TRANSFER_TICKS) & TIMESTAMP_MASK) {
+            self.pending = None;
+            self.send_sync3(SYNC3_PASSIVE);
+        }
+        None
+    }
+
+    fn is_too_far_ahead(&self) -> bool {
+        match self.remote_time {
+            Some(remote) => self.time.wrapping_sub(remote) & TIMESTAMP_MASK > MAX_LEAD
+                && is_at_or_after(self.time, remote),
+            None => false,
+        }
+    }
+}
+
+impl SerialDevice for BgbLink {
+    fn transfer(&mut self, byte: u8) -> u8 {
+        if self.pending.take().is_some() {
+            self.send_sync3(SYNC3_PASSIVE);
+        }
+        self.send(Packet::new(CMD_SYNC1, byte, CONTROL_MASTER, 0, self.time));
+        while self.connected {
+            match self.packets.recv_timeout(REPLY_TIMEOUT) {
+                Ok(packet) if packet.command == CMD_SYNC2 => return packet.b2,
+                // The other side had no transfer armed
+                Ok(packet) if packet.command == CMD_SYNC3 && packet.b2 == SYNC3_PASSIVE => return 0xFF,
+                // Both sides drive the clock, neither one gets anything
+                Ok(packet) if packet.command == CMD_SYNC1 => {
+                    self.update_remote_time(packet.timestamp);
+                    self.send_sync3(SYNC3_PASSIVE);
+                }
+                Ok(packet) => self.handle(packet),
+                Err(RecvTimeoutError::Timeout) => return 0xFF,
+                Err(RecvTimeoutError::Disconnected) => self.connected = false,
+            }
+        }
+        0xFF
+    }
+
+    fn poll(&mut self, cycles: u32, byte: u8, ready: bool) -> Option<u8> {
+        if !self.connected {
+            return None;
+        }
+        self.advance(cycles);
+        while let Ok(packet) = self.packets.try_recv() {
+            self.handle(packet);
+        }
+        // Don't run away from the other side, wait for it to report its time
+        while self.connected && self.pending.is_none() && self.is_too_far_ahead() {
+            match self.packets.recv_timeout(REPLY_TIMEOUT) {
+                Ok(packet) => self.handle(packet),
+                Err(RecvTimeoutError::Timeout) => self.remote_time = None,
+                Err(RecvTimeoutError::Disconnected) => self.connected = false,
+            }
+        }
+        self.answer_pending(byte, ready)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Connected pair of a BgbLink and the raw socket of the other side.
+    fn connect() -> (BgbLink, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let remote = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let link = BgbLink::new(listener.accept().unwrap().0).unwrap();
+        (link, remote)
+    }
+
+    fn receive(remote: &mut TcpStream) -> Packet {
+        let mut buf = [0; 8];
+        remote.read_exact(&mut buf).unwrap();
+        Packet::from_bytes(buf)
+    }
+
+    fn send(remote: &mut TcpStream, packet: Packet) {
+        remote.write_all(&packet.to_bytes()).unwrap();
+    }
+
+    #[test]
+    fn handshake() {
+        let (_link, mut remote) = connect();
+        assert_eq!(receive(&mut remote), Packet::new(CMD_VERSION, 1, 4, 0, 0));
+        assert_eq!(receive(&mut remote), Packet::new(CMD_STATUS, STATUS_RUNNING, 0, 0, 0));
+    }
+
+    #[test]
+    fn master_transfer() {
+        let (mut link, mut remote) = connect();
+        link.poll(2000, 0, false);
+        let peer = thread::spawn(move || {
+            receive(&mut remote);
+            receive(&mut remote);
+            let sync1 = receive(&mut remote);
+            send(&mut remote, Packet::new(CMD_SYNC2, 0x24, CONTROL_SLAVE, 0, 0));
+            sync1
+        });
+        assert_eq!(link.transfer(0x42), 0x24);
+        assert_eq!(peer.join().unwrap(), Packet::new(CMD_SYNC1, 0x42, CONTROL_MASTER, 0, 1000));
+    }
+
+    #[test]
+    fn slave_transfer_waits_for_timestamp() {
+        let (mut link, mut remote) = connect();
+        receive(&mut remote);
+        receive(&mut remote);
+        send(&mut remote, Packet::new(CMD_SYNC1, 0x42, CONTROL_MASTER, 0, 100));
+        let data = loop {
+            if let Some(data) = link.poll(0, 0x24, true) {
+                break data;
+            }
+        };
+        assert_eq!(data, 0x42);
+        assert_eq!(receive(&mut remote), Packet::new(CMD_SYNC2, 0x24, CONTROL_SLAVE, 0, 0));
+        // adopted the clock of the other side, which was ahead
+        assert_eq!(link.time, 100);
+
+        // arming after the timestamp still makes it while the other side shifts the byte out
+        send(&mut remote, Packet::new(CMD_SYNC1, 0x43, CONTROL_MASTER, 0, 110));
+        while link.pending.is_none() {
+            assert_eq!(link.poll(0, 0x24, false), None);
+        }
+        assert_eq!(link.poll(32, 0x24, false), None);
+        assert_eq!(link.poll(4, 0x25, true), Some(0x43));
+        assert_eq!(receive(&mut remote), Packet::new(CMD_SYNC2, 0x25, CONTROL_SLAVE, 0, 0));
+
+        // without an armed transfer it's acknowledged once the whole byte went by
+        send(&mut remote, Packet::new(CMD_SYNC1, 0x44, CONTROL_MASTER, 0, 120));
+        while link.pending.is_none() {
+            assert_eq!(link.poll(0, 0x24, false), None);
+        }
+        assert_eq!(link.poll(2 * TRANSFER_TICKS, 0x24, false), None);
+        assert!(link.pending.is_some());
+        assert_eq!(link.poll(4, 0x24, false), None);
+        assert_eq!(receive(&mut remote), Packet::new(CMD_SYNC3, SYNC3_PASSIVE, 0, 0, 120 + TRANSFER_TICKS));
+    }
+
+    #[test]
+    fn version_mismatch_disconnects() {
+        let (mut link, mut remote) = connect();
+        send(&mut remote, Packet::new(CMD_VERSION, 1, 3, 0, 0));
+        while link.is_connected() {
+            link.poll(4, 0, false);
+        }
+        assert_eq!(link.transfer(0x42), 0xFF);
+    }
+}
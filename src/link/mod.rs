@@ -0,0 +1,207 @@
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, TryRecvError};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::io::serial::SerialDevice;
+
+pub mod bgb;
+
+// Every message is a command byte, the sequence number of the transfer and the data byte.
+// Replies echo the sequence number so a late one can't be taken for the answer to a later transfer.
+const CMD_TRANSFER: u8 = 1;
+const CMD_REPLY: u8 = 2;
+
+// A transfer the other side clocked is answered with 0xFF if this side doesn't
+// arm its own within the time the 8 bits would take on the wire
+const TRANSFER_CYCLES: u32 = 8 * 512;
+
+// How long the clock master waits for the other side before reading 0xFF
+const REPLY_TIMEOUT: Duration = Duration::from_secs(5);
+// Slices of that wait, the abort flag is checked in between
+const WAIT_SLICE: Duration = Duration::from_millis(50);
+
+/// Prefix selecting a Unix domain socket instead of TCP in link addresses.
+pub const UNIX_PREFIX: &str = "unix:";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Message {
+    // Sequence number and data
+    Transfer(u8, u8),
+    Reply(u8, u8),
+}
+
+/// Link cable to another rustboy instance over any byte stream.
+///
+/// Both sides stay in lockstep on transfer boundaries: the side driving the clock
+/// blocks until the other one answered with the contents of its SB. Once a transfer
+/// timed out the other side counts as unplugged until it's heard from again, so a
+/// paused peer doesn't freeze every following transfer.
+pub struct LinkCable {
+    writer: Box<dyn Write + Send>,
+    messages: Receiver<Message>,
+    // Sequence number of the last transfer this side clocked
+    sequence: u8,
+    // Transfer clocked by the other side: its sequence number, data and the cycles it has been waiting for SC
+    pending: Option<(u8, u8, u32)>,
+    // The last transfer timed out
+    unresponsive: bool,
+    abort: Option<Arc<AtomicBool>>,
+}
+
+impl fmt::Debug for LinkCable {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("LinkCable")
+            .field("sequence", &self.sequence)
+            .field("pending", &self.pending)
+            .field("unresponsive", &self.unresponsive)
+            .finish_non_exhaustive()
+    }
+}
+
+impl LinkCable {
+    pub fn new<R, W>(mut reader: R, writer: W) -> LinkCable
+    where
+        R: Read + Send + 'static,
+        W: Write + Send + 'static,
+    {
+        let (sender, messages) = mpsc::channel();
+        thread::spawn(move || {
+            let mut buf = [0; 3];
+            while reader.read_exact(&mut buf).is_ok() {
+                let message = match buf[0] {
+                    CMD_TRANSFER => Message::Transfer(buf[1], buf[2]),
+                    CMD_REPLY => Message::Reply(buf[1], buf[2]),
+                    _ => continue,
+                };
+                if sender.send(message).is_err() {
+                    break;
+                }
+            }
+        });
+        LinkCable {
+            writer: Box::new(writer),
+            messages,
+            sequence: 0,
+            pending: None,
+            unresponsive: false,
+            abort: None,
+        }
+    }
+
+    pub fn from_tcp(stream: TcpStream) -> io::Result<LinkCable> {
+        stream.set_nodelay(true)?;
+        Ok(LinkCable::new(stream.try_clone()?, stream))
+    }
+
+    #[cfg(unix)]
+    pub fn from_unix(stream: UnixStream) -> io::Result<LinkCable> {
+        Ok(LinkCable::new(stream.try_clone()?, stream))
+    }
+
+    /// Waits for the other side to connect. `address` is either `host:port`
+    /// or a socket path prefixed with `unix:`.
+    pub fn listen(address: &str) -> io::Result<LinkCable> {
+        #[cfg(unix)]
+        {
+            if let Some(path) = address.strip_prefix(UNIX_PREFIX) {
+                let (stream, _) = UnixListener::bind(path)?.accept()?;
+                return LinkCable::from_unix(stream);
+            }
+        }
+        let (stream, _) = TcpListener::bind(address)?.accept()?;
+        LinkCable::from_tcp(stream)
+    }
+
+    /// Connects to a listening instance, see `listen` for the address format.
+    pub fn connect(address: &str) -> io::Result<LinkCable> {
+        #[cfg(unix)]
+        {
+            if let Some(path) = address.strip_prefix(UNIX_PREFIX) {
+                return LinkCable::from_unix(UnixStream::connect(path)?);
+            }
+        }
+        LinkCable::from_tcp(TcpStream::connect(address)?)
+    }
+
+    /// Gives up waiting for the other side once `abort` is set, e.g. on shutdown.
+    pub fn set_abort_flag(&mut self, abort: Arc<AtomicBool>) {
+        self.abort = Some(abort);
+    }
+
+    fn aborted(&self) -> bool {
+        self.abort.as_ref().is_some_and(|abort| abort.load(Ordering::SeqCst))
+    }
+
+    fn send(&mut self, command: u8, sequence: u8, data: u8) {
+        // A broken connection reads as an unplugged cable
+        let _ = self.writer.write_all(&[command, sequence, data]).and_then(|_| self.writer.flush());
+    }
+}
+
+impl SerialDevice for LinkCable {
+    fn transfer(&mut self, byte: u8) -> u8 {
+        if let Some((sequence, _, _)) = self.pending.take() {
+            self.send(CMD_REPLY, sequence, 0xFF);
+        }
+        self.sequence = self.sequence.wrapping_add(1);
+        self.send(CMD_TRANSFER, self.sequence, byte);
+        // An unresponsive side only gets the chance to answer right away
+        let deadline = Instant::now() + if self.unresponsive {Duration::ZERO} else {REPLY_TIMEOUT};
+        while !self.aborted() {
+            let wait = deadline.saturating_duration_since(Instant::now()).min(WAIT_SLICE);
+            match self.messages.recv_timeout(wait) {
+                Ok(message) => {
+                    self.unresponsive = false;
+                    match message {
+                        Message::Reply(sequence, data) if sequence == self.sequence => return data,
+                        // Late answer to a transfer that already timed out
+                        Message::Reply(..) => {}
+                        // Both sides drive the clock, neither one gets anything
+                        Message::Transfer(sequence, _) => self.send(CMD_REPLY, sequence, 0xFF),
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) if Instant::now() < deadline => {}
+                Err(RecvTimeoutError::Timeout) => {
+                    self.unresponsive = true;
+                    return 0xFF;
+                }
+                Err(RecvTimeoutError::Disconnected) => return 0xFF,
+            }
+        }
+        0xFF
+    }
+
+    fn poll(&mut self, cycles: u32, byte: u8, ready: bool) -> Option<u8> {
+        if self.pending.is_none() {
+            match self.messages.try_recv() {
+                Ok(Message::Transfer(sequence, data)) => {
+                    self.unresponsive = false;
+                    self.pending = Some((sequence, data, 0));
+                }
+                // Late answer to a transfer that already timed out
+                Ok(Message::Reply(..)) => self.unresponsive = false,
+                Err(TryRecvError::Empty) | Err(TryRecvError::Disconnected) => {}
+            }
+        }
+        let (sequence, data, waited) = self.pending?;
+        if ready {
+            self.pending = None;
+            self.send(CMD_REPLY, sequence, byte);
+            return Some(data);
+        }
+        if waited + cycles >= TRANSFER_CYCLES {
+            self.pending = None;
+            self.send(CMD_REPLY, sequence, 0xFF);
+        } else {
+            self.pending = Some((sequence, data, waited + cycles));
+        }
+        None
+    }
+}
@@ -1,38 +1,364 @@
-extern crate bitvec;
-
+use std::convert::TryInto;
 use std::env;
 use std::fs::File;
-use std::io::Read;
+use std::io::{BufWriter, Read};
 use std::path::Path;
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use rustboy::audio::WavWriter;
+use rustboy::gbs::GbsPlayer;
+use rustboy::io::serial::StdoutDevice;
+use rustboy::io::sound::{Channel, SoundController};
+use rustboy::link::bgb::BgbLink;
+use rustboy::link::LinkCable;
+use rustboy::save::SaveFile;
+use rustboy::vgm::VgmWriter;
+use rustboy::{Config, Emulator, Strictness, CYCLES_PER_FRAME, CYCLES_PER_SECOND};
+
+const DEFAULT_AUTOSAVE_SECONDS: u64 = 30;
+const RECORDING_SAMPLE_RATE: u32 = 44100;
+// About once a second, recordings stay playable even if the process gets killed
+const RECORDING_FLUSH_FRAMES: u64 = 60;
+const DEFAULT_GBS_SECONDS: u64 = 120;
+
+const USAGE: &str = "Usage: rustboy <rom> [--frames <count>] [--autosave <seconds>] [--strict]
+              [--link-listen <address> | --link-connect <address>] [--link-protocol <rustboy|bgb>]
+              [--record-audio <wav>] [--record-channels <prefix>] [--mute <channels>] [--solo <channels>]
+              [--record-vgm <vgm>]
+       rustboy <gbs> [--track <number>] [--seconds <count> | --frames <count>]
+              [--record-audio <wav>] [--record-channels <prefix>] [--mute <channels>] [--solo <channels>]
+              [--record-vgm <vgm>]
+Link addresses are host:port for TCP or unix:<path> for a Unix domain socket.
+The bgb protocol connects to BGB 1.4 compatible emulators over TCP.
+--record-audio writes the sound output as 44100 Hz 16 bit stereo WAV.
+--record-channels writes each channel before mixing to <prefix>-ch1.wav to <prefix>-ch4.wav.
+Channels for --mute and --solo are comma separated numbers from 1 to 4.
+--record-vgm logs every sound register write to a VGM file.
+GBS music rips are rendered headlessly, starting with the rip's first track for 120 seconds by default.
+At least one of the recording options is needed for them, --strict and the link options aren't supported.";
+
+enum LinkProtocol {
+    Rustboy,
+    Bgb,
+}
+
+enum Link {
+    Listen(String),
+    Connect(String),
+}
+
+struct Args {
+    rom: String,
+    frames: Option<u64>,
+    autosave: Option<Duration>,
+    strictness: Strictness,
+    link: Option<Link>,
+    link_protocol: LinkProtocol,
+    record_audio: Option<String>,
+    record_channels: Option<String>,
+    muted: Vec<Channel>,
+    soloed: Vec<Channel>,
+    record_vgm: Option<String>,
+    track: Option<u8>,
+    seconds: Option<u64>,
+}
 
-mod cpu;
-mod ram;
-mod bus;
-mod io;
+fn parse_args() -> Result<Args, String> {
+    let mut rom = None;
+    let mut frames = None;
+    let mut autosave = Some(Duration::from_secs(DEFAULT_AUTOSAVE_SECONDS));
+    let mut strictness = Strictness::Hardware;
+    let mut link = None;
+    let mut link_protocol = LinkProtocol::Rustboy;
+    let mut record_audio = None;
+    let mut record_channels = None;
+    let mut muted = Vec::new();
+    let mut soloed = Vec::new();
+    let mut record_vgm = None;
+    let mut track = None;
+    let mut seconds = None;
 
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--frames" => frames = Some(parse_number(&arg, args.next())?),
+            // 0 disables autosaving, the save file is then only written on exit
+            "--autosave" => autosave = match parse_number(&arg, args.next())? {
+                0 => None,
+                seconds => Some(Duration::from_secs(seconds)),
+            },
+            "--strict" => strictness = Strictness::Strict,
+            "--link-listen" => link = Some(Link::Listen(args.next().ok_or("Missing value for --link-listen")?)),
+            "--link-connect" => link = Some(Link::Connect(args.next().ok_or("Missing value for --link-connect")?)),
+            "--link-protocol" => link_protocol = match args.next().as_deref() {
+                Some("rustboy") => LinkProtocol::Rustboy,
+                Some("bgb") => LinkProtocol::Bgb,
+                Some(value) => return Err(format!("Invalid value {} for {}", value, arg)),
+                None => return Err(format!("Missing value for {}", arg)),
+            },
+            "--record-audio" => record_audio = Some(args.next().ok_or("Missing value for --record-audio")?),
+            "--record-channels" => record_channels = Some(args.next().ok_or("Missing value for --record-channels")?),
+            "--record-vgm" => record_vgm = Some(args.next().ok_or("Missing value for --record-vgm")?),
+            "--mute" => muted = parse_channels(&arg, args.next())?,
+            "--solo" => soloed = parse_channels(&arg, args.next())?,
+            "--track" => track = Some(parse_number(&arg, args.next())?.try_into().map_err(|_| "Invalid value for --track")?),
+            "--seconds" => seconds = Some(parse_number(&arg, args.next())?),
+            _ if arg.starts_with("--") => return Err(format!("Unknown option {}", arg)),
+            _ => rom = Some(arg),
+        }
+    }
 
-const WRAM_CAPACITY: usize = 8 * 1024; 
-const HRAM_CAPACITY: usize = 127;
-const VRAM_CAPACITY: usize = 8 * 1024;
+    Ok(Args {
+        rom: rom.ok_or("Missing ROM path")?,
+        frames,
+        autosave,
+        strictness,
+        link,
+        link_protocol,
+        record_audio,
+        record_channels,
+        muted,
+        soloed,
+        record_vgm,
+        track,
+        seconds,
+    })
+}
+
+fn parse_number(option: &str, value: Option<String>) -> Result<u64, String> {
+    let value = value.ok_or(format!("Missing value for {}", option))?;
+    value.parse().map_err(|_| format!("Invalid value {} for {}", value, option))
+}
+
+fn parse_channels(option: &str, value: Option<String>) -> Result<Vec<Channel>, String> {
+    let value = value.ok_or(format!("Missing value for {}", option))?;
+    value
+        .split(',')
+        .map(|number| match number.trim().parse::<usize>() {
+            Ok(number @ 1..=4) => Ok(Channel::ALL[number - 1]),
+            _ => Err(format!("Invalid channel {} for {}", number, option)),
+        })
+        .collect()
+}
 
 fn main() {
-    let rom_file_name = env::args().nth(1).unwrap();
-    let rom = load_rom(rom_file_name);
-    let ram = ram::Ram::new(WRAM_CAPACITY);
-    let hram = ram::Ram::new(HRAM_CAPACITY);
-    let vram = ram::Ram::new(VRAM_CAPACITY);
-    let bus = bus::Bus::new(ram, rom, hram, vram);
-    let mut cpu = cpu::Cpu::new();
-    cpu.connect_bus(bus);
-    cpu.run();
-    //cpu.run_next_instruction();
-    //cpu.run_next_instruction();
+    let args = parse_args().unwrap_or_else(|err| exit_with_error(&format!("{}\n{}", err, USAGE)));
+    let rom = load_rom(&args.rom);
+    if rom.starts_with(b"GBS") {
+        return play_gbs(&args, rom);
+    }
+    if args.track.is_some() || args.seconds.is_some() {
+        exit_with_error(&format!("--track and --seconds only apply to GBS files\n{}", USAGE));
+    }
+    let config = Config {strictness: args.strictness, ..Default::default()};
+    let mut emulator = Emulator::with_config(rom, config)
+        .unwrap_or_else(|err| exit_with_error(&format!("Failed to load ROM: {}", err)));
+    if let Some(Link::Listen(address)) = &args.link {
+        eprintln!("Waiting for the other side on {}", address);
+    }
+    // Set from the signal handler, the main loop stops at the next frame and writes everything out
+    let terminated = Arc::new(AtomicBool::new(false));
+    match (&args.link, &args.link_protocol) {
+        (Some(Link::Listen(address)), LinkProtocol::Rustboy) => emulator.set_serial_device(lockstep_link(LinkCable::listen(address), &terminated)),
+        (Some(Link::Connect(address)), LinkProtocol::Rustboy) => emulator.set_serial_device(lockstep_link(LinkCable::connect(address), &terminated)),
+        (Some(Link::Listen(address)), LinkProtocol::Bgb) => emulator.set_serial_device(connect_link(BgbLink::listen(address))),
+        (Some(Link::Connect(address)), LinkProtocol::Bgb) => emulator.set_serial_device(connect_link(BgbLink::connect(address))),
+        // Test ROMs print their results over the link port
+        (None, _) => emulator.set_serial_device(StdoutDevice),
+    }
+
+    handle_termination(&terminated);
+    let mut save_file = SaveFile::for_rom(&args.rom, args.autosave);
+    if let Err(err) = save_file.load(&mut emulator) {
+        exit_with_error(&format!("Failed to load save file {}", err));
+    }
+
+    let mut recorder = Recorder::new(&args, emulator.sound_mut());
+
+    let mut frame = 0;
+    let mut stopped = false;
+    while args.frames.is_none_or(|frames| frame < frames) && !terminated.load(Ordering::SeqCst) {
+        if let Err(err) = emulator.run_frame() {
+            eprintln!("Emulation stopped: {}", err);
+            stopped = true;
+            break;
+        }
+        frame += 1;
+        recorder.record(emulator.sound_mut());
+        if let Err(err) = save_file.autosave(&emulator) {
+            eprintln!("Autosave failed {}", err);
+        }
+    }
+
+    if let Err(err) = save_file.save(&emulator) {
+        exit_with_error(&format!("Failed to write save file {}", err));
+    }
+    recorder.finish(emulator.sound());
+    if stopped {
+        process::exit(1);
+    }
+}
+
+/// Renders one track of a GBS rip into the recordings given on the command line.
+fn play_gbs(args: &Args, data: Vec<u8>) {
+    if args.record_audio.is_none() && args.record_channels.is_none() && args.record_vgm.is_none() {
+        exit_with_error(&format!("GBS files need --record-audio, --record-channels or --record-vgm\n{}", USAGE));
+    }
+    if args.strictness == Strictness::Strict || args.link.is_some() {
+        exit_with_error(&format!("GBS files don't support --strict or the link options\n{}", USAGE));
+    }
+    let mut player = GbsPlayer::new(data).unwrap_or_else(|err| exit_with_error(&format!("Failed to load GBS: {}", err)));
+    let header = player.header();
+    eprintln!("{} - {} ({}), {} tracks", header.title, header.author, header.copyright, header.songs);
+    if let Some(track) = args.track {
+        if let Err(err) = player.start_track(track) {
+            exit_with_error(&format!("Failed to start track: {}", err));
+        }
+    }
+
+    let terminated = Arc::new(AtomicBool::new(false));
+    handle_termination(&terminated);
+    let mut recorder = Recorder::new(args, player.sound_mut());
+    let frames = args.frames.unwrap_or(args.seconds.unwrap_or(DEFAULT_GBS_SECONDS) * CYCLES_PER_SECOND as u64 / CYCLES_PER_FRAME as u64);
+    for _ in 0..frames {
+        if terminated.load(Ordering::SeqCst) {
+            break;
+        }
+        if let Err(err) = player.run(CYCLES_PER_FRAME) {
+            eprintln!("Playback stopped: {}", err);
+            break;
+        }
+        recorder.record(player.sound_mut());
+    }
+    recorder.finish(player.sound());
+}
+
+/// The WAV and VGM files given on the command line, filled in once per frame.
+struct Recorder {
+    audio: Option<WavWriter<BufWriter<File>>>,
+    channels: Vec<WavWriter<BufWriter<File>>>,
+    vgm: Option<VgmWriter<BufWriter<File>>>,
+    samples: Vec<i16>,
+    frame: u64,
+}
+
+impl Recorder {
+    /// Creates the files and sets up the sound controller to produce what they need.
+    fn new(args: &Args, sound: &mut SoundController) -> Recorder {
+        let audio = args.record_audio.as_ref().map(|path| {
+            sound.set_sample_rate(Some(RECORDING_SAMPLE_RATE));
+            WavWriter::create(path, RECORDING_SAMPLE_RATE, 2)
+                .unwrap_or_else(|err| exit_with_error(&format!("Failed to create {}: {}", path, err)))
+        });
+        let channels = match &args.record_channels {
+            Some(prefix) => {
+                sound.set_channel_capture(Some(RECORDING_SAMPLE_RATE));
+                (1..=Channel::ALL.len())
+                    .map(|number| {
+                        let path = format!("{}-ch{}.wav", prefix, number);
+                        WavWriter::create(&path, RECORDING_SAMPLE_RATE, 1)
+                            .unwrap_or_else(|err| exit_with_error(&format!("Failed to create {}: {}", path, err)))
+                    })
+                    .collect()
+            }
+            None => Vec::new(),
+        };
+        let vgm = args.record_vgm.as_ref().map(|path| {
+            sound.set_write_log(true);
+            VgmWriter::create(path).unwrap_or_else(|err| exit_with_error(&format!("Failed to create {}: {}", path, err)))
+        });
+        for &channel in &args.muted {
+            sound.set_muted(channel, true);
+        }
+        for &channel in &args.soloed {
+            sound.set_solo(channel, true);
+        }
+        Recorder {audio, channels, vgm, samples: vec![0; RECORDING_SAMPLE_RATE as usize * 2], frame: 0}
+    }
+
+    /// Writes out everything the last frame produced.
+    fn record(&mut self, sound: &mut SoundController) {
+        self.frame += 1;
+        let samples = &mut self.samples;
+        if let Some(wav) = &mut self.audio {
+            let count = sound.read_samples_i16(samples);
+            if let Err(err) = wav.write_samples(&samples[..count]) {
+                exit_with_error(&format!("Failed to record audio {}", err));
+            }
+        }
+        if let Some(vgm) = &mut self.vgm {
+            if let Err(err) = sound.take_writes().iter().try_for_each(|write| vgm.write(write)) {
+                exit_with_error(&format!("Failed to record VGM {}", err));
+            }
+        }
+        for (&channel, wav) in Channel::ALL.iter().zip(&mut self.channels) {
+            let capture = sound.channel_capture_mut(channel).expect("capture was started");
+            let count = capture.read_mono_i16(samples);
+            if let Err(err) = wav.write_samples(&samples[..count]) {
+                exit_with_error(&format!("Failed to record audio {}", err));
+            }
+        }
+        if self.frame.is_multiple_of(RECORDING_FLUSH_FRAMES) {
+            for wav in self.audio.iter_mut().chain(&mut self.channels) {
+                if let Err(err) = wav.flush() {
+                    exit_with_error(&format!("Failed to record audio {}", err));
+                }
+            }
+            if let Some(Err(err)) = self.vgm.as_mut().map(|vgm| vgm.flush()) {
+                exit_with_error(&format!("Failed to record VGM {}", err));
+            }
+        }
+    }
+
+    fn finish(self, sound: &SoundController) {
+        if let Some(Err(err)) = self.vgm.map(|vgm| vgm.finish(sound.log_cycles())) {
+            exit_with_error(&format!("Failed to record VGM {}", err));
+        }
+        for wav in self.audio.into_iter().chain(self.channels) {
+            if let Err(err) = wav.finish() {
+                exit_with_error(&format!("Failed to record audio {}", err));
+            }
+        }
+    }
+}
+
+/// Turns the first Ctrl-C, SIGINT or SIGTERM into a clean shutdown so the save file gets
+/// written, a second one exits right away.
+fn handle_termination(terminated: &Arc<AtomicBool>) {
+    let terminated = Arc::clone(terminated);
+    let result = ctrlc::set_handler(move || {
+        if terminated.swap(true, Ordering::SeqCst) {
+            process::exit(130);
+        }
+    });
+    if let Err(err) = result {
+        eprintln!("Failed to handle termination signals {}", err);
+    }
+}
+
+fn connect_link<T>(result: std::io::Result<T>) -> T {
+    result.unwrap_or_else(|err| exit_with_error(&format!("Failed to set up the link cable: {}", err)))
+}
+
+fn lockstep_link(result: std::io::Result<LinkCable>, terminated: &Arc<AtomicBool>) -> LinkCable {
+    let mut cable = connect_link(result);
+    // Don't keep waiting on a paused peer once asked to shut down
+    cable.set_abort_flag(Arc::clone(terminated));
+    cable
+}
+
+fn exit_with_error(message: &str) -> ! {
+    eprintln!("{}", message);
+    process::exit(1);
 }
 
 
-fn load_rom<P: AsRef<Path>>(path: P) -> Box<[u8]> {
+fn load_rom<P: AsRef<Path>>(path: P) -> Vec<u8> {
     let mut file = File::open(path).unwrap();
     let mut file_buf = Vec::new();
     file.read_to_end(&mut file_buf).unwrap();
-    file_buf.into_boxed_slice()
+    file_buf
 }
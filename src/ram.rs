@@ -6,11 +6,8 @@ pub struct Ram {
 impl Ram {
 
     pub fn new(capacity: usize) -> Ram {
-        let mut ram = Vec::<u8>::with_capacity(capacity);
-        // TODO: Weird
-        ram.resize(capacity, 0);
         Ram {
-            ram: ram.into_boxed_slice(),
+            ram: vec![0; capacity].into_boxed_slice(),
         }
     }
 
@@ -19,7 +16,23 @@ impl Ram {
     }
 
     pub fn read(&self, addr: usize) -> u8 {
-       return self.ram[addr]; 
+       self.ram[addr]
+    }
+
+    pub fn len(&self) -> usize {
+        self.ram.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ram.is_empty()
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        &self.ram
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        &mut self.ram
     }
 }
 
@@ -0,0 +1,146 @@
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use super::cartridge::CartridgeError;
+use super::Emulator;
+
+#[derive(Debug)]
+pub enum SaveError {
+    Io(PathBuf, io::Error),
+    Cartridge(PathBuf, CartridgeError),
+}
+
+impl fmt::Display for SaveError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SaveError::Io(path, err) => write!(f, "{}: {}", path.display(), err),
+            SaveError::Cartridge(path, err) => write!(f, "{}: {}", path.display(), err),
+        }
+    }
+}
+
+impl Error for SaveError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            SaveError::Io(_, err) => Some(err),
+            SaveError::Cartridge(_, err) => Some(err),
+        }
+    }
+}
+
+/// Keeps the battery backed RAM of a cartridge in sync with a .sav file.
+/// Cartridges without a battery never touch the file system.
+#[derive(Debug)]
+pub struct SaveFile {
+    path: PathBuf,
+    autosave_interval: Option<Duration>,
+    last_save: Instant,
+    saved_data: Vec<u8>,
+}
+
+impl SaveFile {
+    pub fn new<P: Into<PathBuf>>(path: P, autosave_interval: Option<Duration>) -> SaveFile {
+        SaveFile {
+            path: path.into(),
+            autosave_interval,
+            last_save: Instant::now(),
+            saved_data: Vec::new(),
+        }
+    }
+
+    /// Save file living next to the ROM, `game.gb` saves to `game.sav`. The extension is
+    /// replaced rather than appended so the file can be shared with other emulators.
+    pub fn for_rom<P: AsRef<Path>>(rom_path: P, autosave_interval: Option<Duration>) -> SaveFile {
+        SaveFile::new(rom_path.as_ref().with_extension("sav"), autosave_interval)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Loads the save file into the cartridge. A missing file is not an error,
+    /// the game simply starts with empty RAM. Returns whether anything was loaded.
+    pub fn load(&mut self, emulator: &mut Emulator) -> Result<bool, SaveError> {
+        if !emulator.has_battery() {
+            return Ok(false);
+        }
+        let data = match fs::read(&self.path) {
+            Ok(data) => data,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(false),
+            Err(err) => return Err(SaveError::Io(self.path.clone(), err)),
+        };
+        emulator.load_save_data(&data).map_err(|err| SaveError::Cartridge(self.path.clone(), err))?;
+        self.saved_data = data;
+        self.last_save = Instant::now();
+        Ok(true)
+    }
+
+    /// Writes the save file if the battery backed state changed since the last save.
+    pub fn save(&mut self, emulator: &Emulator) -> Result<(), SaveError> {
+        self.last_save = Instant::now();
+        if !emulator.has_battery() {
+            return Ok(());
+        }
+        let data = emulator.save_data();
+        if data == self.saved_data {
+            return Ok(());
+        }
+        fs::write(&self.path, &data).map_err(|err| SaveError::Io(self.path.clone(), err))?;
+        self.saved_data = data;
+        Ok(())
+    }
+
+    /// Saves once the autosave interval has passed since the last save.
+    pub fn autosave(&mut self, emulator: &Emulator) -> Result<(), SaveError> {
+        match self.autosave_interval {
+            Some(interval) if self.last_save.elapsed() >= interval => self.save(emulator),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    fn battery_rom() -> Vec<u8> {
+        let mut rom = vec![0; 0x8000];
+        // MBC1+RAM+BATTERY with 8 KiB RAM
+        rom[0x147] = 0x03;
+        rom[0x149] = 0x02;
+        rom
+    }
+
+    #[test]
+    fn load_rejects_mismatched_size() {
+        let path = env::temp_dir().join(format!("rustboy-mismatch-{}.sav", std::process::id()));
+        fs::write(&path, [0; 100]).unwrap();
+        let mut emulator = Emulator::new(battery_rom()).unwrap();
+        let mut save_file = SaveFile::new(&path, None);
+        let err = save_file.load(&mut emulator).unwrap_err();
+        assert!(matches!(err, SaveError::Cartridge(_, CartridgeError::InvalidSaveSize {expected: 8192, actual: 100})));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn save_and_load() {
+        let path = env::temp_dir().join(format!("rustboy-roundtrip-{}.sav", std::process::id()));
+        let mut save_file = SaveFile::new(&path, None);
+        let mut emulator = Emulator::new(battery_rom()).unwrap();
+        assert!(!save_file.load(&mut emulator).unwrap());
+        let mut data = vec![0; 8192];
+        data[0x123] = 0x42;
+        emulator.load_save_data(&data).unwrap();
+        save_file.save(&emulator).unwrap();
+
+        let mut emulator = Emulator::new(battery_rom()).unwrap();
+        assert!(SaveFile::new(&path, None).load(&mut emulator).unwrap());
+        assert_eq!(emulator.save_data(), data);
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,155 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use super::emulator::CYCLES_PER_SECOND;
+use super::io::sound::{self, RegisterWrite};
+
+/// VGM timestamps count samples at 44100 Hz.
+pub const SAMPLE_RATE: u64 = 44100;
+
+const VERSION: u32 = 0x171;
+const HEADER_LENGTH: u32 = 0x100;
+
+// Header fields
+const EOF_OFFSET: u64 = 0x04;
+const TOTAL_SAMPLES: u64 = 0x18;
+const DATA_OFFSET: usize = 0x34;
+const DMG_CLOCK: usize = 0x80;
+
+// Commands
+const DMG_WRITE: u8 = 0xB3;
+const WAIT: u8 = 0x61;
+const WAIT_NTSC_FRAME: u8 = 0x62;
+const WAIT_PAL_FRAME: u8 = 0x63;
+const WAIT_SHORT: u8 = 0x70;
+const END: u8 = 0x66;
+
+/// Turns logged sound register writes into a VGM file for the Game Boy DMG chip.
+#[derive(Debug)]
+pub struct VgmWriter<W: Write + Seek> {
+    writer: W,
+    samples: u64,
+    length: u32,
+}
+
+impl VgmWriter<BufWriter<File>> {
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<VgmWriter<BufWriter<File>>> {
+        VgmWriter::new(BufWriter::new(File::create(path)?))
+    }
+}
+
+impl<W: Write + Seek> VgmWriter<W> {
+    pub fn new(mut writer: W) -> io::Result<VgmWriter<W>> {
+        let mut header = [0; HEADER_LENGTH as usize];
+        header[0..4].copy_from_slice(b"Vgm ");
+        header[8..12].copy_from_slice(&VERSION.to_le_bytes());
+        // Relative to the field itself
+        header[DATA_OFFSET..DATA_OFFSET + 4].copy_from_slice(&(HEADER_LENGTH - DATA_OFFSET as u32).to_le_bytes());
+        header[DMG_CLOCK..DMG_CLOCK + 4].copy_from_slice(&CYCLES_PER_SECOND.to_le_bytes());
+        writer.write_all(&header)?;
+        Ok(VgmWriter {writer, samples: 0, length: HEADER_LENGTH})
+    }
+
+    /// Writes are expected in the order they happened.
+    pub fn write(&mut self, write: &RegisterWrite) -> io::Result<()> {
+        self.wait_until(write.cycle)?;
+        // Registers are numbered from NR10
+        self.emit(&[DMG_WRITE, (write.addr - sound::START as u16) as u8, write.value])
+    }
+
+    fn wait_until(&mut self, cycle: u64) -> io::Result<()> {
+        let target = cycle * SAMPLE_RATE / CYCLES_PER_SECOND as u64;
+        while self.samples < target {
+            let wait = (target - self.samples).min(u16::MAX as u64);
+            match wait {
+                1..=16 => self.emit(&[WAIT_SHORT + wait as u8 - 1])?,
+                735 => self.emit(&[WAIT_NTSC_FRAME])?,
+                882 => self.emit(&[WAIT_PAL_FRAME])?,
+                _ => {
+                    let [low, high] = (wait as u16).to_le_bytes();
+                    self.emit(&[WAIT, low, high])?;
+                }
+            }
+            self.samples += wait;
+        }
+        Ok(())
+    }
+
+    fn emit(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.writer.write_all(bytes)?;
+        self.length += bytes.len() as u32;
+        Ok(())
+    }
+
+    /// Fills in the header for the commands written so far. Players accept the file
+    /// without the end command, so it stays usable even if `finish` never gets to run.
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.seek(SeekFrom::Start(EOF_OFFSET))?;
+        self.writer.write_all(&(self.length - EOF_OFFSET as u32).to_le_bytes())?;
+        self.writer.seek(SeekFrom::Start(TOTAL_SAMPLES))?;
+        self.writer.write_all(&(self.samples as u32).to_le_bytes())?;
+        self.writer.seek(SeekFrom::End(0))?;
+        self.writer.flush()
+    }
+
+    /// Waits until `cycle`, ends the command stream and fills in the header.
+    pub fn finish(mut self, cycle: u64) -> io::Result<W> {
+        self.wait_until(cycle)?;
+        self.emit(&[END])?;
+        self.flush()?;
+        Ok(self.writer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn cycles(samples: u64) -> u64 {
+        samples * CYCLES_PER_SECOND as u64 / SAMPLE_RATE + 1
+    }
+
+    #[test]
+    fn commands_and_header() {
+        let mut vgm = VgmWriter::new(Cursor::new(Vec::new())).unwrap();
+        vgm.write(&RegisterWrite {cycle: 0, addr: 0xFF26, value: 0x80}).unwrap();
+        vgm.write(&RegisterWrite {cycle: cycles(3), addr: 0xFF30, value: 0x12}).unwrap();
+        vgm.write(&RegisterWrite {cycle: cycles(3 + 735), addr: 0xFF12, value: 0xF3}).unwrap();
+        vgm.write(&RegisterWrite {cycle: cycles(3 + 735 + 100_000), addr: 0xFF14, value: 0x87}).unwrap();
+        let data = vgm.finish(cycles(3 + 735 + 100_000 + 882)).unwrap().into_inner();
+
+        assert_eq!(&data[0..4], b"Vgm ");
+        assert_eq!(data[4..8], (data.len() as u32 - 4).to_le_bytes());
+        assert_eq!(data[8..12], 0x171u32.to_le_bytes());
+        assert_eq!(data[0x18..0x1C], (3 + 735 + 100_000 + 882u32).to_le_bytes());
+        assert_eq!(data[0x34..0x38], 0xCCu32.to_le_bytes());
+        assert_eq!(data[0x80..0x84], 4_194_304u32.to_le_bytes());
+        assert_eq!(data[0x100..], [
+            0xB3, 0x16, 0x80,
+            0x72,
+            0xB3, 0x20, 0x12,
+            0x62,
+            0xB3, 0x02, 0xF3,
+            // 100000 samples don't fit into one wait
+            0x61, 0xFF, 0xFF, 0x61, 0xA1, 0x86,
+            0xB3, 0x04, 0x87,
+            0x63,
+            0x66,
+        ]);
+    }
+
+    #[test]
+    fn flush() {
+        let mut vgm = VgmWriter::new(Cursor::new(Vec::new())).unwrap();
+        vgm.write(&RegisterWrite {cycle: cycles(735), addr: 0xFF26, value: 0x80}).unwrap();
+        vgm.flush().unwrap();
+        vgm.write(&RegisterWrite {cycle: cycles(735 + 2), addr: 0xFF24, value: 0x77}).unwrap();
+        let data = vgm.writer.get_ref();
+        // the write after the flush isn't counted yet
+        assert_eq!(data[4..8], (0x104u32 - 4).to_le_bytes());
+        assert_eq!(data[0x18..0x1C], 735u32.to_le_bytes());
+        assert_eq!(data[0x100..], [0x62, 0xB3, 0x16, 0x80, 0x71, 0xB3, 0x14, 0x77]);
+    }
+}
@@ -0,0 +1,100 @@
+use std::fs;
+use std::path::Path;
+
+use rustboy::Emulator;
+
+const ROM_DIR: &str = "gb-test-roms/dmg_sound/rom_singles";
+
+// Blargg's tests report through cartridge RAM: a status byte, a signature and the text output
+const STATUS: u16 = 0xA000;
+const SIGNATURE: [u8; 3] = [0xDE, 0xB0, 0x61];
+const TEXT: u16 = 0xA004;
+const RUNNING: u8 = 0x80;
+
+const MAX_FRAMES: u32 = 60 * 60;
+
+/// Runs one of the dmg_sound ROMs to completion and panics with its output unless it passed.
+/// Passes without running anything when the gb-test-roms submodule isn't checked out.
+fn run_test_rom(name: &str) {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join(ROM_DIR);
+    if !dir.is_dir() {
+        eprintln!("Skipping {}, {} is missing (git submodule update --init)", name, dir.display());
+        return;
+    }
+    let path = dir.join(name);
+    let rom = fs::read(&path).unwrap_or_else(|err| panic!("Failed to read {}: {}", path.display(), err));
+    let mut emulator = Emulator::new(rom).unwrap();
+    for _ in 0..MAX_FRAMES {
+        emulator.run_frame().unwrap();
+        let signature: Vec<u8> = (1..4).map(|offset| emulator.bus().peek(STATUS + offset)).collect();
+        if signature == SIGNATURE && emulator.bus().peek(STATUS) != RUNNING {
+            break;
+        }
+    }
+    let output: String = (TEXT..0xC000)
+        .map(|addr| emulator.bus().peek(addr))
+        .take_while(|&byte| byte != 0)
+        .map(char::from)
+        .collect();
+    assert_eq!(emulator.bus().peek(STATUS), 0, "{} failed:\n{}", name, output);
+}
+
+#[test]
+fn registers() {
+    run_test_rom("01-registers.gb");
+}
+
+#[test]
+fn length_counter() {
+    run_test_rom("02-len ctr.gb");
+}
+
+#[test]
+fn trigger() {
+    run_test_rom("03-trigger.gb");
+}
+
+#[test]
+fn sweep() {
+    run_test_rom("04-sweep.gb");
+}
+
+#[test]
+fn sweep_details() {
+    run_test_rom("05-sweep details.gb");
+}
+
+#[test]
+fn overflow_on_trigger() {
+    run_test_rom("06-overflow on trigger.gb");
+}
+
+#[test]
+fn length_sweep_period_sync() {
+    run_test_rom("07-len sweep period sync.gb");
+}
+
+#[test]
+fn length_counter_during_power() {
+    run_test_rom("08-len ctr during power.gb");
+}
+
+#[test]
+fn wave_read_while_on() {
+    run_test_rom("09-wave read while on.gb");
+}
+
+#[test]
+fn wave_trigger_while_on() {
+    run_test_rom("10-wave trigger while on.gb");
+}
+
+#[test]
+fn registers_after_power() {
+    run_test_rom("11-regs after power.gb");
+}
+
+#[test]
+fn wave_write_while_on() {
+    run_test_rom("12-wave write while on.gb");
+}
@@ -0,0 +1,127 @@
+use std::net::{TcpListener, TcpStream};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::io::{Read, Write};
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use rustboy::io::serial::SerialDevice;
+use rustboy::link::bgb::BgbLink;
+use rustboy::link::LinkCable;
+use rustboy::Emulator;
+
+const RESULT: u16 = 0xC000;
+
+/// Puts `byte` in SB, starts a transfer with the given SC and stores what came back at 0xC000.
+fn exchange_rom(byte: u8, sc: u8) -> Vec<u8> {
+    let program = [
+        0x3E, byte,       // LD A, byte
+        0xE0, 0x01,       // LDH (SB), A
+        0x3E, sc,         // LD A, sc
+        0xE0, 0x02,       // LDH (SC), A
+        0xF0, 0x02,       // LDH A, (SC)
+        0xCB, 0x7F,       // BIT 7, A
+        0x20, 0xFA,       // JR NZ, -6
+        0xF0, 0x01,       // LDH A, (SB)
+        0xEA, 0x00, 0xC0, // LD (0xC000), A
+        0x18, 0xFE,       // JR -2
+    ];
+    let mut rom = vec![0; 0x8000];
+    rom[0x100..0x100 + program.len()].copy_from_slice(&program);
+    rom
+}
+
+/// Runs until the program stored its result, gives up after a while.
+fn run_exchange<D: SerialDevice + 'static>(cable: D, byte: u8, sc: u8) -> u8 {
+    let mut emulator = Emulator::new(exchange_rom(byte, sc)).unwrap();
+    emulator.set_serial_device(cable);
+    let deadline = Instant::now() + Duration::from_secs(20);
+    while emulator.bus().peek(RESULT) == 0 && Instant::now() < deadline {
+        emulator.step_instruction().unwrap();
+    }
+    emulator.bus().peek(RESULT)
+}
+
+fn exchange<D: SerialDevice + Send + 'static>(master: D, slave: D) -> (u8, u8) {
+    let slave = thread::spawn(move || run_exchange(slave, 0x24, 0x80));
+    let master = thread::spawn(move || run_exchange(master, 0x42, 0x81));
+    (master.join().unwrap(), slave.join().unwrap())
+}
+
+#[test]
+#[cfg(unix)]
+fn unix_socket_link() {
+    let (a, b) = UnixStream::pair().unwrap();
+    let master = LinkCable::from_unix(a).unwrap();
+    let slave = LinkCable::from_unix(b).unwrap();
+    assert_eq!(exchange(master, slave), (0x24, 0x42));
+}
+
+#[test]
+fn tcp_link() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    let client = TcpStream::connect(address).unwrap();
+    let (server, _) = listener.accept().unwrap();
+    let master = LinkCable::from_tcp(client).unwrap();
+    let slave = LinkCable::from_tcp(server).unwrap();
+    assert_eq!(exchange(master, slave), (0x24, 0x42));
+}
+
+#[test]
+fn link_cable_drops_stale_replies() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let mut peer = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let (server, _) = listener.accept().unwrap();
+    let mut cable = LinkCable::from_tcp(server).unwrap();
+    // Left over from a transfer that timed out: command, sequence number, data
+    peer.write_all(&[2, 0, 0x99]).unwrap();
+    let peer = thread::spawn(move || {
+        let mut transfer = [0; 3];
+        peer.read_exact(&mut transfer).unwrap();
+        assert_eq!((transfer[0], transfer[2]), (1, 0x42));
+        peer.write_all(&[2, transfer[1], 0x24]).unwrap();
+        peer
+    });
+    assert_eq!(cable.transfer(0x42), 0x24);
+    peer.join().unwrap();
+}
+
+#[test]
+fn link_cable_aborts_the_wait() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let _peer = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let (server, _) = listener.accept().unwrap();
+    let mut cable = LinkCable::from_tcp(server).unwrap();
+    cable.set_abort_flag(Arc::new(AtomicBool::new(true)));
+    let start = Instant::now();
+    assert_eq!(cable.transfer(0x42), 0xFF);
+    assert!(start.elapsed() < Duration::from_secs(1));
+}
+
+#[test]
+fn bgb_link() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    let client = TcpStream::connect(address).unwrap();
+    let (server, _) = listener.accept().unwrap();
+    let master = BgbLink::new(client).unwrap();
+    let slave = BgbLink::new(server).unwrap();
+    assert_eq!(exchange(master, slave), (0x24, 0x42));
+}
+
+#[test]
+fn bgb_link_slave_starts_late() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let (server, _) = listener.accept().unwrap();
+    let master = BgbLink::new(client).unwrap();
+    let slave = BgbLink::new(server).unwrap();
+    // The master's transfer is already waiting when the slave runs its first instruction
+    let master = thread::spawn(move || run_exchange(master, 0x42, 0x81));
+    thread::sleep(Duration::from_millis(200));
+    let slave = thread::spawn(move || run_exchange(slave, 0x24, 0x80));
+    assert_eq!((master.join().unwrap(), slave.join().unwrap()), (0x24, 0x42));
+}